@@ -1,7 +1,9 @@
-use std::str::FromStr;
+use std::{collections::HashMap, future::Future, str::FromStr, time::Duration};
 
+use bitflags::bitflags;
 use enum_iterator::Sequence;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tracing::warn;
 
 use crate::routes::Error;
 
@@ -14,33 +16,157 @@ pub(crate) struct Discord {
     pub(crate) scope: String,
 }
 
+/// What login asks discord for, everything after the token exchange needs all of them
+const REQUIRED_SCOPES: [&str; 3] = ["guilds", "guilds.members.read", "identify"];
+
+impl Discord {
+    /// Scopes the user didn't grant, discord lists the granted ones separated by spaces
+    pub(crate) fn missing_scopes(&self) -> Vec<&'static str> {
+        REQUIRED_SCOPES
+            .into_iter()
+            .filter(|required| !self.scope.split(' ').any(|scope| scope == *required))
+            .collect()
+    }
+}
+
 #[derive(Clone)]
 pub(crate) struct DiscordSecret {
     pub(crate) client_id: String,
     pub(crate) client_secret: String,
 }
 
+pub(crate) async fn refresh_discord_token(
+    secrets: &DiscordSecret,
+    refresh_token: &str,
+) -> Result<Discord, Error> {
+    let mut data = HashMap::new();
+
+    data.insert("client_id", secrets.client_id.as_str());
+    data.insert("client_secret", secrets.client_secret.as_str());
+    data.insert("grant_type", "refresh_token");
+    data.insert("refresh_token", refresh_token);
+
+    reqwest::Client::new()
+        .post("https://discord.com/api/oauth2/token")
+        .form(&data)
+        .send()
+        .await
+        .map_err(|err| Error::Auth(err.to_string()))?
+        .error_for_status()
+        .map_err(|err| Error::Auth(err.to_string()))?
+        .json()
+        .await
+        .map_err(|err| Error::Auth(err.to_string()))
+}
+
+/// How many times a request to discord is tried before giving up
+const DISCORD_ATTEMPTS: u32 = 3;
+/// Wait before the first retry, doubled after each one after that
+const DISCORD_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// Whether a failed request to discord is worth trying again, like a connection problem or
+/// discord having a bad moment. Only use this for requests that are safe to repeat.
+pub(crate) fn is_transient(err: &reqwest::Error) -> bool {
+    err.is_connect()
+        || err.is_timeout()
+        || err.status().is_some_and(|status| status.is_server_error())
+}
+
+/// Sends a request to discord, retrying with backoff when `should_retry` says a failure might
+/// go away by itself
+pub(crate) async fn with_discord_retries<T, E, Fut>(
+    should_retry: impl Fn(&E) -> bool,
+    request: impl FnMut() -> Fut,
+) -> Result<T, E>
+where
+    E: std::fmt::Display,
+    Fut: Future<Output = Result<T, E>>,
+{
+    with_retries(DISCORD_ATTEMPTS, DISCORD_RETRY_DELAY, should_retry, request).await
+}
+
+async fn with_retries<T, E, Fut>(
+    attempts: u32,
+    delay: Duration,
+    should_retry: impl Fn(&E) -> bool,
+    mut request: impl FnMut() -> Fut,
+) -> Result<T, E>
+where
+    E: std::fmt::Display,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempt = 1;
+    let mut delay = delay;
+
+    loop {
+        match request().await {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt < attempts && should_retry(&err) => {
+                warn!(%err, attempt, ?delay, "request failed, retrying");
+
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+                delay *= 2;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct User {
     pub(crate) auth: Discord,
     pub(crate) name: String,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
-pub(crate) struct Permissions(pub(crate) u8);
-impl Default for Permissions {
-    fn default() -> Permissions {
-        Permissions(0)
+bitflags! {
+    /// Stored in the database as the raw `u8`
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub(crate) struct Permissions: u8 {
+        const UPLOAD_SOUNDS = Permission::UploadSounds as u8;
+        const DELETE_SOUNDS = Permission::DeleteSounds as u8;
+        const SOUNDBOARD = Permission::Soundboard as u8;
+        const MODERATOR = Permission::Moderator as u8;
+    }
+}
+
+impl From<Permission> for Permissions {
+    fn from(perm: Permission) -> Permissions {
+        Permissions::from_bits_retain(perm as u8)
     }
 }
 
 impl Permissions {
+    /// Whether `perm` is allowed, moderators are allowed to do everything.
+    /// Use `contains` to check for exactly what's been granted.
     pub(crate) fn can(&self, perm: Permission) -> bool {
-        (self.0 & (perm as u8) > 0) || (self.0 & (Permission::Moderator as u8) > 0)
+        self.contains(Permissions::MODERATOR) || self.intersects(perm.into())
+    }
+
+    /// Each permission that's been granted, without taking `Moderator` implying the rest into account
+    pub(crate) fn granted(&self) -> impl Iterator<Item = Permission> {
+        let permissions = *self;
+        enum_iterator::all::<Permission>()
+            .filter(move |perm| permissions.intersects((*perm).into()))
+    }
+}
+
+// Serialized as a list of permission names instead of the raw bitmask, so api consumers
+// don't need to know the bit layout
+impl Serialize for Permissions {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.granted())
     }
+}
+
+impl<'de> Deserialize<'de> for Permissions {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut permissions = Permissions::empty();
+        for perm in Vec::<Permission>::deserialize(deserializer)? {
+            permissions.insert(perm.into());
+        }
 
-    pub(crate) fn add(&mut self, perm: Permission) {
-        self.0 |= perm as u8;
+        Ok(permissions)
     }
 }
 
@@ -55,15 +181,15 @@ pub(crate) enum Permission {
 }
 
 impl Permission {
-    pub(crate) fn all() -> u8 {
-        0xFF
+    pub(crate) fn from_u8(value: u8) -> Option<Permission> {
+        enum_iterator::all::<Permission>().find(|perm| *perm as u8 == value)
     }
 }
 
 impl ToString for Permission {
     fn to_string(&self) -> String {
         match self {
-            Permission::None => todo!(),
+            Permission::None => "None".to_string(),
             Permission::UploadSounds => "Upload Sounds".to_string(),
             Permission::DeleteSounds => "Delete Sounds".to_string(),
             Permission::Soundboard => "Soundboard".to_string(),
@@ -77,6 +203,7 @@ impl FromStr for Permission {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
+            "None" => Ok(Self::None),
             "Upload Sounds" => Ok(Self::UploadSounds),
             "Delete Sounds" => Ok(Self::DeleteSounds),
             "Soundboard" => Ok(Self::Soundboard),
@@ -85,3 +212,127 @@ impl FromStr for Permission {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum TestError {
+        Transient,
+        Fatal,
+    }
+
+    impl std::fmt::Display for TestError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{self:?}")
+        }
+    }
+
+    /// Fails with each of `errors` in turn, then succeeds with how many attempts it took
+    async fn attempt(attempts: u32, errors: Vec<TestError>) -> (Result<u32, TestError>, u32) {
+        let calls = Cell::new(0);
+        let errors = RefCell::new(errors.into_iter());
+
+        let result = with_retries(
+            attempts,
+            Duration::from_millis(1),
+            |err| *err == TestError::Transient,
+            || {
+                calls.set(calls.get() + 1);
+                let result = match errors.borrow_mut().next() {
+                    Some(err) => Err(err),
+                    None => Ok(calls.get()),
+                };
+
+                async move { result }
+            },
+        )
+        .await;
+
+        (result, calls.get())
+    }
+
+    fn token_response(scope: &str) -> Discord {
+        serde_json::from_value(serde_json::json!({
+            "access_token": "access",
+            "token_type": "Bearer",
+            "expires_in": 604800,
+            "refresh_token": "refresh",
+            "scope": scope,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn all_scopes_granted() {
+        let auth = token_response("identify guilds.members.read guilds");
+        assert!(auth.missing_scopes().is_empty());
+    }
+
+    #[test]
+    fn missing_scopes_are_listed() {
+        let auth = token_response("identify guilds");
+        assert_eq!(auth.missing_scopes(), ["guilds.members.read"]);
+
+        assert_eq!(
+            token_response("").missing_scopes(),
+            ["guilds", "guilds.members.read", "identify"]
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_until_success() {
+        let errors = vec![TestError::Transient, TestError::Transient];
+        assert_eq!(attempt(3, errors).await, (Ok(3), 3));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_last_attempt() {
+        let errors = vec![TestError::Transient; 3];
+        assert_eq!(attempt(3, errors).await, (Err(TestError::Transient), 3));
+    }
+
+    #[tokio::test]
+    async fn doesnt_retry_fatal_errors() {
+        let errors = vec![TestError::Fatal, TestError::Transient];
+        assert_eq!(attempt(3, errors).await, (Err(TestError::Fatal), 1));
+    }
+
+    #[test]
+    fn permission_names_round_trip() {
+        for perm in enum_iterator::all::<Permission>() {
+            let name = perm.to_string();
+            assert_eq!(Permission::from_str(&name).unwrap(), perm, "{name}");
+        }
+    }
+
+    #[test]
+    fn moderators_can_do_everything() {
+        let moderator = Permissions::MODERATOR;
+        for perm in enum_iterator::all::<Permission>() {
+            assert!(moderator.can(perm), "{perm:?}");
+        }
+
+        let uploader = Permissions::from(Permission::UploadSounds);
+        assert!(uploader.can(Permission::UploadSounds));
+        assert!(!uploader.can(Permission::DeleteSounds));
+        assert!(!uploader.can(Permission::Moderator));
+    }
+
+    #[test]
+    fn permissions_round_trip_through_u8() {
+        let permissions = Permissions::UPLOAD_SOUNDS | Permissions::SOUNDBOARD;
+        assert_eq!(permissions.bits(), 5);
+        assert_eq!(
+            Permissions::from_bits_retain(permissions.bits()),
+            permissions
+        );
+
+        // Bits without a permission, like the 0xFF older versions stored for everything, are kept
+        assert_eq!(Permissions::from_bits_retain(0xFF).bits(), 0xFF);
+        assert!(Permissions::from_bits_retain(0xFF).can(Permission::Soundboard));
+    }
+}
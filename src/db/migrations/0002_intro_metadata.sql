@@ -0,0 +1,26 @@
+-- sqlite can't add a column with a non-constant default, so the table has to be rebuilt
+create table Intro_new
+(
+    id     integer not null
+        constraint Intro_pk
+            primary key autoincrement,
+    name   TEXT    not null,
+    volume integer not null,
+    guild_id integer not null
+        constraint Intro_Guild_guild_id_fk
+            references Guild ("id"),
+    filename   TEXT    not null,
+    duration_secs integer,
+    uploaded_by TEXT
+        constraint Intro_User_username_fk
+            references User,
+    created_at DATETIME not null default CURRENT_TIMESTAMP
+);
+
+insert into Intro_new (id, name, volume, guild_id, filename)
+select id, name, volume, guild_id, filename
+from Intro;
+
+drop table Intro;
+
+alter table Intro_new rename to Intro;
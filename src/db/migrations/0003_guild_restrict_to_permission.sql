@@ -0,0 +1 @@
+alter table Guild add column restrict_to_permission integer;
@@ -0,0 +1,26 @@
+-- `kind` is part of the primary key, so the table has to be rebuilt
+create table UserIntro_new
+(
+    username     text    not null
+        constraint UserIntro_User_username_fk
+            references User,
+    intro_id     integer not null
+        constraint UserIntro_Intro_id_fk
+            references Intro,
+    guild_id     integer    not null
+        constraint UserIntro_Guild_guild_id_fk
+            references Guild ("id"),
+    channel_name text    not null
+        constraint UserIntro_Channel_channel_name_fk
+            references Channel ("name"),
+    kind         integer not null default 0,
+    primary key ("username", "intro_id", "guild_id", "channel_name", "kind")
+);
+
+insert into UserIntro_new (username, intro_id, guild_id, channel_name)
+select username, intro_id, guild_id, channel_name
+from UserIntro;
+
+drop table UserIntro;
+
+alter table UserIntro_new rename to UserIntro;
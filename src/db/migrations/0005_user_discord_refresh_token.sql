@@ -0,0 +1 @@
+alter table User add column discord_refresh_token TEXT;
@@ -0,0 +1 @@
+alter table Intro add column play_count integer not null default 0;
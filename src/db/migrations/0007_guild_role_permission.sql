@@ -0,0 +1,9 @@
+create table GuildRolePermission
+(
+    guild_id    integer not null
+        constraint GuildRolePermission_Guild_guild_id_fk
+            references Guild ("id"),
+    role_id     integer not null,
+    permissions integer not null,
+    primary key ("guild_id", "role_id")
+);
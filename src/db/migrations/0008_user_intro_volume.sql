@@ -0,0 +1,2 @@
+-- Null means the user hasn't overridden the intro's own volume
+alter table UserIntro add column volume integer;
@@ -0,0 +1,2 @@
+-- Discord's id for the channel, filled in when channels are synced from discord
+alter table Channel add column id integer;
@@ -0,0 +1,2 @@
+-- Guilds the bot has been removed from are kept around in case it gets added back
+alter table Guild add column active integer not null default 1;
@@ -0,0 +1 @@
+alter table Channel add column intros_enabled integer not null default 1;
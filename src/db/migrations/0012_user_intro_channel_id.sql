@@ -0,0 +1,14 @@
+-- Intros are looked up by discord's channel id so renaming a channel doesn't lose them,
+-- `channel_name` is only kept up to date for display
+alter table UserIntro add column channel_id integer;
+
+update UserIntro
+set channel_id = (
+    select Channel.id
+    from Channel
+    where Channel.name = UserIntro.channel_name
+    and Channel.guild_id = UserIntro.guild_id
+);
+
+create unique index UserIntro_channel_id_uindex
+    on UserIntro (username, intro_id, guild_id, channel_id, kind);
@@ -0,0 +1,2 @@
+-- Played for users who haven't picked an intro of their own, null means nothing plays
+alter table Guild add column default_intro_id integer references Intro (id);
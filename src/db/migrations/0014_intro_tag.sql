@@ -0,0 +1,7 @@
+-- Lets uploaders group a guild's intros, only used for filtering on the dashboard
+create table IntroTag (
+    intro_id integer not null references Intro (id),
+    tag text not null,
+
+    primary key (intro_id, tag)
+);
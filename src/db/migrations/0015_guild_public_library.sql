@@ -0,0 +1,2 @@
+-- Lets anyone browse a guild's intros without logging in
+alter table Guild add column public_library integer not null default 0;
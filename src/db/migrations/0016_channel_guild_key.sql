@@ -0,0 +1,76 @@
+-- Channel names only have to be unique within a guild, so the name can't be the primary key.
+-- Both tables have to be rebuilt to change keys.
+create table Channel_new
+(
+    name           text    not null,
+    guild_id       integer not null
+        constraint Channel_Guild_id_fk
+            references Guild (id),
+    -- Discord's id for the channel, null for channels that were added by hand and haven't
+    -- been synced from discord yet
+    id             integer,
+    intros_enabled integer not null default 1,
+    primary key ("guild_id", "name")
+);
+
+insert into Channel_new (name, guild_id, id, intros_enabled)
+select name, guild_id, id, intros_enabled
+from Channel
+where guild_id is not null;
+
+-- Channels added before guilds were tracked have no guild_id, they're backfilled from the guilds
+-- users picked intros in for them. Discord's id is only kept if that's a single guild, since one
+-- channel id can't belong to several guilds. Channels nobody picked an intro for are dropped.
+insert or ignore into Channel_new (name, guild_id, id, intros_enabled)
+select distinct Channel.name,
+                UserIntro.guild_id,
+                case
+                    when (select count(distinct guild_id)
+                          from UserIntro
+                          where UserIntro.channel_name = Channel.name) = 1
+                        then Channel.id
+                    end,
+                Channel.intros_enabled
+from Channel
+         join UserIntro on UserIntro.channel_name = Channel.name
+where Channel.guild_id is null;
+
+drop table Channel;
+
+alter table Channel_new rename to Channel;
+
+create table UserIntro_new
+(
+    username     text    not null
+        constraint UserIntro_User_username_fk
+            references User,
+    intro_id     integer not null
+        constraint UserIntro_Intro_id_fk
+            references Intro,
+    guild_id     integer not null
+        constraint UserIntro_Guild_guild_id_fk
+            references Guild ("id"),
+    channel_name text    not null,
+    kind         integer not null default 0,
+    volume       integer,
+    channel_id   integer,
+    constraint UserIntro_Channel_fk
+        foreign key ("guild_id", "channel_name") references Channel ("guild_id", "name"),
+    primary key ("username", "intro_id", "guild_id", "channel_name", "kind")
+);
+
+insert into UserIntro_new (username, intro_id, guild_id, channel_name, kind, volume, channel_id)
+select username, intro_id, guild_id, channel_name, kind, volume, channel_id
+from UserIntro
+-- anything still pointing at a channel that wasn't carried over is deleted with it
+where exists (select 1
+              from Channel
+              where Channel.guild_id = UserIntro.guild_id
+                and Channel.name = UserIntro.channel_name);
+
+drop table UserIntro;
+
+alter table UserIntro_new rename to UserIntro;
+
+create unique index UserIntro_channel_id_uindex
+    on UserIntro (username, intro_id, guild_id, channel_id, kind);
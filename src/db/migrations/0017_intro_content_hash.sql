@@ -0,0 +1,5 @@
+-- Hex encoded SHA-256 of the sound file, for spotting the same sound uploaded twice
+alter table Intro add column content_hash text;
+
+create index Intro_guild_id_content_hash_index
+    on Intro (guild_id, content_hash);
@@ -0,0 +1,2 @@
+-- Lets users pause an intro without removing it, everything picked before this plays
+alter table UserIntro add column enabled integer not null default 1;
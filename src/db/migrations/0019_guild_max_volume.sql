@@ -0,0 +1,2 @@
+-- Percentage any intro's volume is clamped to when played, 200 is as loud as intros go anyway
+alter table Guild add column max_volume integer not null default 200;
@@ -0,0 +1,9 @@
+-- Every discord guild a user was in as of their last login, including ones the bot wasn't in yet,
+-- so guilds the bot joins later can be offered to them without logging in again
+create table UserDiscordGuild (
+    username text not null references User (username),
+    guild_id integer not null,
+    owner integer not null default 0,
+
+    primary key (username, guild_id)
+);
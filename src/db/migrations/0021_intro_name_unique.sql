@@ -0,0 +1,12 @@
+-- Intro names were only checked for being taken before inserting, so two uploads at the same
+-- time could both get the same name. Older duplicates get their id tacked on to make them unique.
+update Intro
+set name = name || ' (' || id || ')'
+where exists(select 1
+             from Intro other
+             where other.guild_id = Intro.guild_id
+               and other.name = Intro.name collate nocase
+               and other.id < Intro.id);
+
+create unique index Intro_guild_id_name_uindex
+    on Intro (guild_id, name collate nocase);
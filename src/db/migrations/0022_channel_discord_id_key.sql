@@ -0,0 +1,70 @@
+-- Keys channels on their discord id instead of (guild_id, name), since a guild can have several
+-- channels with the same name. Channels added by hand have no id yet and stay unique by name.
+create table Channel_new
+(
+    name           text    not null,
+    guild_id       integer not null
+        constraint Channel_Guild_id_fk
+            references Guild (id),
+    id             integer
+        constraint Channel_id_uk
+            unique,
+    intros_enabled integer not null default 1
+);
+
+insert into Channel_new (name, guild_id, id, intros_enabled)
+select name, guild_id, id, intros_enabled
+from Channel;
+
+drop table Channel;
+
+alter table Channel_new rename to Channel;
+
+create unique index Channel_unsynced_name_uindex
+    on Channel (guild_id, name)
+    where id is null;
+
+-- User intros are keyed on the channel id too, and no longer reference channels by name.
+-- Intros picked before channel ids were tracked get the id of the channel with their name, ones
+-- for channels that were never synced from discord are dropped since they can't be played.
+create table UserIntro_new
+(
+    username     text    not null
+        constraint UserIntro_User_username_fk
+            references User,
+    intro_id     integer not null
+        constraint UserIntro_Intro_id_fk
+            references Intro,
+    guild_id     integer not null
+        constraint UserIntro_Guild_guild_id_fk
+            references Guild ("id"),
+    channel_name text    not null,
+    kind         integer not null default 0,
+    volume       integer,
+    channel_id   integer not null
+        constraint UserIntro_Channel_id_fk
+            references Channel (id),
+    enabled      integer not null default 1,
+    primary key ("username", "intro_id", "guild_id", "channel_id", "kind")
+);
+
+insert or ignore into UserIntro_new
+    (username, intro_id, guild_id, channel_name, kind, volume, channel_id, enabled)
+select *
+from (select username,
+             intro_id,
+             guild_id,
+             channel_name,
+             kind,
+             volume,
+             coalesce(channel_id, (select Channel.id
+                                   from Channel
+                                   where Channel.guild_id = UserIntro.guild_id
+                                     and Channel.name = UserIntro.channel_name)) as channel_id,
+             enabled
+      from UserIntro)
+where channel_id is not null;
+
+drop table UserIntro;
+
+alter table UserIntro_new rename to UserIntro;
@@ -1,25 +1,176 @@
-use std::path::Path;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
 use chrono::NaiveDateTime;
-use rusqlite::{Connection, OptionalExtension, Result};
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{
+    named_params, params,
+    types::{FromSql, FromSqlError, FromSqlResult, ValueRef},
+    OptionalExtension,
+};
 use serde::{Deserialize, Serialize};
-use tracing::warn;
+use tracing::{info, warn};
 
 use crate::auth;
 
+// Applied in order, `PRAGMA user_version` tracks how many have been applied to a database.
+// Only ever append to this list, changing an existing migration won't re-run it.
+const MIGRATIONS: &[&str] = &[
+    include_str!("migrations/0001_initial.sql"),
+    include_str!("migrations/0002_intro_metadata.sql"),
+    include_str!("migrations/0003_guild_restrict_to_permission.sql"),
+    include_str!("migrations/0004_user_intro_kind.sql"),
+    include_str!("migrations/0005_user_discord_refresh_token.sql"),
+    include_str!("migrations/0006_intro_play_count.sql"),
+    include_str!("migrations/0007_guild_role_permission.sql"),
+    include_str!("migrations/0008_user_intro_volume.sql"),
+    include_str!("migrations/0009_channel_id.sql"),
+    include_str!("migrations/0010_guild_active.sql"),
+    include_str!("migrations/0011_channel_intros_enabled.sql"),
+    include_str!("migrations/0012_user_intro_channel_id.sql"),
+    include_str!("migrations/0013_guild_default_intro.sql"),
+    include_str!("migrations/0014_intro_tag.sql"),
+    include_str!("migrations/0015_guild_public_library.sql"),
+    include_str!("migrations/0016_channel_guild_key.sql"),
+    include_str!("migrations/0017_intro_content_hash.sql"),
+    include_str!("migrations/0018_user_intro_enabled.sql"),
+    include_str!("migrations/0019_guild_max_volume.sql"),
+    include_str!("migrations/0020_user_discord_guild.sql"),
+    include_str!("migrations/0021_intro_name_unique.sql"),
+    include_str!("migrations/0022_channel_discord_id_key.sql"),
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("{0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("couldn't get a database connection: {0}")]
+    Pool(#[from] r2d2::Error),
+    #[error("an intro with that name already exists")]
+    DuplicateIntroName,
+    #[error("a channel with that name already exists")]
+    DuplicateChannelName,
+    #[error("that intro hasn't been picked for this channel")]
+    UserIntroNotFound,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Intro names are unique within a guild (ignoring case), which only the database can enforce
+/// without two requests racing each other
+fn duplicate_intro_name(err: rusqlite::Error) -> Error {
+    match err {
+        rusqlite::Error::SqliteFailure(failure, _)
+            if failure.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE =>
+        {
+            Error::DuplicateIntroName
+        }
+        err => err.into(),
+    }
+}
+
+/// Cheap to clone, every clone shares the same pool of connections
+#[derive(Clone)]
 pub struct Database {
-    conn: Connection,
+    pool: Pool<SqliteConnectionManager>,
+    path: PathBuf,
 }
 
 impl Database {
     pub fn new(path: impl AsRef<Path>) -> Result<Self> {
-        Ok(Self {
-            conn: Connection::open(path)?,
-        })
+        // WAL lets readers carry on while another connection is writing
+        let manager = SqliteConnectionManager::file(&path)
+            .with_init(|conn| conn.execute_batch("PRAGMA journal_mode = WAL;"));
+
+        let db = Self {
+            pool: Pool::new(manager)?,
+            path: path.as_ref().to_path_buf(),
+        };
+        db.migrate()?;
+
+        Ok(db)
+    }
+
+    /// A database that can never hand out a connection, like one whose file has gone away
+    #[cfg(test)]
+    pub(crate) fn unreachable() -> Self {
+        let path = std::env::temp_dir().join("memejoin-missing/memejoin.sqlite");
+        let manager = SqliteConnectionManager::file(&path)
+            .with_flags(rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY);
+
+        Self {
+            pool: Pool::builder()
+                .connection_timeout(std::time::Duration::from_millis(100))
+                .build_unchecked(manager),
+            path,
+        }
+    }
+
+    fn conn(&self) -> Result<PooledConnection<SqliteConnectionManager>> {
+        Ok(self.pool.get()?)
+    }
+
+    fn migrate(&self) -> Result<()> {
+        let mut conn = self.conn()?;
+
+        let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+        // Some migrations rebuild tables, which isn't possible with foreign keys enforced.
+        // NOTE: this can't be changed inside of a transaction
+        let foreign_keys: bool = conn.query_row("PRAGMA foreign_keys", [], |row| row.get(0))?;
+        conn.pragma_update(None, "foreign_keys", false)?;
+
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = conn.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", index + 1)?;
+            tx.commit()?;
+
+            info!("applied database migration {}", index + 1);
+        }
+
+        conn.pragma_update(None, "foreign_keys", foreign_keys)?;
+
+        Ok(())
+    }
+
+    /// Cheapest possible round trip, for checking that the database is usable at all
+    pub(crate) fn ping(&self) -> Result<()> {
+        self.conn()?.query_row("SELECT 1", [], |_| Ok(()))?;
+
+        Ok(())
+    }
+
+    /// Size in bytes of the database file plus its write-ahead log, if there is one
+    pub(crate) fn size_on_disk(&self) -> u64 {
+        let mut wal_path = self.path.clone().into_os_string();
+        wal_path.push("-wal");
+
+        [self.path.as_os_str(), wal_path.as_os_str()]
+            .into_iter()
+            .filter_map(|path| std::fs::metadata(path).ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    }
+
+    /// Flushes the write-ahead log into the database file and rebuilds it to reclaim free pages.
+    ///
+    /// NOTE: this takes an exclusive lock on the database file until it is finished
+    pub(crate) fn vacuum(&self) -> Result<()> {
+        let conn = self.conn()?;
+
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+        conn.execute_batch("VACUUM")?;
+
+        Ok(())
     }
 
     pub(crate) fn get_guild_users(&self, guild_id: u64) -> Result<Vec<String>> {
-        let mut query = self.conn.prepare(
+        let conn = self.conn()?;
+        let mut query = conn.prepare(
             "
             SELECT
                 username
@@ -32,63 +183,113 @@ impl Database {
         // the compiler complains about it being dropped too early (maybe I should update the compiler version)
         let users = query
             .query_map(&[(":guild_id", &guild_id.to_string())], |row| row.get(0))?
-            .collect::<Result<Vec<String>>>()?;
+            .collect::<rusqlite::Result<Vec<String>>>()?;
 
         Ok(users)
     }
 
-    pub(crate) fn get_guilds(&self) -> Result<Vec<Guild>> {
-        let mut query = self.conn.prepare(
+    /// Guilds the bot is currently in, ones it's been removed from are left out
+    pub(crate) fn get_active_guilds(&self) -> Result<Vec<Guild>> {
+        let conn = self.conn()?;
+        let mut query = conn.prepare(
             "
             SELECT
-                id, name, sound_delay
+                id, name, sound_delay, restrict_to_permission, default_intro_id, public_library, max_volume
             FROM Guild
+            WHERE active = 1
             ",
         )?;
 
         // NOTE(pcleavelin): for some reason this needs to be a let-binding or else
         // the compiler complains about it being dropped too early (maybe I should update the compiler version)
         let guilds = query
-            .query_map([], |row| {
-                Ok(Guild {
-                    id: row.get(0)?,
-                    name: row.get(1)?,
-                    sound_delay: row.get(2)?,
-                })
-            })?
+            .query_map([], guild_from_row)?
             .into_iter()
-            .collect::<Result<Vec<Guild>>>();
+            .collect::<rusqlite::Result<Vec<Guild>>>();
 
-        guilds
+        Ok(guilds?)
     }
 
-    pub(crate) fn get_user_from_api_key(&self, api_key: &str) -> Result<User> {
-        self.conn.query_row(
+    pub(crate) fn get_guild(&self, guild_id: u64) -> Result<Guild> {
+        Ok(self.conn()?.query_row(
             "
             SELECT
-                username AS name, api_key, api_key_expires_at, discord_token, discord_token_expires_at
+                id, name, sound_delay, restrict_to_permission, default_intro_id, public_library, max_volume
+            FROM Guild
+            WHERE id = ?1
+            ",
+            [guild_id.to_string()],
+            guild_from_row,
+        )?)
+    }
+
+    /// Adds a guild the bot has joined, or reactivates it if the bot was in it before
+    pub(crate) fn upsert_guild(&self, guild_id: u64, name: &str) -> Result<()> {
+        self.conn()?.execute(
+            "
+            INSERT INTO
+                Guild (id, name, sound_delay)
+            VALUES (?1, ?2, 0)
+            ON CONFLICT(id) DO UPDATE SET name = ?2, active = 1
+            ",
+            &[&guild_id.to_string(), name],
+        )?;
+
+        Ok(())
+    }
+
+    /// Hides a guild the bot was removed from, everything belonging to it is kept
+    pub(crate) fn deactivate_guild(&self, guild_id: u64) -> Result<()> {
+        let affected = self.conn()?.execute(
+            "UPDATE Guild SET active = 0 WHERE id = ?1",
+            [guild_id.to_string()],
+        )?;
+
+        if affected < 1 {
+            warn!("no rows affected when attempting to deactivate guild");
+        }
+
+        Ok(())
+    }
+
+    /// The user `api_key` belongs to, as long as it hasn't expired by `now`
+    pub(crate) fn get_valid_user_from_api_key(
+        &self,
+        api_key: &str,
+        now: NaiveDateTime,
+    ) -> Result<Option<User>> {
+        Ok(self
+            .conn()?
+            .query_row(
+                "
+            SELECT
+                username AS name, api_key, api_key_expires_at, discord_token, discord_token_expires_at, discord_refresh_token
             FROM User
             WHERE api_key = ?1
+            AND api_key_expires_at > ?2
             ",
-            [api_key],
-            |row| {
-                Ok(User {
-                    name: row.get(0)?,
-                    api_key: row.get(1)?,
-                    api_key_expires_at: row.get(2)?,
-                    discord_token: row.get(3)?,
-                    discord_token_expires_at: row.get(4)?,
-                })
-            },
-        )
+                params![api_key, now.to_string()],
+                |row| {
+                    Ok(User {
+                        name: row.get(0)?,
+                        api_key: row.get(1)?,
+                        api_key_expires_at: row.get(2)?,
+                        discord_token: row.get(3)?,
+                        discord_token_expires_at: row.get(4)?,
+                        discord_refresh_token: row.get(5)?,
+                    })
+                },
+            )
+            .optional()?)
     }
 
     pub(crate) fn get_user(&self, username: &str) -> Result<Option<User>> {
-        self.conn
+        Ok(self
+            .conn()?
             .query_row(
                 "
             SELECT
-                username AS name, api_key, api_key_expires_at, discord_token, discord_token_expires_at
+                username AS name, api_key, api_key_expires_at, discord_token, discord_token_expires_at, discord_refresh_token
             FROM User
             WHERE name = ?1
             ",
@@ -100,49 +301,121 @@ impl Database {
                         api_key_expires_at: row.get(2)?,
                         discord_token: row.get(3)?,
                         discord_token_expires_at: row.get(4)?,
+                        discord_refresh_token: row.get(5)?,
                     })
                 },
             )
-            .optional()
+            .optional()?)
     }
 
     pub fn get_user_guilds(&self, username: &str) -> Result<Vec<Guild>> {
-        let mut query = self.conn.prepare(
+        let conn = self.conn()?;
+        let mut query = conn.prepare(
             "
             SELECT
-                id, name, sound_delay
+                id, name, sound_delay, restrict_to_permission, default_intro_id, public_library, max_volume
             FROM Guild
             LEFT JOIN UserGuild ON UserGuild.guild_id = Guild.id
             WHERE UserGuild.username = :username
+            AND Guild.active = 1
             ",
         )?;
 
         // NOTE(pcleavelin): for some reason this needs to be a let-binding or else
         // the compiler complains about it being dropped too early (maybe I should update the compiler version)
         let guilds = query
+            .query_map(&[(":username", username)], guild_from_row)?
+            .into_iter()
+            .collect::<rusqlite::Result<Vec<Guild>>>();
+
+        Ok(guilds?)
+    }
+
+    /// Active guilds the user was in on discord at their last login, but hasn't been set up in
+    /// yet. Usually ones the bot joined after the user logged in.
+    pub fn get_unjoined_user_guilds(&self, username: &str) -> Result<Vec<Guild>> {
+        let conn = self.conn()?;
+        let mut query = conn.prepare(
+            "
+            SELECT
+                id, name, sound_delay, restrict_to_permission, default_intro_id, public_library, max_volume
+            FROM Guild
+            INNER JOIN UserDiscordGuild ON UserDiscordGuild.guild_id = Guild.id
+            WHERE UserDiscordGuild.username = :username
+            AND Guild.active = 1
+            AND NOT EXISTS (
+                SELECT 1 FROM UserGuild
+                WHERE UserGuild.username = :username
+                AND UserGuild.guild_id = Guild.id
+            )
+            ORDER BY Guild.name
+            ",
+        )?;
+
+        let guilds = query
+            .query_map(&[(":username", username)], guild_from_row)?
+            .collect::<rusqlite::Result<Vec<Guild>>>()?;
+
+        Ok(guilds)
+    }
+
+    /// Whether the user owns `guild_id` on discord, or `None` if they weren't in it at their last
+    /// login
+    pub fn get_user_discord_guild_owner(
+        &self,
+        username: &str,
+        guild_id: u64,
+    ) -> Result<Option<bool>> {
+        Ok(self
+            .conn()?
+            .query_row(
+                "SELECT owner FROM UserDiscordGuild WHERE username = ?1 AND guild_id = ?2",
+                [username, &guild_id.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    /// How many intros and outros the user has set up in each guild, keyed by guild id
+    pub fn count_user_intros(&self, username: &str) -> Result<HashMap<u64, u32>> {
+        let conn = self.conn()?;
+        let mut query = conn.prepare(
+            "
+            SELECT
+                guild_id, COUNT(*)
+            FROM UserIntro
+            WHERE username = :username
+            GROUP BY guild_id
+            ",
+        )?;
+
+        let counts = query
             .query_map(&[(":username", username)], |row| {
-                Ok(Guild {
-                    id: row.get(0)?,
-                    name: row.get(1)?,
-                    sound_delay: row.get(2)?,
-                })
+                Ok((row.get(0)?, row.get(1)?))
             })?
-            .into_iter()
-            .collect::<Result<Vec<Guild>>>();
+            .collect::<rusqlite::Result<HashMap<u64, u32>>>();
 
-        guilds
+        Ok(counts?)
     }
 
     pub fn get_guild_intros(&self, guild_id: u64) -> Result<Vec<Intro>> {
-        let mut query = self.conn.prepare(
+        let conn = self.conn()?;
+        let mut query = conn.prepare(
             "
             SELECT
                 Intro.id,
                 Intro.name,
-                Intro.filename
+                Intro.filename,
+                Intro.volume,
+                Intro.duration_secs,
+                Intro.uploaded_by,
+                Intro.created_at,
+                Intro.play_count,
+                Intro.content_hash
             FROM Intro
             WHERE
                 Intro.guild_id = :guild_id
+            ORDER BY Intro.id
             ",
         )?;
 
@@ -154,34 +427,199 @@ impl Database {
                     // :vomit:
                     (":guild_id", &guild_id.to_string()),
                 ],
-                |row| {
-                    Ok(Intro {
-                        id: row.get(0)?,
-                        name: row.get(1)?,
-                        filename: row.get(2)?,
-                    })
-                },
+                intro_from_row,
             )?
             .into_iter()
-            .collect::<Result<Vec<Intro>>>();
+            .collect::<rusqlite::Result<Vec<Intro>>>();
+
+        Ok(intros?)
+    }
+
+    /// Same as [`Database::get_guild_intros`], but only the intros tagged with `tag`
+    pub(crate) fn get_guild_intros_by_tag(&self, guild_id: u64, tag: &str) -> Result<Vec<Intro>> {
+        let conn = self.conn()?;
+        let mut query = conn.prepare(
+            "
+            SELECT
+                Intro.id,
+                Intro.name,
+                Intro.filename,
+                Intro.volume,
+                Intro.duration_secs,
+                Intro.uploaded_by,
+                Intro.created_at,
+                Intro.play_count,
+                Intro.content_hash
+            FROM Intro
+            JOIN IntroTag ON IntroTag.intro_id = Intro.id
+            WHERE
+                Intro.guild_id = :guild_id
+                AND IntroTag.tag = :tag
+            ORDER BY Intro.id
+            ",
+        )?;
+
+        let intros = query
+            .query_map(
+                named_params! {
+                    ":guild_id": guild_id.to_string(),
+                    ":tag": tag,
+                },
+                intro_from_row,
+            )?
+            .collect::<rusqlite::Result<Vec<Intro>>>();
+
+        Ok(intros?)
+    }
 
-        intros
+    /// Same as [`Database::get_guild_intros`], but only the intros with `query` somewhere in their
+    /// name, ignoring case. An empty query matches everything.
+    pub(crate) fn search_guild_intros(&self, guild_id: u64, query: &str) -> Result<Vec<Intro>> {
+        // `%` and `_` typed into the search box are looked for as is, not as wildcards
+        let pattern = format!(
+            "%{}%",
+            query
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_")
+        );
+
+        let conn = self.conn()?;
+        let mut query = conn.prepare(
+            "
+            SELECT
+                Intro.id,
+                Intro.name,
+                Intro.filename,
+                Intro.volume,
+                Intro.duration_secs,
+                Intro.uploaded_by,
+                Intro.created_at,
+                Intro.play_count,
+                Intro.content_hash
+            FROM Intro
+            WHERE
+                Intro.guild_id = :guild_id
+                AND Intro.name LIKE :pattern ESCAPE '\\'
+            ORDER BY Intro.id
+            ",
+        )?;
+
+        let intros = query
+            .query_map(
+                named_params! {
+                    ":guild_id": guild_id.to_string(),
+                    ":pattern": pattern,
+                },
+                intro_from_row,
+            )?
+            .collect::<rusqlite::Result<Vec<Intro>>>();
+
+        Ok(intros?)
+    }
+
+    /// An intro in the guild whose sound file has exactly this hash, if there is one
+    pub(crate) fn find_intro_by_hash(
+        &self,
+        guild_id: u64,
+        content_hash: &str,
+    ) -> Result<Option<Intro>> {
+        Ok(self
+            .conn()?
+            .query_row(
+                "
+                SELECT
+                    Intro.id,
+                    Intro.name,
+                    Intro.filename,
+                    Intro.volume,
+                    Intro.duration_secs,
+                    Intro.uploaded_by,
+                    Intro.created_at,
+                    Intro.play_count,
+                    Intro.content_hash
+                FROM Intro
+                WHERE Intro.guild_id = ?1 AND Intro.content_hash = ?2
+                ORDER BY Intro.id
+                LIMIT 1
+                ",
+                [&guild_id.to_string(), content_hash],
+                intro_from_row,
+            )
+            .optional()?)
+    }
+
+    /// Whether any intro, in any guild, still plays from `filename`
+    pub(crate) fn intro_file_in_use(&self, filename: &str) -> Result<bool> {
+        Ok(self.conn()?.query_row(
+            "SELECT EXISTS (SELECT 1 FROM Intro WHERE filename = ?1)",
+            [filename],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Every tag used by at least one of a guild's intros, for filtering by
+    pub(crate) fn get_guild_tags(&self, guild_id: u64) -> Result<Vec<String>> {
+        let conn = self.conn()?;
+        let mut query = conn.prepare(
+            "
+            SELECT DISTINCT
+                IntroTag.tag
+            FROM IntroTag
+            JOIN Intro ON Intro.id = IntroTag.intro_id
+            WHERE
+                Intro.guild_id = ?1
+            ORDER BY IntroTag.tag ASC
+            ",
+        )?;
+
+        let tags = query
+            .query_map([guild_id.to_string()], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>();
+
+        Ok(tags?)
     }
 
     pub fn get_all_user_intros(&self, guild_id: u64) -> Result<Vec<UserIntro>> {
-        let mut query = self.conn.prepare(
+        // A negative limit means no limit to sqlite
+        self.query_user_intros(guild_id, -1, 0)
+    }
+
+    /// Same as [`Database::get_all_user_intros`], but only `limit` rows starting at `offset`
+    pub(crate) fn get_all_user_intros_paged(
+        &self,
+        guild_id: u64,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Vec<UserIntro>> {
+        self.query_user_intros(guild_id, limit.into(), offset.into())
+    }
+
+    fn query_user_intros(&self, guild_id: u64, limit: i64, offset: i64) -> Result<Vec<UserIntro>> {
+        let conn = self.conn()?;
+        let mut query = conn.prepare(
             "
             SELECT
                 Intro.id,
                 Intro.name,
                 Intro.filename,
+                COALESCE(UI.volume, Intro.volume),
+                Intro.duration_secs,
+                Intro.uploaded_by,
+                Intro.created_at,
+                Intro.play_count,
+                Intro.content_hash,
                 UI.channel_name,
-                UI.username
+                UI.username,
+                UI.kind,
+                UI.enabled,
+                UI.channel_id
             FROM Intro
             LEFT JOIN UserIntro UI ON UI.intro_id = Intro.id
             WHERE
                 UI.guild_id = :guild_id
-            ORDER BY UI.username DESC, UI.channel_name DESC, UI.intro_id;
+            ORDER BY UI.username DESC, UI.channel_name DESC, UI.intro_id
+            LIMIT :limit OFFSET :offset;
             ",
         )?;
 
@@ -189,33 +627,61 @@ impl Database {
         // the compiler complains about it being dropped too early (maybe I should update the compiler version)
         let intros = query
             .query_map(
-                &[
-                    // :vomit:
-                    (":guild_id", &guild_id.to_string()),
-                ],
+                named_params! {
+                    ":guild_id": guild_id.to_string(),
+                    ":limit": limit,
+                    ":offset": offset,
+                },
                 |row| {
                     Ok(UserIntro {
-                        intro: Intro {
-                            id: row.get(0)?,
-                            name: row.get(1)?,
-                            filename: row.get(2)?,
-                        },
-                        channel_name: row.get(3)?,
-                        username: row.get(4)?,
+                        intro: intro_from_row(row)?,
+                        channel_name: row.get(9)?,
+                        channel_id: row.get(13)?,
+                        username: row.get(10)?,
+                        kind: row.get(11)?,
+                        enabled: row.get(12)?,
                     })
                 },
             )?
             .into_iter()
-            .collect::<Result<Vec<UserIntro>>>();
+            .collect::<rusqlite::Result<Vec<UserIntro>>>();
+
+        Ok(intros?)
+    }
+
+    /// Every (username, channel name) that has `intro_id` set as one of their intros or outros
+    pub(crate) fn get_intro_assignments(
+        &self,
+        guild_id: u64,
+        intro_id: i32,
+    ) -> Result<Vec<(String, String)>> {
+        let conn = self.conn()?;
+        let mut query = conn.prepare(
+            "
+            SELECT DISTINCT
+                username, channel_name
+            FROM UserIntro
+            WHERE guild_id = ?1
+            AND intro_id = ?2
+            ORDER BY username, channel_name
+            ",
+        )?;
+
+        let assignments = query
+            .query_map(params![guild_id.to_string(), intro_id], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<(String, String)>>>();
 
-        intros
+        Ok(assignments?)
     }
 
     pub(crate) fn get_all_user_permissions(
         &self,
         guild_id: u64,
     ) -> Result<Vec<(String, auth::Permissions)>> {
-        let mut query = self.conn.prepare(
+        let conn = self.conn()?;
+        let mut query = conn.prepare(
             "
             SELECT
                 username,
@@ -232,9 +698,44 @@ impl Database {
                     // :vomit:
                     (":guild_id", &guild_id.to_string()),
                 ],
-                |row| Ok((row.get(0)?, auth::Permissions(row.get(1)?))),
+                |row| {
+                    Ok((
+                        row.get(0)?,
+                        auth::Permissions::from_bits_retain(row.get(1)?),
+                    ))
+                },
             )?
-            .collect::<Result<Vec<(String, auth::Permissions)>>>()?;
+            .collect::<rusqlite::Result<Vec<(String, auth::Permissions)>>>()?;
+
+        Ok(permissions)
+    }
+
+    /// Permissions granted to holders of each discord role in a guild
+    pub(crate) fn get_guild_role_permissions(
+        &self,
+        guild_id: u64,
+    ) -> Result<Vec<(u64, auth::Permissions)>> {
+        let conn = self.conn()?;
+        let mut query = conn.prepare(
+            "
+            SELECT
+                role_id,
+                permissions
+            FROM GuildRolePermission
+            WHERE
+                guild_id = :guild_id
+            ORDER BY role_id
+            ",
+        )?;
+
+        let permissions = query
+            .query_map(&[(":guild_id", &guild_id.to_string())], |row| {
+                Ok((
+                    row.get(0)?,
+                    auth::Permissions::from_bits_retain(row.get(1)?),
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<(u64, auth::Permissions)>>>()?;
 
         Ok(permissions)
     }
@@ -244,7 +745,7 @@ impl Database {
         username: &str,
         guild_id: u64,
     ) -> Result<auth::Permissions> {
-        self.conn.query_row(
+        Ok(self.conn()?.query_row(
             "
             SELECT
                 permissions
@@ -254,19 +755,23 @@ impl Database {
             AND guild_id = ?2
             ",
             [username, &guild_id.to_string()],
-            |row| Ok(auth::Permissions(row.get(0)?)),
-        )
+            |row| Ok(auth::Permissions::from_bits_retain(row.get(0)?)),
+        )?)
     }
 
-    pub(crate) fn get_guild_channels(&self, guild_id: u64) -> Result<Vec<String>> {
-        let mut query = self.conn.prepare(
+    /// The channels synced from discord, the only ones intros can be picked for
+    pub(crate) fn get_guild_channels(&self, guild_id: u64) -> Result<Vec<(u64, String)>> {
+        let conn = self.conn()?;
+        let mut query = conn.prepare(
             "
             SELECT
+                Channel.id,
                 Channel.name
             FROM Channel
             WHERE
                 Channel.guild_id = :guild_id
-            ORDER BY Channel.name DESC
+            AND Channel.id IS NOT NULL
+            ORDER BY Channel.name DESC, Channel.id
             ",
         )?;
 
@@ -278,107 +783,500 @@ impl Database {
                     // :vomit:
                     (":guild_id", &guild_id.to_string()),
                 ],
-                |row| Ok(row.get(0)?),
+                |row| Ok((row.get(0)?, row.get(1)?)),
             )?
             .into_iter()
-            .collect::<Result<Vec<String>>>();
+            .collect::<rusqlite::Result<Vec<(u64, String)>>>();
 
-        intros
+        Ok(intros?)
     }
 
-    pub(crate) fn get_user_channel_intros(
+    /// Every channel in a guild along with whether intros play in it. Channels that were added by
+    /// hand don't have an id until they're synced.
+    pub(crate) fn get_guild_channel_settings(
         &self,
-        username: &str,
         guild_id: u64,
-        channel_name: &str,
-    ) -> Result<Vec<Intro>> {
-        let all_user_intros = self.get_all_user_intros(guild_id)?.into_iter();
+    ) -> Result<Vec<(Option<u64>, String, bool)>> {
+        let conn = self.conn()?;
+        let mut query = conn.prepare(
+            "
+            SELECT
+                Channel.id,
+                Channel.name,
+                Channel.intros_enabled
+            FROM Channel
+            WHERE
+                Channel.guild_id = :guild_id
+            ORDER BY Channel.name DESC, Channel.id
+            ",
+        )?;
 
-        let intros = all_user_intros
-            .filter(|intro| &intro.username == &username && &intro.channel_name == channel_name)
-            .map(|intro| intro.intro)
-            .collect();
+        let channels = query
+            .query_map(
+                named_params! {
+                    ":guild_id": guild_id.to_string(),
+                },
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )?
+            .collect::<rusqlite::Result<Vec<(Option<u64>, String, bool)>>>()?;
 
-        Ok(intros)
+        Ok(channels)
     }
 
-    pub fn insert_user(
+    /// Name of one of the guild's synced channels, `None` if it isn't one
+    pub(crate) fn get_channel_name(
         &self,
-        username: &str,
-        api_key: &str,
-        api_key_expires_at: NaiveDateTime,
-        discord_token: &str,
-        discord_token_expires_at: NaiveDateTime,
-    ) -> Result<()> {
-        let affected = self.conn.execute(
-            "INSERT INTO
-                User (username, api_key, api_key_expires_at, discord_token, discord_token_expires_at)
-            VALUES (?1, ?2, ?3, ?4, ?5)
-            ON CONFLICT(username) DO UPDATE SET api_key = ?2, api_key_expires_at = ?3, discord_token = ?4, discord_token_expires_at = ?5",
-            &[
-                username,
-                api_key,
-                &api_key_expires_at.to_string(),
-                discord_token,
-                &discord_token_expires_at.to_string(),
-            ],
-        )?;
+        guild_id: u64,
+        channel_id: u64,
+    ) -> Result<Option<String>> {
+        Ok(self
+            .conn()?
+            .query_row(
+                "SELECT name FROM Channel WHERE guild_id = ?1 AND id = ?2",
+                [guild_id.to_string(), channel_id.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
 
-        if affected < 1 {
-            warn!("no rows affected when attempting to insert new user");
-        }
+    /// Channels that aren't known about yet play intros
+    pub(crate) fn channel_intros_enabled(&self, guild_id: u64, channel_id: u64) -> Result<bool> {
+        let enabled = self
+            .conn()?
+            .query_row(
+                "SELECT intros_enabled FROM Channel WHERE guild_id = ?1 AND id = ?2",
+                [guild_id.to_string(), channel_id.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
 
-        Ok(())
+        Ok(enabled.unwrap_or(true))
     }
 
-    pub fn insert_intro(
+    pub(crate) fn set_channel_intros_enabled(
         &self,
-        name: &str,
-        volume: i32,
         guild_id: u64,
-        filename: &str,
+        channel_id: u64,
+        enabled: bool,
     ) -> Result<()> {
-        let affected = self.conn.execute(
-            "INSERT INTO
-                Intro (name, volume, guild_id, filename)
-            VALUES (?1, ?2, ?3, ?4)",
-            &[name, &volume.to_string(), &guild_id.to_string(), filename],
+        let affected = self.conn()?.execute(
+            "UPDATE Channel SET intros_enabled = ?1 WHERE guild_id = ?2 AND id = ?3",
+            params![enabled, guild_id.to_string(), channel_id.to_string()],
         )?;
 
         if affected < 1 {
-            warn!("no rows affected when attempting to insert intro");
+            warn!("no rows affected when attempting to set channel intros enabled");
         }
 
         Ok(())
     }
 
-    pub fn insert_user_guild(&self, username: &str, guild_id: u64) -> Result<()> {
-        let affected = self.conn.execute(
-            "INSERT OR IGNORE INTO UserGuild (username, guild_id) VALUES (?1, ?2)",
-            &[username, &guild_id.to_string()],
+    /// Registers a channel by hand, until syncing from discord fills in its id or removes it
+    pub(crate) fn insert_channel(&self, guild_id: u64, name: &str) -> Result<()> {
+        let affected = self.conn()?.execute(
+            "
+            INSERT INTO
+                Channel (name, guild_id)
+            SELECT ?1, ?2
+            WHERE NOT EXISTS (SELECT 1 FROM Channel WHERE guild_id = ?2 AND name = ?1)
+            ",
+            [name, &guild_id.to_string()],
         )?;
 
         if affected < 1 {
-            warn!("no rows affected when attempting to insert user guild");
+            return Err(Error::DuplicateChannelName);
         }
 
         Ok(())
     }
 
-    pub fn insert_user_intro(
+    /// Replaces a guild's channels with `channels` (id, name). Renamed channels keep their intros,
+    /// channels missing from it are removed along with any intros users had set for them.
+    pub(crate) fn upsert_guild_channels(
+        &self,
+        guild_id: u64,
+        channels: &[(u64, String)],
+    ) -> Result<()> {
+        let conn = self.conn()?;
+        let tx = conn.unchecked_transaction()?;
+
+        let existing = tx
+            .prepare("SELECT id FROM Channel WHERE guild_id = ?1 AND id IS NOT NULL")?
+            .query_map([guild_id.to_string()], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<u64>>>()?;
+
+        for (channel_id, name) in channels {
+            // A channel that was added by hand becomes the synced one
+            tx.execute(
+                "
+                UPDATE Channel SET id = ?3
+                WHERE
+                    guild_id = ?2
+                AND name = ?1
+                AND id IS NULL
+                AND NOT EXISTS (SELECT 1 FROM Channel WHERE id = ?3)
+                ",
+                [name, &guild_id.to_string(), &channel_id.to_string()],
+            )?;
+            tx.execute(
+                "
+                INSERT INTO
+                    Channel (name, guild_id, id)
+                VALUES (?1, ?2, ?3)
+                ON CONFLICT(id) DO UPDATE SET name = ?1
+                ",
+                [name, &guild_id.to_string(), &channel_id.to_string()],
+            )?;
+            tx.execute(
+                "UPDATE UserIntro SET channel_name = ?1 WHERE guild_id = ?2 AND channel_id = ?3",
+                [name, &guild_id.to_string(), &channel_id.to_string()],
+            )?;
+        }
+
+        for channel_id in existing
+            .iter()
+            .filter(|channel_id| !channels.iter().any(|(id, _)| id == *channel_id))
+        {
+            tx.execute(
+                "DELETE FROM UserIntro WHERE guild_id = ?1 AND channel_id = ?2",
+                [guild_id.to_string(), channel_id.to_string()],
+            )?;
+            tx.execute(
+                "DELETE FROM Channel WHERE guild_id = ?1 AND id = ?2",
+                [guild_id.to_string(), channel_id.to_string()],
+            )?;
+        }
+
+        // Channels added by hand that are still without an id don't exist in discord
+        tx.execute(
+            "DELETE FROM Channel WHERE guild_id = ?1 AND id IS NULL",
+            [guild_id.to_string()],
+        )?;
+
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /// The intros a user has picked for a channel that haven't been paused, which are the ones
+    /// the bot plays
+    pub(crate) fn get_user_channel_intros(
+        &self,
+        username: &str,
+        guild_id: u64,
+        channel_id: u64,
+        kind: IntroKind,
+    ) -> Result<Vec<Intro>> {
+        let conn = self.conn()?;
+        let mut query = conn.prepare(
+            "
+            SELECT
+                Intro.id,
+                Intro.name,
+                Intro.filename,
+                COALESCE(UI.volume, Intro.volume),
+                Intro.duration_secs,
+                Intro.uploaded_by,
+                Intro.created_at,
+                Intro.play_count,
+                Intro.content_hash
+            FROM Intro
+            JOIN UserIntro UI ON UI.intro_id = Intro.id
+            WHERE
+                UI.username = :username
+            AND UI.guild_id = :guild_id
+            AND UI.channel_id = :channel_id
+            AND UI.kind = :kind
+            AND UI.enabled = 1
+            ORDER BY UI.intro_id
+            ",
+        )?;
+
+        let intros = query
+            .query_map(
+                named_params! {
+                    ":username": username,
+                    ":guild_id": guild_id.to_string(),
+                    ":channel_id": channel_id.to_string(),
+                    ":kind": kind as u8,
+                },
+                intro_from_row,
+            )?
+            .collect::<rusqlite::Result<Vec<Intro>>>();
+
+        Ok(intros?)
+    }
+
+    /// Every intro a user has picked for a channel, paused or not, for showing on the dashboard
+    pub(crate) fn get_user_channel_picks(
+        &self,
+        username: &str,
+        guild_id: u64,
+        channel_id: u64,
+        kind: IntroKind,
+    ) -> Result<Vec<UserIntro>> {
+        let conn = self.conn()?;
+        let mut query = conn.prepare(
+            "
+            SELECT
+                Intro.id,
+                Intro.name,
+                Intro.filename,
+                COALESCE(UI.volume, Intro.volume),
+                Intro.duration_secs,
+                Intro.uploaded_by,
+                Intro.created_at,
+                Intro.play_count,
+                Intro.content_hash,
+                UI.channel_name,
+                UI.username,
+                UI.kind,
+                UI.enabled,
+                UI.channel_id
+            FROM Intro
+            JOIN UserIntro UI ON UI.intro_id = Intro.id
+            WHERE
+                UI.username = :username
+            AND UI.guild_id = :guild_id
+            AND UI.channel_id = :channel_id
+            AND UI.kind = :kind
+            ORDER BY UI.intro_id
+            ",
+        )?;
+
+        let intros = query
+            .query_map(
+                named_params! {
+                    ":username": username,
+                    ":guild_id": guild_id.to_string(),
+                    ":channel_id": channel_id.to_string(),
+                    ":kind": kind as u8,
+                },
+                |row| {
+                    Ok(UserIntro {
+                        intro: intro_from_row(row)?,
+                        channel_name: row.get(9)?,
+                        channel_id: row.get(13)?,
+                        username: row.get(10)?,
+                        kind: row.get(11)?,
+                        enabled: row.get(12)?,
+                    })
+                },
+            )?
+            .collect::<rusqlite::Result<Vec<UserIntro>>>();
+
+        Ok(intros?)
+    }
+
+    /// Pauses or resumes one of a user's intros without removing it
+    pub(crate) fn set_user_intro_enabled(
         &self,
         username: &str,
         guild_id: u64,
-        channel_name: &str,
+        channel_id: u64,
         intro_id: i32,
+        kind: IntroKind,
+        enabled: bool,
+    ) -> Result<()> {
+        let affected = self.conn()?.execute(
+            "
+            UPDATE UserIntro
+            SET enabled = ?6
+            WHERE
+                username = ?1
+            AND guild_id = ?2
+            AND channel_id = ?3
+            AND intro_id = ?4
+            AND kind = ?5
+            ",
+            params![
+                username,
+                guild_id.to_string(),
+                channel_id.to_string(),
+                intro_id,
+                kind as u8,
+                enabled,
+            ],
+        )?;
+
+        if affected < 1 {
+            warn!("no rows affected when attempting to set user intro enabled");
+        }
+
+        Ok(())
+    }
+
+    pub fn insert_user(
+        &self,
+        username: &str,
+        api_key: &str,
+        api_key_expires_at: NaiveDateTime,
+        discord_token: &str,
+        discord_token_expires_at: NaiveDateTime,
+        discord_refresh_token: &str,
     ) -> Result<()> {
-        let affected = self.conn.execute(
-            "INSERT INTO UserIntro (username, guild_id, channel_name, intro_id) VALUES (?1, ?2, ?3, ?4)",
+        let affected = self.conn()?.execute(
+            "INSERT INTO
+                User (username, api_key, api_key_expires_at, discord_token, discord_token_expires_at, discord_refresh_token)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            ON CONFLICT(username) DO UPDATE SET api_key = ?2, api_key_expires_at = ?3, discord_token = ?4, discord_token_expires_at = ?5, discord_refresh_token = ?6",
             &[
                 username,
-                &guild_id.to_string(),
-                channel_name,
-                &intro_id.to_string(),
+                api_key,
+                &api_key_expires_at.to_string(),
+                discord_token,
+                &discord_token_expires_at.to_string(),
+                discord_refresh_token,
+            ],
+        )?;
+
+        if affected < 1 {
+            warn!("no rows affected when attempting to insert new user");
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn update_user_discord_token(
+        &self,
+        username: &str,
+        discord_token: &str,
+        discord_token_expires_at: NaiveDateTime,
+        discord_refresh_token: &str,
+    ) -> Result<()> {
+        let affected = self.conn()?.execute(
+            "UPDATE User
+            SET discord_token = ?2, discord_token_expires_at = ?3, discord_refresh_token = ?4
+            WHERE username = ?1",
+            &[
+                username,
+                discord_token,
+                &discord_token_expires_at.to_string(),
+                discord_refresh_token,
+            ],
+        )?;
+
+        if affected < 1 {
+            warn!("no rows affected when attempting to update user discord token");
+        }
+
+        Ok(())
+    }
+
+    /// Adds an intro to a guild, returning its id
+    #[allow(clippy::too_many_arguments)]
+    pub fn insert_intro(
+        &self,
+        name: &str,
+        volume: i32,
+        guild_id: u64,
+        filename: &str,
+        uploaded_by: &str,
+        content_hash: Option<&str>,
+        duration_secs: Option<u32>,
+        tags: &[String],
+    ) -> Result<i32> {
+        let conn = self.conn()?;
+        let tx = conn.unchecked_transaction()?;
+
+        let affected = tx
+            .execute(
+                "INSERT INTO
+                    Intro (name, volume, guild_id, filename, uploaded_by, content_hash, duration_secs)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    name,
+                    volume,
+                    guild_id.to_string(),
+                    filename,
+                    uploaded_by,
+                    content_hash,
+                    duration_secs,
+                ],
+            )
+            .map_err(duplicate_intro_name)?;
+
+        let intro_id = tx.last_insert_rowid();
+        for tag in tags {
+            tx.execute(
+                "INSERT OR IGNORE INTO IntroTag (intro_id, tag) VALUES (?1, ?2)",
+                params![intro_id, tag],
+            )?;
+        }
+
+        tx.commit()?;
+
+        if affected < 1 {
+            warn!("no rows affected when attempting to insert intro");
+        }
+
+        Ok(intro_id as i32)
+    }
+
+    pub fn insert_user_guild(&self, username: &str, guild_id: u64) -> Result<()> {
+        let affected = self.conn()?.execute(
+            "INSERT OR IGNORE INTO UserGuild (username, guild_id) VALUES (?1, ?2)",
+            &[username, &guild_id.to_string()],
+        )?;
+
+        if affected < 1 {
+            warn!("no rows affected when attempting to insert user guild");
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the discord guilds remembered for the user with `guilds`, given as their ids and
+    /// whether the user owns them
+    pub fn set_user_discord_guilds(&self, username: &str, guilds: &[(u64, bool)]) -> Result<()> {
+        let conn = self.conn()?;
+        let tx = conn.unchecked_transaction()?;
+
+        tx.execute(
+            "DELETE FROM UserDiscordGuild WHERE username = ?1",
+            [username],
+        )?;
+        for (guild_id, owner) in guilds {
+            tx.execute(
+                "INSERT INTO UserDiscordGuild (username, guild_id, owner) VALUES (?1, ?2, ?3)",
+                params![username, guild_id.to_string(), owner],
+            )?;
+        }
+
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /// Assigns an intro to a user's channel, picking one that's already there leaves it as it is
+    pub fn insert_user_intro(
+        &self,
+        username: &str,
+        guild_id: u64,
+        channel_id: u64,
+        intro_id: i32,
+        kind: IntroKind,
+    ) -> Result<()> {
+        let affected = self.conn()?.execute(
+            "
+            INSERT INTO
+                UserIntro (username, guild_id, channel_id, channel_name, intro_id, kind)
+            VALUES (
+                ?1,
+                ?2,
+                ?3,
+                (SELECT name FROM Channel WHERE guild_id = ?2 AND id = ?3),
+                ?4,
+                ?5
+            )
+            ON CONFLICT(username, intro_id, guild_id, channel_id, kind) DO NOTHING
+            ",
+            params![
+                username,
+                guild_id.to_string(),
+                channel_id.to_string(),
+                intro_id,
+                kind as u8,
             ],
         )?;
 
@@ -389,19 +1287,61 @@ impl Database {
         Ok(())
     }
 
+    /// Overrides how loud one of the intros a user has already picked plays
+    pub(crate) fn set_user_intro_volume(
+        &self,
+        username: &str,
+        guild_id: u64,
+        channel_id: u64,
+        intro_id: i32,
+        kind: IntroKind,
+        volume: i32,
+    ) -> Result<()> {
+        let affected = self.conn()?.execute(
+            "
+            UPDATE UserIntro
+            SET volume = ?6
+            WHERE
+                username = ?1
+            AND guild_id = ?2
+            AND channel_id = ?3
+            AND intro_id = ?4
+            AND kind = ?5
+            ",
+            params![
+                username,
+                guild_id.to_string(),
+                channel_id.to_string(),
+                intro_id,
+                kind as u8,
+                volume,
+            ],
+        )?;
+
+        if affected < 1 {
+            return Err(Error::UserIntroNotFound);
+        }
+
+        Ok(())
+    }
+
     pub(crate) fn insert_user_permission(
         &self,
         username: &str,
         guild_id: u64,
         permissions: auth::Permissions,
     ) -> Result<()> {
-        let affected = self.conn.execute(
+        let affected = self.conn()?.execute(
             "
             INSERT INTO
                 UserPermission (username, guild_id, permissions)
             VALUES (?1, ?2, ?3)
             ON CONFLICT(username, guild_id) DO UPDATE SET permissions = ?3",
-            &[username, &guild_id.to_string(), &permissions.0.to_string()],
+            &[
+                username,
+                &guild_id.to_string(),
+                &permissions.bits().to_string(),
+            ],
         )?;
 
         if affected < 1 {
@@ -411,26 +1351,249 @@ impl Database {
         Ok(())
     }
 
+    pub(crate) fn insert_guild_role_permission(
+        &self,
+        guild_id: u64,
+        role_id: u64,
+        permissions: auth::Permissions,
+    ) -> Result<()> {
+        let affected = self.conn()?.execute(
+            "
+            INSERT INTO
+                GuildRolePermission (guild_id, role_id, permissions)
+            VALUES (?1, ?2, ?3)
+            ON CONFLICT(guild_id, role_id) DO UPDATE SET permissions = ?3",
+            &[
+                &guild_id.to_string(),
+                &role_id.to_string(),
+                &permissions.bits().to_string(),
+            ],
+        )?;
+
+        if affected < 1 {
+            warn!("no rows affected when attempting to insert guild role permissions");
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn delete_guild_role_permission(&self, guild_id: u64, role_id: u64) -> Result<()> {
+        let affected = self.conn()?.execute(
+            "DELETE FROM GuildRolePermission WHERE guild_id = ?1 AND role_id = ?2",
+            &[&guild_id.to_string(), &role_id.to_string()],
+        )?;
+
+        if affected < 1 {
+            warn!("no rows affected when attempting to delete guild role permissions");
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn set_guild_restrict_to_permission(
+        &self,
+        guild_id: u64,
+        permission: Option<auth::Permission>,
+    ) -> Result<()> {
+        let affected = self.conn()?.execute(
+            "UPDATE Guild SET restrict_to_permission = ?1 WHERE id = ?2",
+            (
+                permission.map(|permission| permission as u8),
+                guild_id.to_string(),
+            ),
+        )?;
+
+        if affected < 1 {
+            warn!("no rows affected when attempting to set guild intro restriction");
+        }
+
+        Ok(())
+    }
+
+    /// `None` turns the guild's default intro off
+    pub(crate) fn set_guild_default_intro(
+        &self,
+        guild_id: u64,
+        intro_id: Option<i32>,
+    ) -> Result<()> {
+        let affected = self.conn()?.execute(
+            "
+            UPDATE Guild SET default_intro_id = ?1
+            WHERE id = ?2
+            AND (?1 IS NULL OR EXISTS (SELECT 1 FROM Intro WHERE id = ?1 AND guild_id = ?2))
+            ",
+            (intro_id, guild_id.to_string()),
+        )?;
+
+        if affected < 1 {
+            warn!("no rows affected when attempting to set guild default intro");
+        }
+
+        Ok(())
+    }
+
+    /// The intro to play for users that haven't set any of their own, if the guild has one
+    pub(crate) fn get_guild_default_intro(&self, guild_id: u64) -> Result<Option<Intro>> {
+        Ok(self
+            .conn()?
+            .query_row(
+                "
+                SELECT
+                    Intro.id,
+                    Intro.name,
+                    Intro.filename,
+                    Intro.volume,
+                    Intro.duration_secs,
+                    Intro.uploaded_by,
+                    Intro.created_at,
+                    Intro.play_count,
+                    Intro.content_hash
+                FROM Guild
+                JOIN Intro ON Intro.id = Guild.default_intro_id
+                WHERE Guild.id = ?1
+                ",
+                [guild_id.to_string()],
+                intro_from_row,
+            )
+            .optional()?)
+    }
+
+    pub(crate) fn set_guild_public_library(
+        &self,
+        guild_id: u64,
+        public_library: bool,
+    ) -> Result<()> {
+        let affected = self.conn()?.execute(
+            "UPDATE Guild SET public_library = ?1 WHERE id = ?2",
+            (public_library, guild_id.to_string()),
+        )?;
+
+        if affected < 1 {
+            warn!("no rows affected when attempting to set guild public library");
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn set_guild_max_volume(&self, guild_id: u64, max_volume: u32) -> Result<()> {
+        let affected = self.conn()?.execute(
+            "UPDATE Guild SET max_volume = ?1 WHERE id = ?2",
+            (max_volume, guild_id.to_string()),
+        )?;
+
+        if affected < 1 {
+            warn!("no rows affected when attempting to set guild max volume");
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn set_guild_sound_delay(&self, guild_id: u64, sound_delay: u32) -> Result<()> {
+        let affected = self.conn()?.execute(
+            "UPDATE Guild SET sound_delay = ?1 WHERE id = ?2",
+            (sound_delay, guild_id.to_string()),
+        )?;
+
+        if affected < 1 {
+            warn!("no rows affected when attempting to set guild sound delay");
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn increment_intro_play(&self, intro_id: i32) -> Result<()> {
+        let affected = self.conn()?.execute(
+            "UPDATE Intro SET play_count = play_count + 1 WHERE id = ?1",
+            [intro_id],
+        )?;
+
+        if affected < 1 {
+            warn!("no rows affected when attempting to increment intro play count");
+        }
+
+        Ok(())
+    }
+
+    /// Whether a guild already has an intro called `name`, ignoring case
+    pub(crate) fn intro_name_exists(&self, guild_id: u64, name: &str) -> Result<bool> {
+        Ok(self.conn()?.query_row(
+            "SELECT EXISTS(SELECT 1 FROM Intro WHERE guild_id = ?1 AND name = ?2 COLLATE NOCASE)",
+            [&guild_id.to_string(), name],
+            |row| row.get(0),
+        )?)
+    }
+
+    pub(crate) fn rename_intro(&self, guild_id: u64, intro_id: i32, name: &str) -> Result<()> {
+        let affected = self
+            .conn()?
+            .execute(
+                "UPDATE Intro SET name = ?1 WHERE guild_id = ?2 AND id = ?3",
+                &[name, &guild_id.to_string(), &intro_id.to_string()],
+            )
+            .map_err(duplicate_intro_name)?;
+
+        if affected < 1 {
+            warn!("no rows affected when attempting to rename intro");
+        }
+
+        Ok(())
+    }
+
+    /// Deletes an intro from a guild, along with every user's assignment of it
+    pub(crate) fn delete_intro(&self, guild_id: u64, intro_id: i32) -> Result<()> {
+        let conn = self.conn()?;
+        let tx = conn.unchecked_transaction()?;
+
+        tx.execute(
+            "DELETE FROM UserIntro WHERE guild_id = ?1 AND intro_id = ?2",
+            &[&guild_id.to_string(), &intro_id.to_string()],
+        )?;
+        tx.execute(
+            "DELETE FROM IntroTag
+            WHERE intro_id IN (SELECT id FROM Intro WHERE guild_id = ?1 AND id = ?2)",
+            &[&guild_id.to_string(), &intro_id.to_string()],
+        )?;
+        tx.execute(
+            "UPDATE Guild SET default_intro_id = NULL WHERE id = ?1 AND default_intro_id = ?2",
+            &[&guild_id.to_string(), &intro_id.to_string()],
+        )?;
+        let affected = tx.execute(
+            "DELETE FROM Intro WHERE guild_id = ?1 AND id = ?2",
+            &[&guild_id.to_string(), &intro_id.to_string()],
+        )?;
+
+        tx.commit()?;
+
+        if affected < 1 {
+            warn!("no rows affected when attempting to delete intro");
+        }
+
+        Ok(())
+    }
+
     pub fn delete_user_intro(
         &self,
         username: &str,
         guild_id: u64,
-        channel_name: &str,
+        channel_id: u64,
         intro_id: i32,
+        kind: IntroKind,
     ) -> Result<()> {
-        let affected = self.conn.execute(
+        let affected = self.conn()?.execute(
             "DELETE FROM
                 UserIntro
             WHERE 
                 username = ?1 
             AND guild_id = ?2 
-            AND channel_name = ?3 
-            AND intro_id = ?4",
+            AND channel_id = ?3 
+            AND intro_id = ?4
+            AND kind = ?5",
             &[
                 username,
                 &guild_id.to_string(),
-                channel_name,
+                &channel_id.to_string(),
                 &intro_id.to_string(),
+                &(kind as u8).to_string(),
             ],
         )?;
 
@@ -445,7 +1608,39 @@ impl Database {
 pub struct Guild {
     pub id: u64,
     pub name: String,
+    /// Milliseconds to wait after joining a channel before playing an intro
     pub sound_delay: u32,
+    /// When set, only users holding this permission get their intros played
+    pub restrict_to_permission: Option<auth::Permission>,
+    /// Played for users without an intro of their own in the channel
+    pub default_intro_id: Option<i32>,
+    /// Anyone can browse and preview this guild's intros without logging in
+    pub public_library: bool,
+    /// Loudest any intro plays in this guild, as a percentage like intro volumes
+    pub max_volume: u32,
+}
+
+impl Guild {
+    /// Caps a volume from [`Intro::volume_scale`] to the guild's `max_volume`
+    pub fn clamp_volume(&self, volume_scale: f32) -> f32 {
+        volume_scale.min(self.max_volume as f32 / 100.0)
+    }
+}
+
+/// Maps a row selecting `id, name, sound_delay, restrict_to_permission, default_intro_id,
+/// public_library, max_volume` from Guild, in that order
+fn guild_from_row(row: &rusqlite::Row) -> rusqlite::Result<Guild> {
+    Ok(Guild {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        sound_delay: row.get(2)?,
+        restrict_to_permission: row
+            .get::<_, Option<u8>>(3)?
+            .and_then(auth::Permission::from_u8),
+        default_intro_id: row.get(4)?,
+        public_library: row.get(5)?,
+        max_volume: row.get(6)?,
+    })
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -455,16 +1650,902 @@ pub struct User {
     pub api_key_expires_at: NaiveDateTime,
     pub discord_token: String,
     pub discord_token_expires_at: NaiveDateTime,
+    /// Users who logged in before refresh tokens were stored won't have one
+    pub discord_refresh_token: Option<String>,
 }
 
+#[derive(Serialize)]
 pub struct Intro {
     pub id: i32,
     pub name: String,
     pub filename: String,
+    pub volume: i32,
+    pub duration_secs: Option<u32>,
+    pub uploaded_by: Option<String>,
+    pub created_at: NaiveDateTime,
+    /// How many times the bot has played this intro
+    pub play_count: u32,
+    /// SHA-256 of the sound file, intros from before this was tracked don't have one
+    pub content_hash: Option<String>,
+}
+
+impl Intro {
+    /// `volume` is stored as a percentage from 0-200, where 0 is treated as unset
+    pub fn volume_scale(&self) -> f32 {
+        if self.volume <= 0 {
+            1.0
+        } else {
+            self.volume.min(200) as f32 / 100.0
+        }
+    }
+}
+
+/// Maps a row whose first columns are `id, name, filename, volume, duration_secs, uploaded_by,
+/// created_at, play_count, content_hash` from Intro, in that order. Queries joining other tables
+/// select their own columns after these.
+fn intro_from_row(row: &rusqlite::Row) -> rusqlite::Result<Intro> {
+    Ok(Intro {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        filename: row.get(2)?,
+        volume: row.get(3)?,
+        duration_secs: row.get(4)?,
+        uploaded_by: row.get(5)?,
+        created_at: row.get(6)?,
+        play_count: row.get(7)?,
+        content_hash: row.get(8)?,
+    })
 }
 
 pub struct UserIntro {
     pub intro: Intro,
     pub channel_name: String,
+    pub channel_id: u64,
     pub username: String,
+    pub kind: IntroKind,
+    /// Paused intros stay picked but aren't played
+    pub enabled: bool,
+}
+
+/// Whether a user's sound plays when they join a channel or when they leave it
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum IntroKind {
+    Intro = 0,
+    Outro = 1,
+}
+
+impl FromSql for IntroKind {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_i64()? {
+            0 => Ok(Self::Intro),
+            1 => Ok(Self::Outro),
+            other => Err(FromSqlError::OutOfRange(other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Each in-memory connection is its own database, so the pool only ever gets the one
+    fn in_memory_db() -> Database {
+        let db = Database {
+            pool: Pool::builder()
+                .max_size(1)
+                .build(SqliteConnectionManager::memory())
+                .expect("failed to create pool"),
+            path: PathBuf::from(":memory:"),
+        };
+        db.migrate().expect("failed to migrate database");
+
+        db
+    }
+
+    /// A guild (1) with a `general` voice channel (100), the user `bob` in it, and an intro that
+    /// bob uploaded. Returns the intro's id.
+    fn seeded_db() -> (Database, i32) {
+        let db = in_memory_db();
+        let expires_at = chrono::Utc::now().naive_utc();
+
+        db.upsert_guild(1, "guild").unwrap();
+        db.upsert_guild_channels(1, &[(100, "general".to_string())])
+            .unwrap();
+        db.insert_user("bob", "api_key", expires_at, "token", expires_at, "refresh")
+            .unwrap();
+        db.insert_user_guild("bob", 1).unwrap();
+        db.insert_intro("hello", 0, 1, "hello.mp3", "bob", None, None, &[])
+            .unwrap();
+
+        let intro_id = db.get_guild_intros(1).unwrap()[0].id;
+        (db, intro_id)
+    }
+
+    #[test]
+    fn guild_intros_round_trip() {
+        let (db, _) = seeded_db();
+        db.insert_intro("goodbye", 50, 1, "goodbye.mp3", "bob", None, None, &[])
+            .unwrap();
+
+        let intros = db.get_guild_intros(1).unwrap();
+        assert_eq!(intros.len(), 2);
+        assert_eq!(intros[1].name, "goodbye");
+        assert_eq!(intros[1].volume, 50);
+        assert_eq!(intros[1].filename, "goodbye.mp3");
+        assert_eq!(intros[1].uploaded_by.as_deref(), Some("bob"));
+        assert_eq!(intros[1].play_count, 0);
+
+        assert!(db.get_guild_intros(2).unwrap().is_empty());
+    }
+
+    #[test]
+    fn guild_intros_have_every_field() {
+        let (db, _) = seeded_db();
+        let before = chrono::Utc::now().naive_utc() - chrono::Duration::seconds(1);
+        let intro_id = db
+            .insert_intro(
+                "goodbye",
+                50,
+                1,
+                "goodbye.mp3",
+                "bob",
+                Some("abc123"),
+                Some(7),
+                &[],
+            )
+            .unwrap();
+        db.increment_intro_play(intro_id).unwrap();
+
+        let intros = db.get_guild_intros(1).unwrap();
+        let intro = intros.iter().find(|intro| intro.id == intro_id).unwrap();
+        assert_eq!(intro.name, "goodbye");
+        assert_eq!(intro.filename, "goodbye.mp3");
+        assert_eq!(intro.volume, 50);
+        assert_eq!(intro.duration_secs, Some(7));
+        assert_eq!(intro.uploaded_by.as_deref(), Some("bob"));
+        assert!(intro.created_at >= before, "{}", intro.created_at);
+        assert_eq!(intro.play_count, 1);
+        assert_eq!(intro.content_hash.as_deref(), Some("abc123"));
+    }
+
+    #[test]
+    fn plays_are_counted_per_intro() {
+        let (db, intro_id) = seeded_db();
+        let other_id = db
+            .insert_intro("goodbye", 0, 1, "goodbye.mp3", "bob", None, None, &[])
+            .unwrap();
+
+        for _ in 0..3 {
+            db.increment_intro_play(intro_id).unwrap();
+        }
+
+        let intros = db.get_guild_intros(1).unwrap();
+        let play_count = |id| {
+            intros
+                .iter()
+                .find(|intro| intro.id == id)
+                .unwrap()
+                .play_count
+        };
+        assert_eq!(play_count(intro_id), 3);
+        assert_eq!(play_count(other_id), 0);
+    }
+
+    #[test]
+    fn expired_api_keys_find_no_user() {
+        let (db, _) = seeded_db();
+        let now = chrono::Utc::now().naive_utc();
+        let day = chrono::Duration::days(1);
+        db.insert_user(
+            "alice",
+            "expired_key",
+            now - day,
+            "token",
+            now + day,
+            "refresh",
+        )
+        .unwrap();
+
+        assert!(db
+            .get_valid_user_from_api_key("expired_key", now)
+            .unwrap()
+            .is_none());
+        // Still valid as of before it expired
+        let user = db
+            .get_valid_user_from_api_key("expired_key", now - day * 2)
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.name, "alice");
+
+        assert!(db
+            .get_valid_user_from_api_key("unknown_key", now)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn discord_refresh_token_round_trip() {
+        let (db, _) = seeded_db();
+        let now = chrono::Utc::now().naive_utc();
+        let day = chrono::Duration::days(1);
+
+        let user = db.get_user("bob").unwrap().unwrap();
+        assert_eq!(user.discord_refresh_token.as_deref(), Some("refresh"));
+
+        db.update_user_discord_token("bob", "new_token", now + day, "new_refresh")
+            .unwrap();
+        db.insert_user(
+            "alice",
+            "alice_key",
+            now + day,
+            "token",
+            now + day,
+            "alice_refresh",
+        )
+        .unwrap();
+
+        let user = db.get_user("bob").unwrap().unwrap();
+        assert_eq!(user.discord_token, "new_token");
+        assert_eq!(user.discord_refresh_token.as_deref(), Some("new_refresh"));
+
+        let user = db
+            .get_valid_user_from_api_key("alice_key", now)
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.discord_refresh_token.as_deref(), Some("alice_refresh"));
+    }
+
+    #[test]
+    fn intros_found_by_name() {
+        let (db, _) = seeded_db();
+        db.insert_intro("Goodbye", 0, 1, "goodbye.mp3", "bob", None, None, &[])
+            .unwrap();
+        db.insert_intro("100% loud", 0, 1, "loud.mp3", "bob", None, None, &[])
+            .unwrap();
+
+        let names = |query: &str| {
+            db.search_guild_intros(1, query)
+                .unwrap()
+                .into_iter()
+                .map(|intro| intro.name)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(names("bye"), ["Goodbye"]);
+        assert_eq!(names("GOOD"), ["Goodbye"]);
+        assert_eq!(names("o"), ["hello", "Goodbye", "100% loud"]);
+        assert_eq!(names(""), ["hello", "Goodbye", "100% loud"]);
+        // Not a wildcard
+        assert_eq!(names("%"), ["100% loud"]);
+        assert!(names("nothing like it").is_empty());
+        assert!(db.search_guild_intros(2, "").unwrap().is_empty());
+    }
+
+    #[test]
+    fn inserted_intro_id() {
+        let (db, hello_id) = seeded_db();
+        let goodbye_id = db
+            .insert_intro("goodbye", 0, 1, "goodbye.mp3", "bob", None, None, &[])
+            .unwrap();
+        assert_ne!(goodbye_id, hello_id);
+
+        let intros = db.get_guild_intros(1).unwrap();
+        let goodbye = intros.iter().find(|intro| intro.id == goodbye_id).unwrap();
+        assert_eq!(goodbye.name, "goodbye");
+        assert_eq!(goodbye.filename, "goodbye.mp3");
+    }
+
+    #[test]
+    fn intro_names_unique_per_guild() {
+        let (db, intro_id) = seeded_db();
+        db.upsert_guild(2, "other").unwrap();
+
+        assert!(matches!(
+            db.insert_intro("HELLO", 0, 1, "hello2.mp3", "bob", None, None, &[]),
+            Err(Error::DuplicateIntroName)
+        ));
+        // Only unique within a guild
+        db.insert_intro("hello", 0, 2, "hello2.mp3", "bob", None, None, &[])
+            .unwrap();
+
+        let other_id = db
+            .insert_intro("goodbye", 0, 1, "goodbye.mp3", "bob", None, None, &[])
+            .unwrap();
+        assert!(matches!(
+            db.rename_intro(1, other_id, "Hello"),
+            Err(Error::DuplicateIntroName)
+        ));
+        // Changing the case of its own name is fine
+        db.rename_intro(1, intro_id, "Hello").unwrap();
+        assert!(db.intro_name_exists(1, "hello").unwrap());
+    }
+
+    #[test]
+    fn user_intros_round_trip() {
+        let (db, intro_id) = seeded_db();
+        db.insert_user_intro("bob", 1, 100, intro_id, IntroKind::Intro)
+            .unwrap();
+
+        let intros = db
+            .get_user_channel_intros("bob", 1, 100, IntroKind::Intro)
+            .unwrap();
+        assert_eq!(intros.len(), 1);
+        assert_eq!(intros[0].id, intro_id);
+        assert!(db
+            .get_user_channel_intros("bob", 1, 100, IntroKind::Outro)
+            .unwrap()
+            .is_empty());
+
+        // Picking it again doesn't add it twice
+        db.insert_user_intro("bob", 1, 100, intro_id, IntroKind::Intro)
+            .unwrap();
+        db.set_user_intro_volume("bob", 1, 100, intro_id, IntroKind::Intro, 150)
+            .unwrap();
+        let intros = db
+            .get_user_channel_intros("bob", 1, 100, IntroKind::Intro)
+            .unwrap();
+        assert_eq!(intros.len(), 1);
+        assert_eq!(intros[0].volume, 150);
+
+        // Only intros that were picked can have their volume changed
+        assert!(matches!(
+            db.set_user_intro_volume("bob", 1, 100, intro_id, IntroKind::Outro, 50),
+            Err(Error::UserIntroNotFound)
+        ));
+        assert!(db
+            .get_user_channel_intros("bob", 1, 100, IntroKind::Outro)
+            .unwrap()
+            .is_empty());
+
+        db.delete_user_intro("bob", 1, 100, intro_id, IntroKind::Intro)
+            .unwrap();
+        assert!(db
+            .get_user_channel_intros("bob", 1, 100, IntroKind::Intro)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn user_intros_paged() {
+        let (db, first_id) = seeded_db();
+        let mut intro_ids = vec![first_id];
+        for i in 1..50 {
+            let name = format!("intro {i}");
+            intro_ids.push(
+                db.insert_intro(&name, 0, 1, &format!("{i}.mp3"), "bob", None, None, &[])
+                    .unwrap(),
+            );
+        }
+        for &intro_id in &intro_ids {
+            db.insert_user_intro("bob", 1, 100, intro_id, IntroKind::Intro)
+                .unwrap();
+        }
+
+        let page = db.get_all_user_intros_paged(1, 20, 20).unwrap();
+        let page_ids = page.iter().map(|intro| intro.intro.id).collect::<Vec<_>>();
+        assert_eq!(page_ids, intro_ids[20..40]);
+
+        // The last page is whatever is left over
+        assert_eq!(db.get_all_user_intros_paged(1, 20, 40).unwrap().len(), 10);
+        assert_eq!(db.get_all_user_intros(1).unwrap().len(), 50);
+    }
+
+    #[test]
+    fn paused_intros_are_not_played() {
+        let (db, intro_id) = seeded_db();
+        db.insert_user_intro("bob", 1, 100, intro_id, IntroKind::Intro)
+            .unwrap();
+
+        db.set_user_intro_enabled("bob", 1, 100, intro_id, IntroKind::Intro, false)
+            .unwrap();
+        assert!(db
+            .get_user_channel_intros("bob", 1, 100, IntroKind::Intro)
+            .unwrap()
+            .is_empty());
+        // Still shows up on the dashboard, just paused
+        let picks = db
+            .get_user_channel_picks("bob", 1, 100, IntroKind::Intro)
+            .unwrap();
+        assert_eq!(picks.len(), 1);
+        assert_eq!(picks[0].intro.id, intro_id);
+        assert!(!picks[0].enabled);
+
+        // Picking it again doesn't resume it
+        db.insert_user_intro("bob", 1, 100, intro_id, IntroKind::Intro)
+            .unwrap();
+        assert!(db
+            .get_user_channel_intros("bob", 1, 100, IntroKind::Intro)
+            .unwrap()
+            .is_empty());
+
+        db.set_user_intro_enabled("bob", 1, 100, intro_id, IntroKind::Intro, true)
+            .unwrap();
+        let intros = db
+            .get_user_channel_intros("bob", 1, 100, IntroKind::Intro)
+            .unwrap();
+        assert_eq!(intros.len(), 1);
+        assert_eq!(intros[0].id, intro_id);
+    }
+
+    #[test]
+    fn intro_assignments() {
+        let (db, intro_id) = seeded_db();
+        assert!(db.get_intro_assignments(1, intro_id).unwrap().is_empty());
+
+        db.insert_user_intro("bob", 1, 100, intro_id, IntroKind::Intro)
+            .unwrap();
+        db.insert_user_intro("bob", 1, 100, intro_id, IntroKind::Outro)
+            .unwrap();
+
+        assert_eq!(
+            db.get_intro_assignments(1, intro_id).unwrap(),
+            vec![("bob".to_string(), "general".to_string())]
+        );
+    }
+
+    #[test]
+    fn user_permissions_round_trip() {
+        let (db, _) = seeded_db();
+        assert!(db.get_user_permissions("bob", 1).is_err());
+
+        let mut permissions = auth::Permissions::default();
+        permissions.insert(auth::Permission::UploadSounds.into());
+        db.insert_user_permission("bob", 1, permissions).unwrap();
+
+        let permissions = db.get_user_permissions("bob", 1).unwrap();
+        assert!(permissions.can(auth::Permission::UploadSounds));
+        assert!(!permissions.can(auth::Permission::DeleteSounds));
+    }
+
+    #[test]
+    fn user_permissions_are_per_guild() {
+        let (db, _) = seeded_db();
+        db.upsert_guild(2, "other").unwrap();
+        db.insert_user_guild("bob", 2).unwrap();
+
+        db.insert_user_permission("bob", 1, auth::Permissions::all())
+            .unwrap();
+        let mut permissions = auth::Permissions::default();
+        permissions.insert(auth::Permission::UploadSounds.into());
+        db.insert_user_permission("bob", 2, permissions).unwrap();
+
+        assert!(db
+            .get_user_permissions("bob", 1)
+            .unwrap()
+            .can(auth::Permission::DeleteSounds));
+        // Moderating guild 1 doesn't carry over to guild 2
+        let permissions = db.get_user_permissions("bob", 2).unwrap();
+        assert!(permissions.can(auth::Permission::UploadSounds));
+        assert!(!permissions.can(auth::Permission::DeleteSounds));
+    }
+
+    #[test]
+    fn guild_default_intro() {
+        let (db, intro_id) = seeded_db();
+        assert!(db.get_guild_default_intro(1).unwrap().is_none());
+
+        db.set_guild_default_intro(1, Some(intro_id)).unwrap();
+        assert_eq!(db.get_guild(1).unwrap().default_intro_id, Some(intro_id));
+        assert_eq!(
+            db.get_guild_default_intro(1).unwrap().map(|intro| intro.id),
+            Some(intro_id)
+        );
+
+        // Intros from other guilds can't be picked
+        db.upsert_guild(2, "other").unwrap();
+        db.set_guild_default_intro(2, Some(intro_id)).unwrap();
+        assert!(db.get_guild_default_intro(2).unwrap().is_none());
+
+        db.set_guild_default_intro(1, None).unwrap();
+        assert!(db.get_guild_default_intro(1).unwrap().is_none());
+
+        // Deleting the intro turns the default off rather than leaving it dangling
+        db.set_guild_default_intro(1, Some(intro_id)).unwrap();
+        db.delete_intro(1, intro_id).unwrap();
+        assert_eq!(db.get_guild(1).unwrap().default_intro_id, None);
+    }
+
+    #[test]
+    fn inactive_guilds_are_excluded() {
+        let (db, _) = seeded_db();
+        db.upsert_guild(2, "other").unwrap();
+
+        db.deactivate_guild(2).unwrap();
+        let guilds = db.get_active_guilds().unwrap();
+        assert_eq!(guilds.iter().map(|guild| guild.id).collect::<Vec<_>>(), [1]);
+        assert!(db
+            .get_user_guilds("bob")
+            .unwrap()
+            .iter()
+            .all(|guild| guild.id == 1));
+
+        // Rejoining brings it back
+        db.upsert_guild(2, "other").unwrap();
+        assert_eq!(db.get_active_guilds().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn guild_sound_delay_round_trip() {
+        let (db, _) = seeded_db();
+        assert_eq!(db.get_guild(1).unwrap().sound_delay, 0);
+
+        db.set_guild_sound_delay(1, 2500).unwrap();
+        assert_eq!(db.get_guild(1).unwrap().sound_delay, 2500);
+    }
+
+    #[test]
+    fn intros_filtered_by_tag() {
+        let (db, hello_id) = seeded_db();
+        let tags = ["meme".to_string(), "loud".to_string()];
+        db.insert_intro("goodbye", 0, 1, "goodbye.mp3", "bob", None, None, &tags)
+            .unwrap();
+        db.upsert_guild(2, "other").unwrap();
+        db.insert_intro(
+            "elsewhere",
+            0,
+            2,
+            "elsewhere.mp3",
+            "bob",
+            None,
+            None,
+            &tags[..1],
+        )
+        .unwrap();
+
+        let tagged = db.get_guild_intros_by_tag(1, "meme").unwrap();
+        assert_eq!(tagged.len(), 1);
+        assert_eq!(tagged[0].name, "goodbye");
+        assert!(db.get_guild_intros_by_tag(1, "quiet").unwrap().is_empty());
+        assert_eq!(db.get_guild_tags(1).unwrap(), ["loud", "meme"]);
+
+        let goodbye_id = tagged[0].id;
+        db.delete_intro(1, goodbye_id).unwrap();
+        assert!(db.get_guild_intros_by_tag(1, "meme").unwrap().is_empty());
+        assert!(db.get_guild_tags(1).unwrap().is_empty());
+        assert_eq!(db.get_guild_tags(2).unwrap(), ["meme"]);
+        assert_eq!(db.get_guild_intros(1).unwrap()[0].id, hello_id);
+    }
+
+    #[test]
+    fn guild_max_volume_clamps() {
+        let (db, _) = seeded_db();
+        // Nothing is louder than 200% to begin with
+        assert_eq!(db.get_guild(1).unwrap().clamp_volume(2.0), 2.0);
+
+        db.set_guild_max_volume(1, 80).unwrap();
+        let guild = db.get_guild(1).unwrap();
+        assert_eq!(guild.max_volume, 80);
+        assert_eq!(guild.clamp_volume(1.5), 0.8);
+        assert_eq!(guild.clamp_volume(0.8), 0.8);
+        assert_eq!(guild.clamp_volume(0.5), 0.5);
+    }
+
+    #[test]
+    fn unjoined_guilds_offered_until_set_up() {
+        let (db, _) = seeded_db();
+        db.upsert_guild(2, "new").unwrap();
+
+        // Guild 3 doesn't have the bot in it
+        db.set_user_discord_guilds("bob", &[(1, false), (2, true), (3, false)])
+            .unwrap();
+        let unjoined = db.get_unjoined_user_guilds("bob").unwrap();
+        assert_eq!(
+            unjoined.iter().map(|guild| guild.id).collect::<Vec<_>>(),
+            [2]
+        );
+        assert_eq!(
+            db.get_user_discord_guild_owner("bob", 2).unwrap(),
+            Some(true)
+        );
+        assert_eq!(
+            db.get_user_discord_guild_owner("bob", 1).unwrap(),
+            Some(false)
+        );
+
+        db.insert_user_guild("bob", 2).unwrap();
+        assert!(db.get_unjoined_user_guilds("bob").unwrap().is_empty());
+
+        // Leaving on discord is picked up at the next login
+        db.set_user_discord_guilds("bob", &[(1, false)]).unwrap();
+        assert_eq!(db.get_user_discord_guild_owner("bob", 2).unwrap(), None);
+    }
+
+    #[test]
+    fn guild_public_library_round_trip() {
+        let (db, _) = seeded_db();
+        assert!(!db.get_guild(1).unwrap().public_library);
+
+        db.set_guild_public_library(1, true).unwrap();
+        assert!(db.get_guild(1).unwrap().public_library);
+        assert!(db.get_active_guilds().unwrap()[0].public_library);
+
+        db.set_guild_public_library(1, false).unwrap();
+        assert!(!db.get_guild(1).unwrap().public_library);
+    }
+
+    #[test]
+    fn insert_channel_round_trip() {
+        let (db, _) = seeded_db();
+        db.upsert_guild(2, "other").unwrap();
+
+        db.insert_channel(1, "lounge").unwrap();
+        assert_eq!(
+            db.get_guild_channel_settings(1).unwrap(),
+            [
+                (None, "lounge".to_string(), true),
+                (Some(100), "general".to_string(), true)
+            ]
+        );
+        // Intros can't be picked for it yet
+        assert_eq!(
+            db.get_guild_channels(1).unwrap(),
+            [(100, "general".to_string())]
+        );
+
+        assert!(matches!(
+            db.insert_channel(1, "lounge"),
+            Err(Error::DuplicateChannelName)
+        ));
+        assert!(matches!(
+            db.insert_channel(1, "general"),
+            Err(Error::DuplicateChannelName)
+        ));
+
+        // Only unique within a guild
+        db.insert_channel(2, "general").unwrap();
+        assert_eq!(db.get_guild_channel_settings(2).unwrap().len(), 1);
+
+        // Syncing from discord fills in the id
+        db.upsert_guild_channels(
+            1,
+            &[(100, "general".to_string()), (101, "lounge".to_string())],
+        )
+        .unwrap();
+        assert_eq!(
+            db.get_channel_name(1, 101).unwrap().as_deref(),
+            Some("lounge")
+        );
+        assert_eq!(db.get_guild_channel_settings(1).unwrap().len(), 2);
+        assert_eq!(db.get_guild_channel_settings(2).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn guild_channels_synced() {
+        let (db, intro_id) = seeded_db();
+        db.insert_user_intro("bob", 1, 100, intro_id, IntroKind::Intro)
+            .unwrap();
+
+        db.upsert_guild_channels(
+            1,
+            &[(100, "general".to_string()), (101, "lounge".to_string())],
+        )
+        .unwrap();
+        assert_eq!(
+            db.get_guild_channels(1).unwrap(),
+            [(101, "lounge".to_string()), (100, "general".to_string())]
+        );
+        // Only the guild's own channels
+        assert_eq!(db.get_channel_name(2, 101).unwrap(), None);
+
+        // Renamed channels keep their intros
+        db.upsert_guild_channels(
+            1,
+            &[(100, "chatting".to_string()), (101, "lounge".to_string())],
+        )
+        .unwrap();
+        assert_eq!(
+            db.get_guild_channels(1).unwrap(),
+            [(101, "lounge".to_string()), (100, "chatting".to_string())]
+        );
+        assert_eq!(
+            db.get_intro_assignments(1, intro_id).unwrap(),
+            vec![("bob".to_string(), "chatting".to_string())]
+        );
+
+        // Removed channels take their intros with them
+        db.upsert_guild_channels(1, &[(101, "lounge".to_string())])
+            .unwrap();
+        assert_eq!(
+            db.get_guild_channels(1).unwrap(),
+            [(101, "lounge".to_string())]
+        );
+        assert!(db.get_intro_assignments(1, intro_id).unwrap().is_empty());
+    }
+
+    #[test]
+    fn same_named_channels_kept_apart() {
+        let (db, intro_id) = seeded_db();
+
+        db.upsert_guild_channels(
+            1,
+            &[(100, "general".to_string()), (101, "general".to_string())],
+        )
+        .unwrap();
+        assert_eq!(
+            db.get_guild_channels(1).unwrap(),
+            [(100, "general".to_string()), (101, "general".to_string())]
+        );
+        db.set_channel_intros_enabled(1, 101, false).unwrap();
+        assert!(db.channel_intros_enabled(1, 100).unwrap());
+        assert!(!db.channel_intros_enabled(1, 101).unwrap());
+
+        db.insert_user_intro("bob", 1, 100, intro_id, IntroKind::Intro)
+            .unwrap();
+        assert_eq!(
+            db.get_user_channel_intros("bob", 1, 100, IntroKind::Intro)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(db
+            .get_user_channel_intros("bob", 1, 101, IntroKind::Intro)
+            .unwrap()
+            .is_empty());
+
+        // Removing one of them leaves the other alone
+        db.upsert_guild_channels(1, &[(100, "general".to_string())])
+            .unwrap();
+        assert_eq!(
+            db.get_guild_channels(1).unwrap(),
+            [(100, "general".to_string())]
+        );
+        assert_eq!(
+            db.get_user_channel_intros("bob", 1, 100, IntroKind::Intro)
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn old_user_intros_keyed_on_channel_id() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        // Same as `migrate`, the rows below don't have users or intros to point at
+        conn.pragma_update(None, "foreign_keys", false).unwrap();
+        // Everything before 0022_channel_discord_id_key
+        let (before, after) = MIGRATIONS.split_at(21);
+        for migration in before {
+            conn.execute_batch(migration).unwrap();
+        }
+        conn.execute_batch(
+            "
+            insert into Guild (id, name, sound_delay) values (1, 'guild', 0);
+            insert into Channel (name, guild_id, id) values ('general', 1, 100);
+            insert into Channel (name, guild_id) values ('lounge', 1);
+            insert into UserIntro (username, intro_id, guild_id, channel_name, channel_id)
+            values
+                ('bob', 1, 1, 'general', null),
+                ('bob', 2, 1, 'general', 100),
+                ('bob', 3, 1, 'lounge', null);
+            ",
+        )
+        .unwrap();
+        for migration in after {
+            conn.execute_batch(migration).unwrap();
+        }
+
+        let user_intros = conn
+            .prepare("SELECT intro_id, channel_id FROM UserIntro ORDER BY intro_id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<(i32, u64)>>>()
+            .unwrap();
+        // The lounge was never synced, so there's no channel to key its intro on
+        assert_eq!(user_intros, [(1, 100), (2, 100)]);
+    }
+
+    #[test]
+    fn guildless_channels_backfilled_from_user_intros() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "foreign_keys", false).unwrap();
+        // Everything before 0016_channel_guild_key
+        let (before, after) = MIGRATIONS.split_at(15);
+        for migration in before {
+            conn.execute_batch(migration).unwrap();
+        }
+        conn.execute_batch(
+            "
+            insert into Guild (id, name, sound_delay) values (1, 'guild', 0);
+            insert into Channel (name, id) values ('general', 100), ('unused', null);
+            insert into UserIntro (username, intro_id, guild_id, channel_name, channel_id)
+            values ('bob', 1, 1, 'general', null);
+            ",
+        )
+        .unwrap();
+        for migration in after {
+            conn.execute_batch(migration).unwrap();
+        }
+
+        let channels = conn
+            .prepare("SELECT guild_id, id, name FROM Channel")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<(u64, u64, String)>>>()
+            .unwrap();
+        assert_eq!(channels, [(1, 100, "general".to_string())]);
+
+        let user_intros = conn
+            .prepare("SELECT intro_id, channel_id FROM UserIntro")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<(i32, u64)>>>()
+            .unwrap();
+        assert_eq!(user_intros, [(1, 100)]);
+    }
+
+    #[test]
+    fn channel_intros_toggled() {
+        let (db, _) = seeded_db();
+        assert!(db.channel_intros_enabled(1, 100).unwrap());
+
+        db.set_channel_intros_enabled(1, 100, false).unwrap();
+        assert!(!db.channel_intros_enabled(1, 100).unwrap());
+        assert_eq!(
+            db.get_guild_channel_settings(1).unwrap(),
+            [(Some(100), "general".to_string(), false)]
+        );
+
+        // Still disabled after being renamed on discord
+        db.upsert_guild_channels(1, &[(100, "chatting".to_string())])
+            .unwrap();
+        assert!(!db.channel_intros_enabled(1, 100).unwrap());
+
+        db.set_channel_intros_enabled(1, 100, true).unwrap();
+        assert!(db.channel_intros_enabled(1, 100).unwrap());
+        // Channels that haven't been synced yet play intros
+        assert!(db.channel_intros_enabled(1, 101).unwrap());
+    }
+
+    #[test]
+    fn intros_found_by_hash() {
+        let (db, _) = seeded_db();
+        db.upsert_guild(2, "other").unwrap();
+        assert!(db.find_intro_by_hash(1, "abc123").unwrap().is_none());
+
+        // The same sound uploaded twice under different names
+        let first_id = db
+            .insert_intro(
+                "first",
+                0,
+                1,
+                "first.mp3",
+                "bob",
+                Some("abc123"),
+                Some(5),
+                &[],
+            )
+            .unwrap();
+        let duplicate = db.find_intro_by_hash(1, "abc123").unwrap().unwrap();
+        assert_eq!(duplicate.id, first_id);
+        assert_eq!(duplicate.duration_secs, Some(5));
+        assert_eq!(duplicate.content_hash.as_deref(), Some("abc123"));
+
+        db.insert_intro(
+            "second",
+            0,
+            1,
+            &duplicate.filename,
+            "bob",
+            Some("abc123"),
+            None,
+            &[],
+        )
+        .unwrap();
+        assert_eq!(
+            db.find_intro_by_hash(1, "abc123").unwrap().unwrap().id,
+            first_id
+        );
+        assert!(db.find_intro_by_hash(2, "abc123").unwrap().is_none());
+
+        // The file sticks around until every intro using it is gone
+        db.delete_intro(1, first_id).unwrap();
+        assert!(db.intro_file_in_use("first.mp3").unwrap());
+        let second_id = db.find_intro_by_hash(1, "abc123").unwrap().unwrap().id;
+        db.delete_intro(1, second_id).unwrap();
+        assert!(!db.intro_file_in_use("first.mp3").unwrap());
+    }
 }
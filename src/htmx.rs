@@ -1,9 +1,26 @@
-use std::collections::HashMap;
-
 pub trait Build {
     fn build(self) -> String;
 }
 
+/// Escapes characters that would otherwise be interpreted as markup, safe for use in both text
+/// content and (quoted) attribute values.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
 #[derive(PartialEq)]
 pub enum Tag {
     Empty,
@@ -35,6 +52,7 @@ pub enum Tag {
     TableData,
 
     Progress,
+    Audio,
 
     Form,
     Label,
@@ -60,6 +78,7 @@ pub enum Tag {
     Header5,
     Header6,
     Strong,
+    Small,
     Paragraph,
     JustText,
 }
@@ -77,9 +96,10 @@ impl Tag {
             Self::Title => "title",
             Self::Body => "body",
             Self::Main => "main",
-            Self::Break => "break",
+            Self::Break => "br",
 
             Self::Progress => "progress",
+            Self::Audio => "audio",
 
             Self::Details => "details",
             Self::Summary => "summary",
@@ -122,7 +142,8 @@ impl Tag {
             Self::Header5 => "h5",
             Self::Header6 => "h6",
             Self::Strong => "strong",
-            Self::Paragraph => "paragraph",
+            Self::Small => "small",
+            Self::Paragraph => "p",
         }
     }
 
@@ -139,6 +160,7 @@ impl Tag {
             && *self != Self::Empty
             && *self != Self::Link
             && *self != Self::Input
+            && *self != Self::Break
         {
             format!("</{}>", self.as_str())
         } else {
@@ -147,11 +169,11 @@ impl Tag {
     }
 }
 
+/// Values for `hx-swap`. To reload the whole page respond with the `HX-Refresh` header instead
 pub enum SwapMethod {
     InnerHtml,
     OuterHtml,
     BeforeEnd,
-    Refresh,
 }
 
 impl SwapMethod {
@@ -160,14 +182,14 @@ impl SwapMethod {
             SwapMethod::InnerHtml => "innerHTML",
             SwapMethod::OuterHtml => "outerHTML",
             SwapMethod::BeforeEnd => "beforeend",
-            SwapMethod::Refresh => "refresh",
         }
     }
 }
 
 pub struct HtmxBuilder {
     tag: Tag,
-    attributes: HashMap<String, String>,
+    // Kept in insertion order so the rendered html is deterministic
+    attributes: Vec<(String, String)>,
     children: Vec<HtmxBuilder>,
     text: Option<String>,
 }
@@ -187,7 +209,7 @@ impl Build for HtmxBuilder {
                 if value.is_empty() {
                     string.push_str(&format!(" {attr} "));
                 } else {
-                    string.push_str(&format!(" {attr}='{value}' "));
+                    string.push_str(&format!(" {attr}='{}' ", escape(&value)));
                 }
             }
             if self.tag != Tag::JustText && self.tag != Tag::Empty {
@@ -196,7 +218,7 @@ impl Build for HtmxBuilder {
         }
 
         if let Some(text) = self.text {
-            string.push_str(&text);
+            string.push_str(&escape(&text));
         }
 
         for child in self.children {
@@ -213,19 +235,27 @@ impl HtmxBuilder {
     pub fn new(tag: Tag) -> Self {
         Self {
             tag,
-            attributes: HashMap::new(),
+            attributes: Vec::new(),
             children: Vec::new(),
             text: None,
         }
     }
 
+    fn set_attribute(&mut self, attr: &str, val: &str) {
+        if let Some((_, value)) = self.attributes.iter_mut().find(|(name, _)| name == attr) {
+            *value = val.to_string();
+        } else {
+            self.attributes.push((attr.to_string(), val.to_string()));
+        }
+    }
+
     pub fn push_builder(mut self, builder: HtmxBuilder) -> Self {
         self.children.push(builder);
         self
     }
 
     pub fn attribute(mut self, attr: &str, val: &str) -> Self {
-        self.attributes.insert(attr.to_string(), val.to_string());
+        self.set_attribute(attr, val);
         self
     }
 
@@ -237,6 +267,15 @@ impl HtmxBuilder {
         self.attribute("hx-post", uri)
     }
 
+    pub fn hx_delete(mut self, uri: &str) -> Self {
+        self.attribute("hx-delete", uri)
+    }
+
+    /// Asks the user to confirm with `message` before the request is sent
+    pub fn hx_confirm(mut self, message: &str) -> Self {
+        self.attribute("hx-confirm", message)
+    }
+
     pub fn hx_swap(mut self, swap_method: SwapMethod) -> Self {
         self.attribute("hx-swap", swap_method.as_str())
     }
@@ -301,7 +340,7 @@ impl HtmxBuilder {
     }
 
     pub fn flag(mut self, flag: &str) -> Self {
-        self.attributes.insert(flag.to_string(), "".to_string());
+        self.set_attribute(flag, "");
         self
     }
 
@@ -334,6 +373,47 @@ impl HtmxBuilder {
         self
     }
 
+    /// A grid with a column per header and a row per item in `rows`, `render_row` fills in the
+    /// `<tr>` for each item
+    pub fn table<R, F>(
+        mut self,
+        headers: &[&str],
+        rows: impl IntoIterator<Item = R>,
+        render_row: F,
+    ) -> HtmxBuilder
+    where
+        F: Fn(HtmxBuilder, R) -> HtmxBuilder,
+    {
+        let mut head = HtmxBuilder::new(Tag::TableRow);
+        for header in headers {
+            head = head.builder_text(Tag::TableHeader, header);
+        }
+
+        let mut body = HtmxBuilder::new(Tag::TableBody);
+        for row in rows {
+            body = body.push_builder(render_row(HtmxBuilder::new(Tag::TableRow), row));
+        }
+
+        self.children.push(
+            HtmxBuilder::new(Tag::Table)
+                .attribute("role", "grid")
+                .builder(Tag::TableHead, |b| b.push_builder(head))
+                .push_builder(body),
+        );
+        self
+    }
+
+    /// A collapsible section that only shows `summary_text` until it's opened
+    pub fn details<F>(mut self, summary_text: &str, builder_fn: F) -> HtmxBuilder
+    where
+        F: FnOnce(HtmxBuilder) -> HtmxBuilder,
+    {
+        self.children.push(builder_fn(
+            HtmxBuilder::new(Tag::Details).builder_text(Tag::Summary, summary_text),
+        ));
+        self
+    }
+
     pub fn label<F>(mut self, builder_fn: F) -> HtmxBuilder
     where
         F: FnOnce(HtmxBuilder) -> HtmxBuilder,
@@ -377,6 +457,32 @@ impl HtmxBuilder {
         self
     }
 
+    pub fn select<F>(mut self, builder_fn: F) -> HtmxBuilder
+    where
+        F: FnOnce(HtmxBuilder) -> HtmxBuilder,
+    {
+        self.children
+            .push(builder_fn(HtmxBuilder::new(Tag::Select)));
+        self
+    }
+
+    pub fn option(self, value: &str, label: &str) -> HtmxBuilder {
+        self.option_selected(value, label, false)
+    }
+
+    pub fn option_selected(mut self, value: &str, label: &str, selected: bool) -> HtmxBuilder {
+        let option = HtmxBuilder::new(Tag::Option)
+            .attribute("value", value)
+            .text(label);
+
+        self.children.push(if selected {
+            option.flag("selected")
+        } else {
+            option
+        });
+        self
+    }
+
     pub fn link(mut self, text: &str, href: &str) -> HtmxBuilder {
         // TODO: add href attribute
         self.children.push(
@@ -397,3 +503,136 @@ impl HtmxBuilder {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_renders_headers_and_rows() {
+        let html = HtmxBuilder::new(Tag::Empty)
+            .table(
+                &["Name", "Plays"],
+                [("hello", 1), ("bye", 2)],
+                |b, (name, plays)| {
+                    b.builder_text(Tag::TableData, name)
+                        .builder_text(Tag::TableData, &plays.to_string())
+                },
+            )
+            .build();
+
+        assert_eq!(
+            html,
+            "<table role='grid' >\
+                <thead><tr><th>Name</th><th>Plays</th></tr></thead>\
+                <tbody>\
+                    <tr><td>hello</td><td>1</td></tr>\
+                    <tr><td>bye</td><td>2</td></tr>\
+                </tbody>\
+            </table>"
+        );
+    }
+
+    #[test]
+    fn paragraphs_render_as_p() {
+        assert_eq!(
+            HtmxBuilder::new(Tag::Paragraph).text("hi").build(),
+            "<p>hi</p>"
+        );
+    }
+
+    #[test]
+    fn breaks_render_as_a_void_element() {
+        assert_eq!(HtmxBuilder::new(Tag::Break).build(), "<br>");
+    }
+
+    #[test]
+    fn attributes_render_in_insertion_order() {
+        let html = HtmxBuilder::new(Tag::Input)
+            .attribute("type", "text")
+            .attribute("name", "intro")
+            .attribute("placeholder", "it's an intro")
+            .build();
+
+        assert_eq!(
+            html,
+            "<input type='text'  name='intro'  placeholder='it&#39;s an intro' >"
+        );
+    }
+
+    #[test]
+    fn select_renders_its_options() {
+        let html = HtmxBuilder::new(Tag::Empty)
+            .select(|b| {
+                b.attribute("name", "volume")
+                    .option("50", "Quiet")
+                    .option_selected("100", "Loud", true)
+            })
+            .build();
+
+        assert_eq!(
+            html,
+            "<select name='volume' >\
+                <option value='50' >Quiet</option>\
+                <option value='100'  selected >Loud</option>\
+            </select>"
+        );
+    }
+
+    #[test]
+    fn delete_buttons_confirm_first() {
+        let html = HtmxBuilder::new(Tag::Button)
+            .hx_delete("/v2/intros/remove/1/general/2")
+            .hx_confirm("Remove \"hello\"?")
+            .text("Remove")
+            .build();
+
+        assert_eq!(
+            html,
+            "<button hx-delete='/v2/intros/remove/1/general/2'  hx-confirm='Remove &quot;hello&quot;?' >\
+                Remove\
+            </button>"
+        );
+    }
+
+    #[test]
+    fn swap_methods_are_valid_htmx() {
+        // Everything htmx accepts for `hx-swap`
+        const VALID: [&str; 8] = [
+            "innerHTML",
+            "outerHTML",
+            "beforebegin",
+            "afterbegin",
+            "beforeend",
+            "afterend",
+            "delete",
+            "none",
+        ];
+
+        for swap_method in [
+            SwapMethod::InnerHtml,
+            SwapMethod::OuterHtml,
+            SwapMethod::BeforeEnd,
+        ] {
+            let value = swap_method.as_str();
+            assert!(VALID.contains(&value), "{value}");
+
+            assert_eq!(
+                HtmxBuilder::new(Tag::Div).hx_swap(swap_method).build(),
+                format!("<div hx-swap='{value}' ></div>")
+            );
+        }
+    }
+
+    #[test]
+    fn details_render_summary_first() {
+        let html = HtmxBuilder::new(Tag::Empty)
+            .details("general", |b| b.builder_text(Tag::Paragraph, "intros"))
+            .build();
+
+        assert_eq!(
+            html,
+            "<details><summary>general</summary><p>intros</p></details>"
+        );
+    }
+}
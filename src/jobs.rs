@@ -0,0 +1,148 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Semaphore;
+use tracing::error;
+use uuid::Uuid;
+
+use crate::routes::Error;
+
+/// How long finished jobs are remembered for, the browser only needs to see them finish once
+const FINISHED_JOB_RETENTION: Duration = Duration::from_secs(10 * 60);
+
+/// Where a background media job (normalizing an upload, downloading from a url) is at
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum JobStatus {
+    /// Waiting for its turn to run
+    Pending,
+    Running,
+    /// Finished, with the id of the intro it added
+    Done(i32),
+    Failed(String),
+}
+
+struct Job {
+    guild_id: u64,
+    status: JobStatus,
+    finished_at: Option<Instant>,
+}
+
+/// In-memory tracking of media jobs so the browser can poll for their progress
+#[derive(Clone, Default)]
+pub(crate) struct Jobs(Arc<Mutex<HashMap<String, Job>>>);
+
+impl Jobs {
+    /// Runs `job` in the background right away, returning its id. `kind` labels the job in metrics.
+    pub(crate) fn spawn<F>(&self, guild_id: u64, kind: &'static str, job: F) -> String
+    where
+        F: Future<Output = Result<i32, Error>> + Send + 'static,
+    {
+        self.spawn_inner(guild_id, kind, None, job)
+    }
+
+    /// Runs `job` in the background once a permit from `permits` is available, returning its id
+    pub(crate) fn spawn_limited<F>(
+        &self,
+        guild_id: u64,
+        kind: &'static str,
+        permits: Arc<Semaphore>,
+        job: F,
+    ) -> String
+    where
+        F: Future<Output = Result<i32, Error>> + Send + 'static,
+    {
+        self.spawn_inner(guild_id, kind, Some(permits), job)
+    }
+
+    /// Status of a job, as long as it belongs to `guild_id`
+    pub(crate) fn status(&self, guild_id: u64, job_id: &str) -> Option<JobStatus> {
+        self.0
+            .lock()
+            .expect("jobs lock was poisoned")
+            .get(job_id)
+            .filter(|job| job.guild_id == guild_id)
+            .map(|job| job.status.clone())
+    }
+
+    fn spawn_inner<F>(
+        &self,
+        guild_id: u64,
+        kind: &'static str,
+        permits: Option<Arc<Semaphore>>,
+        job: F,
+    ) -> String
+    where
+        F: Future<Output = Result<i32, Error>> + Send + 'static,
+    {
+        let job_id = Uuid::new_v4().to_string();
+
+        {
+            let mut jobs = self.0.lock().expect("jobs lock was poisoned");
+            jobs.retain(|_, job| {
+                job.finished_at.map_or(true, |finished_at| {
+                    finished_at.elapsed() < FINISHED_JOB_RETENTION
+                })
+            });
+            jobs.insert(
+                job_id.clone(),
+                Job {
+                    guild_id,
+                    status: JobStatus::Pending,
+                    finished_at: None,
+                },
+            );
+        }
+
+        let jobs = self.clone();
+        let id = job_id.clone();
+        tokio::spawn(async move {
+            let _permit = match permits {
+                Some(permits) => Some(
+                    permits
+                        .acquire_owned()
+                        .await
+                        .expect("job semaphore is never closed"),
+                ),
+                None => None,
+            };
+
+            jobs.set_status(&id, JobStatus::Running);
+
+            let status = match job.await {
+                Ok(intro_id) => JobStatus::Done(intro_id),
+                Err(err) => {
+                    error!(?err, job_id = %id, kind, "media job failed");
+                    JobStatus::Failed(err.to_string())
+                }
+            };
+
+            let result = if matches!(status, JobStatus::Done(_)) {
+                "success"
+            } else {
+                "failure"
+            };
+            metrics::increment_counter!("memejoin_jobs_total", "kind" => kind, "result" => result);
+            jobs.set_status(&id, status);
+        });
+
+        job_id
+    }
+
+    fn set_status(&self, job_id: &str, status: JobStatus) {
+        if let Some(job) = self
+            .0
+            .lock()
+            .expect("jobs lock was poisoned")
+            .get_mut(job_id)
+        {
+            if matches!(status, JobStatus::Done(_) | JobStatus::Failed(_)) {
+                job.finished_at = Some(Instant::now());
+            }
+            job.status = status;
+        }
+    }
+}
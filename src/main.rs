@@ -5,39 +5,77 @@
 mod auth;
 mod db;
 mod htmx;
+mod jobs;
 mod media;
+mod now_playing;
 mod page;
+mod rate_limit;
 mod routes;
 pub mod settings;
+mod voice_state;
 
-use axum::http::Method;
-use axum::routing::{get, post};
+use axum::extract::DefaultBodyLimit;
+use axum::http::{HeaderValue, Method};
+use axum::routing::{delete, get, post};
 use axum::Router;
+use now_playing::NowPlaying;
+use rate_limit::RateLimiter;
 use settings::ApiState;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use tokio::sync::mpsc;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, Semaphore};
+use tokio::task::JoinHandle;
 use tower_http::cors::{Any, CorsLayer};
+use voice_state::JoinDebouncer;
 
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use rand::seq::SliceRandom;
+use rand::Rng;
 use serenity::async_trait;
-use serenity::model::prelude::{Channel, ChannelId, GuildId, Member, Ready};
+use serenity::model::prelude::{
+    Channel, ChannelId, ChannelType, Guild, GuildChannel, GuildId, Member, Ready, UnavailableGuild,
+    UserId,
+};
 use serenity::model::voice::VoiceState;
 use serenity::prelude::GatewayIntents;
 use serenity::prelude::*;
 use songbird::SerenityInit;
 use tracing::*;
 
-use crate::settings::Settings;
+use crate::settings::{MediaSettings, Settings, SweeperSettings};
 
 enum HandlerMessage {
     Ready(Context),
     PlaySound(Context, Member, ChannelId),
+    PlayOutro(Context, Member, ChannelId),
+    /// Play an intro on demand for a user if they're in the channel, its name is for `NowPlaying`
+    PlaySoundboard(GuildId, ChannelId, String, String, db::Intro),
     TrackEnded(GuildId),
+    /// Sent once a guild's call has sat idle for the configured timeout, only the latest
+    /// generation for a guild is acted on
+    IdleTimeout(GuildId, u64),
+    /// The bot was added to (or is starting up in) a guild
+    GuildCreate(Context, GuildId, String),
+    /// The bot was removed from a guild
+    GuildDelete(GuildId),
 }
 
 struct Handler {
     tx: std::sync::Mutex<mpsc::Sender<HandlerMessage>>,
+    joins: JoinDebouncer,
+    /// Set once the bot is ready, so it can tell its own voice state updates apart
+    bot_id: OnceLock<UserId>,
+}
+
+impl Handler {
+    fn is_self(&self, member: &Member) -> bool {
+        self.bot_id
+            .get()
+            .is_some_and(|&bot_id| voice_state::is_self(member, bot_id))
+    }
 }
 
 struct TrackEventHandler {
@@ -65,9 +103,35 @@ impl songbird::EventHandler for TrackEventHandler {
     }
 }
 
+/// Keeps the active voice connections gauge up to date
+struct VoiceConnectionHandler;
+
+#[async_trait]
+impl songbird::EventHandler for VoiceConnectionHandler {
+    async fn act<'a, 'b, 'c>(
+        &'a self,
+        ctx: &'b songbird::EventContext<'c>,
+    ) -> Option<songbird::Event> {
+        match ctx {
+            songbird::EventContext::DriverConnect(_) => {
+                metrics::increment_gauge!("memejoin_voice_connections", 1.0);
+            }
+            songbird::EventContext::DriverDisconnect(_) => {
+                metrics::decrement_gauge!("memejoin_voice_connections", 1.0);
+            }
+            _ => {}
+        }
+
+        None
+    }
+}
+
 #[async_trait]
 impl EventHandler for Handler {
     async fn ready(&self, ctx: Context, ready: Ready) {
+        // Ready fires again after a reconnect, but it's always the same bot
+        let _ = self.bot_id.set(ready.user.id);
+
         let tx = self
             .tx
             .lock()
@@ -81,10 +145,56 @@ impl EventHandler for Handler {
         info!("{} is ready", ready.user.name);
     }
 
+    async fn guild_create(&self, ctx: Context, guild: Guild, _is_new: bool) {
+        let tx = self
+            .tx
+            .lock()
+            .expect("couldn't get lock for Handler messenger")
+            .clone();
+
+        if let Err(err) = tx
+            .send(HandlerMessage::GuildCreate(ctx, guild.id, guild.name))
+            .await
+        {
+            error!("Failed to send guild create message to handler: {err}");
+        }
+    }
+
+    async fn guild_delete(
+        &self,
+        _ctx: Context,
+        incomplete: UnavailableGuild,
+        _full: Option<Guild>,
+    ) {
+        // Discord outages also show up as a guild delete, only leaving the guild counts
+        if incomplete.unavailable {
+            return;
+        }
+
+        let tx = self
+            .tx
+            .lock()
+            .expect("couldn't get lock for Handler messenger")
+            .clone();
+
+        if let Err(err) = tx.send(HandlerMessage::GuildDelete(incomplete.id)).await {
+            error!("Failed to send guild delete message to handler: {err}");
+        }
+    }
+
     async fn voice_state_update(&self, ctx: Context, old: Option<VoiceState>, new: VoiceState) {
-        if old.is_none() {
+        let joined = new.member.as_ref().map_or(false, |member| {
+            self.joins.is_join(
+                member.guild_id.0,
+                member.user.id.0,
+                old.as_ref().and_then(|old| old.channel_id).map(|id| id.0),
+                new.channel_id.map(|id| id.0),
+            )
+        });
+
+        if joined {
             if let (Some(member), Some(channel_id)) = (new.member, new.channel_id) {
-                if member.user.name == "MemeJoin" {
+                if self.is_self(&member) {
                     return;
                 }
 
@@ -112,11 +222,180 @@ impl EventHandler for Handler {
                     error!("Failed to send play sound message to handler: {err}");
                 }
             }
+        } else if let (Some(old_channel_id), None) =
+            (old.and_then(|old| old.channel_id), new.channel_id)
+        {
+            if let Some(member) = new.member {
+                if self.is_self(&member) {
+                    return;
+                }
+
+                info!(
+                    "{}#{} left voice channel {:?} in {:?}",
+                    member.user.name,
+                    member.user.discriminator,
+                    old_channel_id.name(&ctx.cache).await,
+                    member
+                        .guild_id
+                        .name(&ctx.cache)
+                        .unwrap_or("no_guild_name".to_string())
+                );
+
+                let tx = self
+                    .tx
+                    .lock()
+                    .expect("couldn't get lock for Handler messenger")
+                    .clone();
+
+                if let Err(err) = tx
+                    .send(HandlerMessage::PlayOutro(ctx, member, old_channel_id))
+                    .await
+                {
+                    error!("Failed to send play outro message to handler: {err}");
+                }
+            }
         }
     }
 }
 
-fn spawn_api(db: Arc<tokio::sync::Mutex<db::Database>>) {
+fn pick_intro<'a>(intros: &'a [db::Intro], rng: &mut impl Rng) -> Option<&'a db::Intro> {
+    intros.choose(rng)
+}
+
+/// Every page and endpoint the api serves, kept apart from [`spawn_api`] so tests can route requests
+fn api_router(state: ApiState, cors_origins: Vec<HeaderValue>) -> Router {
+    let max_upload_bytes = state.max_upload_bytes;
+
+    Router::new()
+        .route("/", get(page::home))
+        .route("/index.html", get(page::home))
+        .route("/login", get(page::login))
+        .route("/guild/:guild_id", get(page::guild_dashboard))
+        .route("/guild/:guild_id/public", get(page::public_guild_library))
+        .route("/guild/:guild_id/setup", post(routes::v2_set_up_guild))
+        .route(
+            "/guild/:guild_id/permissions/update",
+            post(routes::update_guild_permissions),
+        )
+        .route(
+            "/guild/:guild_id/roles/update",
+            post(routes::update_guild_role_permissions),
+        )
+        .route(
+            "/guild/:guild_id/settings",
+            post(routes::update_guild_settings),
+        )
+        .route(
+            "/guild/:guild_id/channels/update",
+            post(routes::update_guild_channels),
+        )
+        .route(
+            "/guild/:guild_id/channels/add",
+            post(routes::add_guild_channel),
+        )
+        .route(
+            "/guild/:guild_id/maintenance/vacuum",
+            post(routes::vacuum_database),
+        )
+        .route("/v2/auth", get(routes::v2_auth))
+        .route(
+            "/v2/intros/add/:guild_id/:channel_id",
+            post(routes::v2_add_intro_to_user),
+        )
+        .route(
+            "/v2/intros/remove/:guild_id/:channel_id",
+            post(routes::v2_remove_intro_from_user),
+        )
+        .route(
+            "/v2/intros/remove/:guild_id/:channel_id/:intro_id",
+            delete(routes::v2_delete_intro_from_user),
+        )
+        .route(
+            "/v2/intros/volume/:guild_id/:channel_id/:intro_id",
+            post(routes::v2_set_user_intro_volume),
+        )
+        .route(
+            "/v2/intros/enabled/:guild_id/:channel_id/:intro_id",
+            post(routes::v2_set_user_intro_enabled),
+        )
+        .route(
+            "/v2/outros/add/:guild_id/:channel_id",
+            post(routes::v2_add_outro_to_user),
+        )
+        .route(
+            "/v2/outros/remove/:guild_id/:channel_id",
+            post(routes::v2_remove_outro_from_user),
+        )
+        .route(
+            "/v2/outros/remove/:guild_id/:channel_id/:intro_id",
+            delete(routes::v2_delete_outro_from_user),
+        )
+        .route(
+            "/v2/outros/volume/:guild_id/:channel_id/:intro_id",
+            post(routes::v2_set_user_outro_volume),
+        )
+        .route(
+            "/v2/outros/enabled/:guild_id/:channel_id/:intro_id",
+            post(routes::v2_set_user_outro_enabled),
+        )
+        .route("/v2/guild/:guild_id/events", get(routes::v2_guild_events))
+        .route("/v2/intros/:guild", get(routes::v2_list_guild_intros))
+        .route("/v2/intros/:guild/add", post(routes::v2_add_guild_intro))
+        .route(
+            "/v2/intros/:guild/search",
+            get(routes::v2_search_guild_intros),
+        )
+        .route(
+            "/v2/intros/:guild/upload",
+            post(routes::v2_upload_guild_intro).layer(DefaultBodyLimit::max(max_upload_bytes)),
+        )
+        .route(
+            "/v2/intros/:guild/preview/:intro_id",
+            get(routes::v2_preview_guild_intro),
+        )
+        .route(
+            "/v2/intros/:guild/job/:job_id",
+            get(routes::v2_guild_intro_job),
+        )
+        .route(
+            "/v2/intros/:guild/users/:intro_id",
+            get(routes::v2_list_guild_intro_users),
+        )
+        .route(
+            "/v2/intros/:guild/rename/:intro_id",
+            post(routes::v2_rename_guild_intro),
+        )
+        .route(
+            "/v2/intros/:guild/delete/:intro_id",
+            post(routes::v2_delete_guild_intro).delete(routes::v2_delete_guild_intro),
+        )
+        .route(
+            "/v2/guild/:guild_id/permissions",
+            get(routes::v2_list_guild_permissions),
+        )
+        .route(
+            "/v2/soundboard/:guild_id/:channel_id/:intro_id",
+            post(routes::v2_play_soundboard),
+        )
+        .route("/health", get(routes::health))
+        .route("/metrics", get(routes::prometheus_metrics))
+        .layer(
+            CorsLayer::new()
+                .allow_origin(cors_origins)
+                .allow_headers(Any)
+                .allow_methods([Method::GET, Method::POST, Method::DELETE]),
+        )
+        .with_state(state)
+}
+
+fn spawn_api(
+    db: db::Database,
+    bot_tx: mpsc::Sender<HandlerMessage>,
+    metrics: PrometheusHandle,
+    now_playing: broadcast::Sender<NowPlaying>,
+    settings: &Settings,
+    mut shutdown: broadcast::Receiver<()>,
+) -> JoinHandle<()> {
     let secrets = auth::DiscordSecret {
         client_id: env::var("DISCORD_CLIENT_ID").expect("expected DISCORD_CLIENT_ID env var"),
         client_secret: env::var("DISCORD_CLIENT_SECRET")
@@ -128,55 +407,52 @@ fn spawn_api(db: Arc<tokio::sync::Mutex<db::Database>>) {
         db,
         secrets,
         origin: origin.clone(),
+        bot_tx,
+        media: settings.media.clone(),
+        ytdl_limiter: Arc::new(RateLimiter::new(Duration::from_secs(
+            settings.media.ytdl_cooldown_secs,
+        ))),
+        ytdl_permits: Arc::new(Semaphore::new(settings.media.max_concurrent_ytdl.max(1))),
+        jobs: Default::default(),
+        metrics,
+        max_intros_per_channel: settings.max_intros_per_channel,
+        max_upload_bytes: settings.max_upload_bytes,
+        now_playing,
+        operators: settings.operators.clone(),
+        vacuum_lock: Default::default(),
     };
+    let cors_origins = settings
+        .cors_origins(&origin)
+        .unwrap_or_else(|err| panic!("{err}"));
 
     tokio::spawn(async move {
-        let api = Router::new()
-            .route("/", get(page::home))
-            .route("/index.html", get(page::home))
-            .route("/login", get(page::login))
-            .route("/guild/:guild_id", get(page::guild_dashboard))
-            .route(
-                "/guild/:guild_id/permissions/update",
-                post(routes::update_guild_permissions),
-            )
-            .route("/v2/auth", get(routes::v2_auth))
-            .route(
-                "/v2/intros/add/:guild_id/:channel",
-                post(routes::v2_add_intro_to_user),
-            )
-            .route(
-                "/v2/intros/remove/:guild_id/:channel",
-                post(routes::v2_remove_intro_from_user),
-            )
-            .route("/v2/intros/:guild/add", get(routes::v2_add_guild_intro))
-            .route(
-                "/v2/intros/:guild/upload",
-                post(routes::v2_upload_guild_intro),
-            )
-            .route("/health", get(routes::health))
-            .layer(
-                CorsLayer::new()
-                    .allow_origin([origin.parse().unwrap()])
-                    .allow_headers(Any)
-                    .allow_methods([Method::GET, Method::POST, Method::DELETE]),
-            )
-            .with_state(state);
+        let api = api_router(state, cors_origins);
         let addr = SocketAddr::from(([0, 0, 0, 0], 8100));
         info!("socket listening on {addr}");
         axum::Server::bind(&addr)
             .serve(api.into_make_service())
+            .with_graceful_shutdown(async move {
+                let _ = shutdown.recv().await;
+            })
             .await
             .unwrap();
-    });
+
+        info!("api shut down");
+    })
 }
 
-async fn spawn_bot(db: Arc<tokio::sync::Mutex<db::Database>>) {
+async fn spawn_bot(
+    db: db::Database,
+    tx: mpsc::Sender<HandlerMessage>,
+    mut rx: mpsc::Receiver<HandlerMessage>,
+    media_settings: MediaSettings,
+    idle_timeout: Duration,
+    now_playing: broadcast::Sender<NowPlaying>,
+    mut shutdown: broadcast::Receiver<()>,
+) -> JoinHandle<()> {
     let token = env::var("DISCORD_TOKEN").expect("expected DISCORD_TOKEN env var");
     let songbird = songbird::Songbird::serenity();
 
-    let (tx, mut rx) = mpsc::channel(10);
-
     let intents = GatewayIntents::GUILDS
         | GatewayIntents::GUILD_MESSAGES
         | GatewayIntents::MESSAGE_CONTENT
@@ -184,12 +460,15 @@ async fn spawn_bot(db: Arc<tokio::sync::Mutex<db::Database>>) {
     let mut client = Client::builder(&token, intents)
         .event_handler(Handler {
             tx: std::sync::Mutex::new(tx.clone()),
+            joins: JoinDebouncer::new(REJOIN_DEBOUNCE),
+            bot_id: OnceLock::new(),
         })
         .register_songbird_with(songbird.clone())
         .await
         .expect("Error creating client");
 
     info!("Starting bot with token '{token}'");
+    let shard_manager = client.shard_manager.clone();
     tokio::spawn(async move {
         if let Err(err) = client.start().await {
             error!("An error occurred while running the client: {err:?}");
@@ -197,14 +476,30 @@ async fn spawn_bot(db: Arc<tokio::sync::Mutex<db::Database>>) {
     });
 
     tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
+        // Kept around for handling messages that don't come from a discord event
+        let mut ready_ctx: Option<Context> = None;
+        // Bumped every time a guild's queue empties so older idle timers can be ignored
+        let mut idle_generations: HashMap<GuildId, u64> = HashMap::new();
+        // A guild's call keeps its global events across reconnects, adding them again on the next
+        // ready would run every handler twice
+        let mut guilds_with_events: HashSet<GuildId> = HashSet::new();
+
+        loop {
+            let msg = tokio::select! {
+                msg = rx.recv() => msg,
+                _ = shutdown.recv() => break,
+            };
+            let Some(msg) = msg else {
+                break;
+            };
+
             match msg {
                 HandlerMessage::Ready(ctx) => {
                     info!("Got Ready message");
 
-                    let songbird = songbird::get(&ctx).await.expect("no songbird instance");
+                    ready_ctx = Some(ctx.clone());
 
-                    let guilds = match db.lock().await.get_guilds() {
+                    let guilds = match db.get_active_guilds() {
                         Ok(guilds) => guilds,
                         Err(err) => {
                             error!(?err, "failed to get guild on bot ready");
@@ -212,96 +507,604 @@ async fn spawn_bot(db: Arc<tokio::sync::Mutex<db::Database>>) {
                         }
                     };
 
-                    for guild in guilds {
-                        let handler_lock = songbird.get_or_insert(GuildId(guild.id));
-
-                        let mut handler = handler_lock.lock().await;
-
-                        handler.add_global_event(
-                            songbird::Event::Track(songbird::TrackEvent::End),
-                            TrackEventHandler {
-                                tx: tx.clone(),
-                                guild_id: GuildId(guild.id),
-                            },
-                        );
+                    for guild in &guilds {
+                        if guilds_with_events.insert(GuildId(guild.id)) {
+                            add_guild_events(&songbird, &tx, GuildId(guild.id)).await;
+                        }
                     }
+
+                    tokio::spawn(sync_guild_channels(
+                        ctx,
+                        db.clone(),
+                        guilds.iter().map(|guild| GuildId(guild.id)).collect(),
+                    ));
                 }
                 HandlerMessage::TrackEnded(guild_id) => {
                     info!("Got TrackEnded message");
 
                     if let Some(manager) = songbird.get(guild_id) {
                         let mut handler = manager.lock().await;
-                        let queue = handler.queue();
+                        if !handler.queue().is_empty() {
+                            continue;
+                        }
+
+                        let has_listeners = match (&ready_ctx, handler.current_channel()) {
+                            (Some(ctx), Some(channel_id)) => {
+                                has_listeners(ctx, ChannelId(channel_id.0)).await
+                            }
+                            _ => false,
+                        };
 
-                        if queue.is_empty() {
+                        if idle_timeout.is_zero() || !has_listeners {
                             info!("Track Queue is empty, leaving voice channel");
                             if let Err(err) = handler.leave().await {
                                 error!("Failed to leave channel: {err:?}");
                             }
+                        } else {
+                            let generation = idle_generations.entry(guild_id).or_default();
+                            *generation += 1;
+
+                            let generation = *generation;
+                            let tx = tx.clone();
+                            tokio::spawn(async move {
+                                tokio::time::sleep(idle_timeout).await;
+
+                                let _ = tx
+                                    .send(HandlerMessage::IdleTimeout(guild_id, generation))
+                                    .await;
+                            });
+                        }
+                    }
+                }
+                HandlerMessage::IdleTimeout(guild_id, generation) => {
+                    // Another track ended since this timer started, its own timer will handle it
+                    if idle_generations.get(&guild_id) != Some(&generation) {
+                        continue;
+                    }
+
+                    if let Some(manager) = songbird.get(guild_id) {
+                        let mut handler = manager.lock().await;
+
+                        if handler.queue().is_empty() {
+                            info!("Idle for {idle_timeout:?}, leaving voice channel");
+                            if let Err(err) = handler.leave().await {
+                                error!("Failed to leave channel: {err:?}");
+                            }
                         }
                     }
                 }
 
+                HandlerMessage::GuildCreate(ctx, guild_id, name) => {
+                    info!("Got GuildCreate message for {name}");
+
+                    if let Err(err) = db.upsert_guild(guild_id.0, &name) {
+                        error!(?err, %guild_id, "failed to add guild");
+                        continue;
+                    }
+
+                    // Same setup the guilds that were already known get on ready
+                    if guilds_with_events.insert(guild_id) {
+                        add_guild_events(&songbird, &tx, guild_id).await;
+                    }
+                    tokio::spawn(sync_guild_channels(ctx, db.clone(), vec![guild_id]));
+                }
+                HandlerMessage::GuildDelete(guild_id) => {
+                    info!("Got GuildDelete message");
+
+                    if let Err(err) = db.deactivate_guild(guild_id.0) {
+                        error!(?err, %guild_id, "failed to deactivate guild");
+                    }
+                }
+
                 HandlerMessage::PlaySound(ctx, member, channel_id) => {
                     info!("Got PlaySound message");
 
-                    let Some(Channel::Guild(channel)) = channel_id.to_channel_cached(&ctx.cache)
-                    else {
-                        error!("Failed to get cached channel from member!");
+                    play_sound(
+                        &ctx,
+                        &db,
+                        &media_settings,
+                        &songbird,
+                        &now_playing,
+                        member,
+                        channel_id,
+                        db::IntroKind::Intro,
+                    )
+                    .await;
+                }
+                HandlerMessage::PlayOutro(ctx, member, channel_id) => {
+                    info!("Got PlayOutro message");
+
+                    play_sound(
+                        &ctx,
+                        &db,
+                        &media_settings,
+                        &songbird,
+                        &now_playing,
+                        member,
+                        channel_id,
+                        db::IntroKind::Outro,
+                    )
+                    .await;
+                }
+                HandlerMessage::PlaySoundboard(
+                    guild_id,
+                    soundboard_channel_id,
+                    channel_name,
+                    username,
+                    intro,
+                ) => {
+                    info!("Got PlaySoundboard message");
+
+                    let Some(ctx) = &ready_ctx else {
+                        error!("Bot isn't ready yet, can't play soundboard intro");
                         continue;
                     };
 
-                    let intros = match db.lock().await.get_user_channel_intros(
-                        &member.user.name,
-                        channel.guild_id.0,
-                        channel.name(),
-                    ) {
-                        Ok(intros) => intros,
-                        Err(err) => {
-                            error!(
-                                ?err,
-                                "failed to get user channel intros when playing sound through bot"
-                            );
-                            continue;
-                        }
+                    let Some(guild) = guild_id.to_guild_cached(&ctx.cache) else {
+                        error!("Failed to get cached guild for soundboard!");
+                        continue;
                     };
 
-                    // TODO: randomly choose a intro to play
-                    let Some(intro) = intros.first() else {
-                        error!("couldn't get user intro, none exist");
+                    let Some(channel_id) = guild
+                        .members
+                        .values()
+                        .find(|member| member.user.name == username)
+                        .and_then(|member| guild.voice_states.get(&member.user.id))
+                        .and_then(|voice_state| voice_state.channel_id)
+                    else {
+                        info!("{username} isn't in a voice channel, not playing soundboard intro");
                         continue;
                     };
 
-                    let source = match songbird::ffmpeg(format!("sounds/{}", &intro.filename)).await
-                    {
-                        Ok(source) => source,
+                    if channel_id != soundboard_channel_id {
+                        info!("{username} isn't in {channel_name}, not playing soundboard intro");
+                        continue;
+                    }
+
+                    let db_guild = match db.get_guild(guild_id.0) {
+                        Ok(guild) => guild,
                         Err(err) => {
-                            error!(
-                                "Error starting file source from {}: {err:?}",
-                                intro.filename
-                            );
+                            error!(?err, "failed to get guild when playing soundboard intro");
                             continue;
                         }
                     };
 
-                    match songbird.join(member.guild_id, channel_id).await {
-                        (handler_lock, Ok(())) => {
-                            let mut handler = handler_lock.lock().await;
+                    let event = NowPlaying {
+                        guild_id: guild_id.0,
+                        username,
+                        channel: channel_name,
+                        intro: intro.name.clone(),
+                    };
+                    spawn_playback(
+                        db.clone(),
+                        &media_settings,
+                        songbird.clone(),
+                        &db_guild,
+                        guild_id,
+                        channel_id,
+                        &intro,
+                        Duration::ZERO,
+                        &now_playing,
+                        event,
+                    );
+                }
+            }
+        }
 
-                            let _track_handler = handler.enqueue_source(source);
-                            // TODO: set volume
-                        }
+        info!("shutting down bot");
 
-                        (_, Err(err)) => {
-                            error!("Failed to join voice channel {}: {err:?}", channel.name());
-                        }
+        let guilds = db.get_active_guilds().unwrap_or_else(|err| {
+            error!(?err, "failed to get guilds when shutting down");
+            Vec::new()
+        });
+        futures::future::join_all(
+            guilds
+                .into_iter()
+                .map(|guild| leave_after_queue(&songbird, GuildId(guild.id))),
+        )
+        .await;
+
+        shard_manager.lock().await.shutdown_all().await;
+
+        info!("bot shut down");
+    })
+}
+
+/// Lets whatever is queued up in a guild finish playing before leaving its voice channel.
+/// Shutdown puts a time limit on this, so it won't wait forever on a long queue.
+async fn leave_after_queue(songbird: &songbird::Songbird, guild_id: GuildId) {
+    let Some(call) = songbird.get(guild_id) else {
+        return;
+    };
+
+    while !call.lock().await.queue().is_empty() {
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+
+    if let Err(err) = songbird.remove(guild_id).await {
+        error!("Failed to leave channel in {guild_id}: {err:?}");
+    }
+}
+
+/// Hooks up the events the bot needs from a guild's call, for ending tracks and keeping the
+/// voice connection metrics up to date
+async fn add_guild_events(
+    songbird: &songbird::Songbird,
+    tx: &mpsc::Sender<HandlerMessage>,
+    guild_id: GuildId,
+) {
+    let handler_lock = songbird.get_or_insert(guild_id);
+
+    let mut handler = handler_lock.lock().await;
+
+    handler.add_global_event(
+        songbird::Event::Track(songbird::TrackEvent::End),
+        TrackEventHandler {
+            tx: tx.clone(),
+            guild_id,
+        },
+    );
+    handler.add_global_event(
+        songbird::Event::Core(songbird::CoreEvent::DriverConnect),
+        VoiceConnectionHandler,
+    );
+    handler.add_global_event(
+        songbird::Event::Core(songbird::CoreEvent::DriverDisconnect),
+        VoiceConnectionHandler,
+    );
+}
+
+/// Keeps the voice channels shown on the dashboard in line with what's actually in discord
+async fn sync_guild_channels(ctx: Context, db: db::Database, guild_ids: Vec<GuildId>) {
+    for guild_id in guild_ids {
+        let channels = match guild_id.channels(&ctx).await {
+            Ok(channels) => channels,
+            Err(err) => {
+                error!(?err, %guild_id, "failed to get guild channels");
+                continue;
+            }
+        };
+
+        let voice_channels = channels
+            .into_values()
+            .filter(|channel| channel.kind == ChannelType::Voice)
+            .map(|channel| (channel.id.0, channel.name))
+            .collect::<Vec<_>>();
+
+        if let Err(err) = db.upsert_guild_channels(guild_id.0, &voice_channels) {
+            error!(?err, %guild_id, "failed to sync guild channels");
+        }
+    }
+}
+
+/// Channels fetched over http because they weren't in the cache yet
+struct FetchedChannels;
+
+impl TypeMapKey for FetchedChannels {
+    type Value = HashMap<ChannelId, GuildChannel>;
+}
+
+// The cache takes a bit to warm up after the bot starts, so members joining right away
+// would otherwise never get their intros played
+async fn guild_channel(ctx: &Context, channel_id: ChannelId) -> Option<GuildChannel> {
+    if let Some(Channel::Guild(channel)) = channel_id.to_channel_cached(&ctx.cache) {
+        return Some(channel);
+    }
+
+    let fetched = ctx
+        .data
+        .read()
+        .await
+        .get::<FetchedChannels>()
+        .and_then(|channels| channels.get(&channel_id).cloned());
+    if fetched.is_some() {
+        return fetched;
+    }
+
+    match channel_id.to_channel(ctx).await {
+        Ok(Channel::Guild(channel)) => {
+            ctx.data
+                .write()
+                .await
+                .entry::<FetchedChannels>()
+                .or_insert_with(HashMap::new)
+                .insert(channel_id, channel.clone());
+
+            Some(channel)
+        }
+        Ok(_) => {
+            error!(%channel_id, "channel isn't a guild channel");
+            None
+        }
+        Err(err) => {
+            error!(?err, %channel_id, "failed to fetch channel");
+            None
+        }
+    }
+}
+
+/// Whether anyone besides bots is still in `channel_id`
+async fn has_listeners(ctx: &Context, channel_id: ChannelId) -> bool {
+    let Some(channel) = guild_channel(ctx, channel_id).await else {
+        return false;
+    };
+
+    match channel.members(&ctx.cache).await {
+        Ok(members) => members.iter().any(|member| !member.user.bot),
+        Err(err) => {
+            error!(?err, "failed to get channel members");
+            false
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn play_sound(
+    ctx: &Context,
+    db: &db::Database,
+    media_settings: &MediaSettings,
+    songbird: &Arc<songbird::Songbird>,
+    now_playing: &broadcast::Sender<NowPlaying>,
+    member: Member,
+    channel_id: ChannelId,
+    kind: db::IntroKind,
+) {
+    // Most joins are from users without any intros, so find that out before resolving the
+    // channel or anything else
+    let intros = match db.get_user_channel_intros(
+        &member.user.name,
+        member.guild_id.0,
+        channel_id.0,
+        kind,
+    ) {
+        Ok(intros) => intros,
+        Err(err) => {
+            error!(
+                ?err,
+                "failed to get user channel intros when playing sound through bot"
+            );
+            return;
+        }
+    };
+
+    // Users that haven't picked anything get the guild's default intro, if it has one. Users
+    // that paused everything they picked don't get anything.
+    let intros = if intros.is_empty() {
+        match db.get_user_channel_picks(&member.user.name, member.guild_id.0, channel_id.0, kind) {
+            Ok(picks) if !picks.is_empty() => return,
+            Ok(_) => {}
+            Err(err) => {
+                error!(?err, "failed to get user channel picks");
+                return;
+            }
+        }
+
+        match db.get_guild_default_intro(member.guild_id.0) {
+            Ok(default_intro) => default_intro.into_iter().collect(),
+            Err(err) => {
+                error!(?err, "failed to get guild default intro");
+                return;
+            }
+        }
+    } else {
+        intros
+    };
+
+    let Some(intro) = pick_intro(&intros, &mut rand::thread_rng()) else {
+        error!("couldn't get user intro, none exist");
+        return;
+    };
+
+    let Some(channel) = guild_channel(ctx, channel_id).await else {
+        error!("Failed to get channel from member!");
+        return;
+    };
+
+    // Don't bother joining an empty channel just to play an outro
+    if kind == db::IntroKind::Outro {
+        match channel.members(&ctx.cache).await {
+            Ok(members) if members.iter().any(|member| !member.user.bot) => {}
+            Ok(_) => {
+                info!("Nobody left in {} to hear the outro", channel.name());
+                return;
+            }
+            Err(err) => {
+                error!(?err, "failed to get channel members when playing outro");
+                return;
+            }
+        }
+    }
+
+    let guild = match db.get_guild(channel.guild_id.0) {
+        Ok(guild) => guild,
+        Err(err) => {
+            error!(?err, "failed to get guild when playing sound through bot");
+            return;
+        }
+    };
+
+    match db.channel_intros_enabled(channel.guild_id.0, channel.id.0) {
+        Ok(true) => {}
+        Ok(false) => {
+            info!(
+                "Intros are disabled in {}, not playing sound",
+                channel.name()
+            );
+            return;
+        }
+        Err(err) => {
+            error!(?err, "failed to check if intros are enabled for channel");
+            return;
+        }
+    }
+
+    if let Some(permission) = guild.restrict_to_permission {
+        let user_permissions = db
+            .get_user_permissions(&member.user.name, channel.guild_id.0)
+            .unwrap_or_default();
+
+        if !user_permissions.can(permission) {
+            info!(
+                "{} doesn't have {} permission, not playing intro",
+                member.user.name,
+                permission.to_string()
+            );
+            return;
+        }
+    }
+
+    let event = NowPlaying {
+        guild_id: member.guild_id.0,
+        username: member.user.name.clone(),
+        channel: channel.name().to_string(),
+        intro: intro.name.clone(),
+    };
+    // `sound_delay` is in milliseconds
+    let sound_delay = Duration::from_millis(guild.sound_delay.into());
+    spawn_playback(
+        db.clone(),
+        media_settings,
+        songbird.clone(),
+        &guild,
+        member.guild_id,
+        channel_id,
+        intro,
+        sound_delay,
+        now_playing,
+        event,
+    );
+}
+
+// Play from a separate task so a long delay doesn't hold up intros in other guilds.
+// The delay only applies when the bot has to join the channel first, anything played while it's
+// already connected just gets added to the end of the queue. `event` goes out to `now_playing`
+// once the sound has actually been queued.
+#[allow(clippy::too_many_arguments)]
+fn spawn_playback(
+    db: db::Database,
+    media_settings: &MediaSettings,
+    songbird: Arc<songbird::Songbird>,
+    guild: &db::Guild,
+    guild_id: GuildId,
+    channel_id: ChannelId,
+    intro: &db::Intro,
+    delay: Duration,
+    now_playing: &broadcast::Sender<NowPlaying>,
+    event: NowPlaying,
+) {
+    let now_playing = now_playing.clone();
+    let path = media::sound_path(media_settings, &intro.filename);
+    let volume = guild.clamp_volume(intro.volume_scale());
+    let intro_id = intro.id;
+
+    tokio::spawn(async move {
+        let source = match songbird::ffmpeg(&path).await {
+            Ok(source) => source,
+            Err(err) => {
+                error!("Error starting file source from {path}: {err:?}");
+                return;
+            }
+        };
+
+        let handler_lock = songbird.get_or_insert(guild_id);
+
+        // Figure out whether a join is needed while holding the lock, so members joining at the
+        // same time get their intros appended to one queue instead of each re-joining.
+        let join = {
+            let mut handler = handler_lock.lock().await;
+
+            match handler.current_channel() {
+                // Covers a join that's still in progress too, `current_channel` is set as soon as
+                // `join` is called
+                Some(current) if current == channel_id.into() => {
+                    info!("Reusing existing connection to {channel_id} in {guild_id}");
+                    None
+                }
+                // Can't be in two channels at once, and moving would cut off whatever is playing
+                Some(_) if !handler.queue().is_empty() => {
+                    info!("Already playing in another channel in {guild_id}, skipping intro");
+                    return;
+                }
+                _ => match handler.join(channel_id).await {
+                    Ok(join) => Some(join),
+                    Err(err) => {
+                        error!("Failed to join voice channel {channel_id}: {err:?}");
+                        return;
                     }
+                },
+            }
+        };
+
+        // NOTE: the lock can't be held here, finishing the connection needs it
+        if let Some(join) = join {
+            if let Err(err) = join.await {
+                error!("Failed to join voice channel {channel_id}: {err:?}");
+                return;
+            }
+            info!("Joined {channel_id} in {guild_id}");
+
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+        }
+
+        let mut handler = handler_lock.lock().await;
+
+        let track_handle = handler.enqueue_source(source);
+        if let Err(err) = track_handle.set_volume(volume) {
+            error!("Failed to set track volume: {err:?}");
+        }
+        drop(handler);
+
+        record_play(&db, &now_playing, event, intro_id);
+    });
+}
+
+/// Everything that happens once an intro has been queued up, none of it affects the playback
+fn record_play(
+    db: &db::Database,
+    now_playing: &broadcast::Sender<NowPlaying>,
+    event: NowPlaying,
+    intro_id: i32,
+) {
+    metrics::increment_counter!("memejoin_intros_played_total");
+    // Fails when no dashboards are watching, which is fine
+    let _ = now_playing.send(event);
+
+    if let Err(err) = db.increment_intro_play(intro_id) {
+        error!(?err, intro_id, "failed to increment intro play count");
+    }
+}
+
+fn spawn_sweeper(settings: SweeperSettings, media_settings: &MediaSettings) {
+    let targets = media::sweep_targets(media_settings, &settings);
+
+    tokio::spawn(async move {
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(settings.interval_secs.max(1)));
+
+        loop {
+            interval.tick().await;
+
+            for target in &targets {
+                let dir = target.dir.as_str();
+                match media::sweep(target).await {
+                    Ok(removed) => info!(dir, removed, "swept old files"),
+                    Err(err) => error!(?err, dir, "failed to sweep old files"),
                 }
             }
         }
     });
 }
 
+const SETTINGS_PATH: &str = "config/settings.json";
+
+/// Rejoining the same voice channel within this long won't play another intro
+const REJOIN_DEBOUNCE: Duration = Duration::from_secs(5);
+
+/// How long in-flight requests and playback get to finish up once shutdown starts
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[tokio::main]
 #[instrument]
 async fn main() -> std::io::Result<()> {
@@ -309,22 +1112,80 @@ async fn main() -> std::io::Result<()> {
 
     tracing_subscriber::fmt::init();
 
-    let settings = serde_json::from_str::<Settings>(
-        &std::fs::read_to_string("config/settings.json").expect("no config/settings.json"),
-    )
-    .expect("error parsing settings file");
+    // Lets a deploy check its config without starting anything up
+    if env::args().skip(1).any(|arg| arg == "--check-config") {
+        match Settings::load(SETTINGS_PATH) {
+            Ok(_) => {
+                println!("{SETTINGS_PATH} is valid");
+                std::process::exit(0);
+            }
+            Err(err) => {
+                eprintln!("{err}");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let settings = match Settings::load(SETTINGS_PATH) {
+        Ok(settings) => settings,
+        Err(err) => {
+            error!("{err}");
+            std::process::exit(1);
+        }
+    };
     info!("{settings:?}");
 
     let (run_api, run_bot) = (settings.run_api, settings.run_bot);
-    let db = Arc::new(tokio::sync::Mutex::new(
-        db::Database::new("./config/db.sqlite").expect("couldn't open sqlite db"),
-    ));
+    let db = db::Database::new("./config/db.sqlite").expect("couldn't open sqlite db");
+
+    media::create_dirs(&settings.media).expect("couldn't create sounds directory");
+    spawn_sweeper(settings.sweeper.clone(), &settings.media);
+
+    let missing_dependencies = media::check_dependencies().await;
+    if !missing_dependencies.is_empty() {
+        error!(
+            ?missing_dependencies,
+            "couldn't run some required programs, make sure they're installed and on PATH. \
+            Sounds won't be uploaded or played until they are!"
+        );
+    }
+
+    let (bot_tx, bot_rx) = mpsc::channel(10);
+    let (now_playing_tx, _) = broadcast::channel(now_playing::CAPACITY);
+    let (shutdown_tx, _) = broadcast::channel(1);
+    let mut tasks = Vec::new();
 
     if run_api {
-        spawn_api(db.clone());
+        // Only installed with the api running, there'd be nowhere to scrape it from otherwise
+        let metrics = PrometheusBuilder::new()
+            .install_recorder()
+            .expect("failed to install metrics recorder");
+
+        tasks.push(spawn_api(
+            db.clone(),
+            bot_tx.clone(),
+            metrics,
+            now_playing_tx.clone(),
+            &settings,
+            shutdown_tx.subscribe(),
+        ));
     }
     if run_bot {
-        spawn_bot(db).await;
+        tasks.push(
+            spawn_bot(
+                db,
+                bot_tx,
+                bot_rx,
+                settings.media.clone(),
+                Duration::from_secs(settings.idle_timeout_secs),
+                now_playing_tx,
+                shutdown_tx.subscribe(),
+            )
+            .await,
+        );
+    } else {
+        // Nothing will ever read the messages, so let senders know right away
+        drop(bot_rx);
     }
 
     info!("spawned background tasks");
@@ -332,5 +1193,123 @@ async fn main() -> std::io::Result<()> {
     let _ = tokio::signal::ctrl_c().await;
     info!("Received Ctrl-C, shuttdown down.");
 
+    let _ = shutdown_tx.send(());
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, futures::future::join_all(tasks))
+        .await
+        .is_err()
+    {
+        warn!("timed out waiting for background tasks to shut down");
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    fn intro(id: i32) -> db::Intro {
+        db::Intro {
+            id,
+            name: format!("intro {id}"),
+            filename: format!("{id}.mp3"),
+            volume: 0,
+            duration_secs: None,
+            uploaded_by: None,
+            created_at: chrono::Utc::now().naive_utc(),
+            play_count: 0,
+            content_hash: None,
+        }
+    }
+
+    #[test]
+    fn plays_show_up_in_metrics() {
+        let metrics = PrometheusBuilder::new()
+            .install_recorder()
+            .expect("failed to install metrics recorder");
+        let (state, user, _dir) = settings::test_state("record-play");
+        let intro_id = state
+            .db
+            .insert_intro("hello", 0, 1, "hello.mp3", &user.name, None, None, &[])
+            .unwrap();
+        let mut rx = state.now_playing.subscribe();
+
+        let event = NowPlaying {
+            guild_id: 1,
+            username: user.name.clone(),
+            channel: "general".to_string(),
+            intro: "hello".to_string(),
+        };
+        record_play(&state.db, &state.now_playing, event, intro_id);
+
+        assert!(
+            metrics
+                .render()
+                .contains("memejoin_intros_played_total 1\n"),
+            "{}",
+            metrics.render()
+        );
+        assert_eq!(rx.try_recv().unwrap().intro, "hello");
+        assert_eq!(state.db.get_guild_intros(1).unwrap()[0].play_count, 1);
+    }
+
+    #[test]
+    fn nothing_picked_without_intros() {
+        assert!(pick_intro(&[], &mut StdRng::seed_from_u64(0)).is_none());
+    }
+
+    #[test]
+    fn only_intro_always_picked() {
+        let intros = [intro(1)];
+        let mut rng = StdRng::seed_from_u64(0);
+
+        for _ in 0..10 {
+            assert_eq!(pick_intro(&intros, &mut rng).map(|intro| intro.id), Some(1));
+        }
+    }
+
+    #[tokio::test]
+    async fn picks_removed_from_channels_named_like_routes() {
+        let (state, user, _dir) = settings::test_state("channel-named-delete");
+        state
+            .db
+            .upsert_guild_channels(1, &[(100, "delete".to_string())])
+            .unwrap();
+        state
+            .db
+            .insert_user_permission(&user.name, 1, auth::Permissions::all())
+            .unwrap();
+        let intro_id = state
+            .db
+            .insert_intro("hello", 0, 1, "hello.mp3", &user.name, None, None, &[])
+            .unwrap();
+        state
+            .db
+            .insert_user_intro(&user.name, 1, 100, intro_id, db::IntroKind::Intro)
+            .unwrap();
+
+        let db = state.db.clone();
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(api_router(state, Vec::new()).into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let response = reqwest::Client::new()
+            .delete(format!("http://{addr}/v2/intros/remove/1/100/{intro_id}"))
+            .header("cookie", "access_token=api_key")
+            .header(settings::CSRF_HEADER, settings::csrf_token(&user))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+        assert!(db
+            .get_user_channel_intros(&user.name, 1, 100, db::IntroKind::Intro)
+            .unwrap()
+            .is_empty());
+        // Only the pick is gone, not the guild's intro
+        assert_eq!(db.get_guild_intros(1).unwrap().len(), 1);
+    }
+}
@@ -1,20 +1,778 @@
-use crate::routes::Error;
+use std::{process::Stdio, time::Duration};
 
-pub(crate) async fn normalize(src: &str, dest: &str) -> Result<(), Error> {
-    let child = tokio::process::Command::new("ffmpeg")
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::{
+    routes::Error,
+    settings::{AudioFormat, MediaSettings, SweeperSettings},
+};
+
+pub(crate) fn sound_path(settings: &MediaSettings, filename: &str) -> String {
+    format!("{}/{filename}", settings.sounds_dir)
+}
+
+/// Where uploads are written to before being normalized
+pub(crate) fn temp_dir(settings: &MediaSettings) -> String {
+    format!("{}/temp", settings.sounds_dir)
+}
+
+pub(crate) fn temp_path(settings: &MediaSettings, filename: &str) -> String {
+    format!("{}/{filename}", temp_dir(settings))
+}
+
+/// Makes sure the sounds directory and its temp directory exist
+pub(crate) fn create_dirs(settings: &MediaSettings) -> std::io::Result<()> {
+    std::fs::create_dir_all(temp_dir(settings))
+}
+
+/// Deletes the file at its path when dropped, so error paths don't leave files lying around.
+/// Call [`TempFile::keep`] once the file should stick around.
+pub(crate) struct TempFile {
+    path: String,
+    keep: bool,
+}
+
+impl TempFile {
+    pub(crate) fn new(path: String) -> Self {
+        Self { path, keep: false }
+    }
+
+    pub(crate) fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub(crate) fn keep(mut self) {
+        self.keep = true;
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if self.keep {
+            return;
+        }
+
+        match std::fs::remove_file(&self.path) {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => warn!(?err, path = %self.path, "failed to remove temporary file"),
+        }
+    }
+}
+
+/// A directory of a test's own under the system temp dir, removed along with everything in it
+/// when dropped
+#[cfg(test)]
+pub(crate) struct TestDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    pub(crate) fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("memejoin-{name}-{}", std::process::id()));
+        // Left behind by a run that was killed before it could clean up
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+
+        Self(path)
+    }
+
+    pub(crate) fn path(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+
+    /// Path of `name` inside the directory
+    pub(crate) fn join(&self, name: &str) -> String {
+        format!("{}/{name}", self.path())
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A directory the sweeper prunes, along with which of its files it's allowed to remove
+pub(crate) struct SweepTarget {
+    pub(crate) dir: String,
+    pub(crate) retention: Duration,
+    /// Given a file's name, whether it's transient
+    pub(crate) is_transient: fn(&str) -> bool,
+}
+
+/// Every place files are left while on their way to becoming a sound
+pub(crate) fn sweep_targets(media: &MediaSettings, sweeper: &SweeperSettings) -> Vec<SweepTarget> {
+    vec![
+        SweepTarget {
+            dir: temp_dir(media),
+            retention: Duration::from_secs(sweeper.temp_retention_secs),
+            is_transient: |_| true,
+        },
+        // yt-dlp downloads straight into the sounds directory, so only its own in-progress files
+        // are fair game there
+        SweepTarget {
+            dir: media.sounds_dir.clone(),
+            retention: Duration::from_secs(sweeper.partial_download_retention_secs),
+            is_transient: is_partial_download,
+        },
+    ]
+}
+
+/// Removes every transient file in the target's directory that hasn't been modified within its
+/// retention, returning how many were removed.
+pub(crate) async fn sweep(target: &SweepTarget) -> std::io::Result<usize> {
+    let mut removed = 0;
+
+    let mut entries = tokio::fs::read_dir(&target.dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        if !metadata.is_file() || !(target.is_transient)(&entry.file_name().to_string_lossy()) {
+            continue;
+        }
+
+        let age = metadata.modified()?.elapsed().unwrap_or_default();
+        if age > target.retention {
+            tokio::fs::remove_file(entry.path()).await?;
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+// Keep error output short enough to show to a user, ffmpeg especially likes to be chatty
+const MAX_STDERR_SUMMARY_LINES: usize = 5;
+const MAX_STDERR_SUMMARY_LEN: usize = 500;
+
+/// Condenses a process's stderr down to its last few lines, which is where the actual
+/// reason for failing usually is.
+pub(crate) fn stderr_summary(stderr: &[u8]) -> String {
+    let stderr = String::from_utf8_lossy(stderr);
+
+    let lines = stderr
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>();
+    let summary = lines[lines.len().saturating_sub(MAX_STDERR_SUMMARY_LINES)..].join("\n");
+
+    if summary.chars().count() > MAX_STDERR_SUMMARY_LEN {
+        let truncated = summary
+            .chars()
+            .skip(summary.chars().count() - MAX_STDERR_SUMMARY_LEN)
+            .collect::<String>();
+
+        format!("...{truncated}")
+    } else {
+        summary
+    }
+}
+
+/// External programs that sounds are processed and played with
+const DEPENDENCIES: [&str; 3] = ["ffmpeg", "ffprobe", "yt-dlp"];
+
+/// Runs each external program once to make sure it's installed, returning the ones that
+/// couldn't be run.
+pub(crate) async fn check_dependencies() -> Vec<&'static str> {
+    missing_programs(&DEPENDENCIES).await
+}
+
+async fn missing_programs<'a>(programs: &[&'a str]) -> Vec<&'a str> {
+    let mut missing = Vec::new();
+
+    for &program in programs {
+        // yt-dlp only understands `--version`, ffmpeg and ffprobe accept it as well
+        let status = tokio::process::Command::new(program)
+            .arg("--version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .await;
+
+        if !matches!(status, Ok(status) if status.success()) {
+            missing.push(program);
+        }
+    }
+
+    missing
+}
+
+/// Hex encoded SHA-256 of the file at `path`, identical sounds end up with the same hash.
+/// The file is streamed through the hasher on a blocking thread rather than read in all at once.
+pub(crate) async fn content_hash(path: &str) -> std::io::Result<String> {
+    let path = path.to_string();
+
+    tokio::task::spawn_blocking(move || {
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+
+        Ok(format!("{:x}", hasher.finalize()))
+    })
+    .await
+    .map_err(std::io::Error::other)?
+}
+
+/// Checks that `path` has at least one audio stream ffmpeg can decode, so garbage uploads get
+/// turned away with a clear error instead of whatever ffmpeg happens to complain about.
+/// Returns the codec of the first audio stream.
+pub(crate) async fn probe_audio(path: &str) -> Result<String, Error> {
+    let output = tokio::process::Command::new("ffprobe")
+        .args(["-v", "error"])
+        .args(["-select_streams", "a"])
+        .args(["-show_entries", "stream=codec_name"])
+        .args(["-of", "csv=p=0"])
+        .arg(path)
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .map_err(|err| Error::Ffmpeg(err.to_string()))?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let codec = stdout.lines().map(str::trim).find(|line| !line.is_empty());
+
+    match codec {
+        Some(codec) if output.status.success() => Ok(codec.to_string()),
+        _ => Err(Error::UnsupportedAudio),
+    }
+}
+
+/// Length in whole seconds from ffprobe's `format=duration` output, which is `N/A` for streams it
+/// can't work out a length for
+fn parse_duration(output: &str) -> Option<u32> {
+    let secs = output
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())?
+        .parse::<f64>()
+        .ok()?;
+
+    (secs.is_finite() && secs >= 0.0).then(|| secs.round() as u32)
+}
+
+/// How long the sound at `path` is. Only used for display, so anything going wrong just means
+/// the length isn't known.
+pub(crate) async fn probe_duration(path: &str) -> Option<u32> {
+    let output = tokio::process::Command::new("ffprobe")
+        .args(["-v", "error"])
+        .args(["-show_entries", "format=duration"])
+        .args(["-of", "default=noprint_wrappers=1:nokey=1"])
+        .arg(path)
+        .stderr(Stdio::piped())
+        .output()
+        .await;
+
+    match output {
+        Ok(output) if output.status.success() => {
+            parse_duration(&String::from_utf8_lossy(&output.stdout))
+        }
+        Ok(output) => {
+            warn!(path, stderr = %stderr_summary(&output.stderr), "couldn't probe sound duration");
+            None
+        }
+        Err(err) => {
+            warn!(path, ?err, "couldn't run ffprobe");
+            None
+        }
+    }
+}
+
+/// Extension to keep audio in `codec` as, when it's good enough to store without re-encoding it.
+/// Anything lossless or already opus would only lose quality by being converted.
+fn passthrough_extension(codec: &str) -> Option<&'static str> {
+    match codec {
+        "opus" => Some("opus"),
+        "flac" => Some("flac"),
+        codec if codec.starts_with("pcm_") => Some("wav"),
+        _ => None,
+    }
+}
+
+/// ffmpeg output options that trim and level audio according to `settings`
+fn output_args(settings: &MediaSettings) -> Vec<String> {
+    let mut args = vec![
+        "-t".to_string(),
+        settings.max_duration_secs.to_string(),
+        "-af".to_string(),
+        // Single pass loudnorm, close enough for short clips and avoids running ffmpeg twice
+        format!("loudnorm=I={}:TP=-1.5:LRA=11", settings.loudness_target),
+    ];
+
+    if settings.audio_format == AudioFormat::Opus {
+        // Same rate discord voice uses, there's no point storing anything better
+        args.extend(["-c:a", "libopus", "-b:a", "64k"].map(str::to_string));
+    }
+
+    args
+}
+
+/// Filename a new sound called `name` gets stored as, given the configured audio format
+pub(crate) fn sound_filename(name: &str, settings: &MediaSettings) -> String {
+    format!("{name}.{}", settings.audio_format.extension())
+}
+
+/// Filename an upload called `name` gets stored as, uploads in `codec` that aren't re-encoded
+/// keep a matching extension
+pub(crate) fn upload_filename(name: &str, codec: &str, settings: &MediaSettings) -> String {
+    match passthrough_extension(codec) {
+        Some(extension) => format!("{name}.{extension}"),
+        None => sound_filename(name, settings),
+    }
+}
+
+/// Strips everything but the audio out of `src`, trims it, and levels its loudness before
+/// writing it to `dest`. Audio in `codec` that's stored as is only gets trimmed, since leveling
+/// it would mean re-encoding it. Returns the length of `dest` in seconds, if it could be probed.
+pub(crate) async fn normalize(
+    src: &str,
+    dest: &str,
+    codec: &str,
+    settings: &MediaSettings,
+) -> Result<Option<u32>, Error> {
+    let args = if passthrough_extension(codec).is_some() {
+        vec![
+            "-t".to_string(),
+            settings.max_duration_secs.to_string(),
+            "-c:a".to_string(),
+            "copy".to_string(),
+        ]
+    } else {
+        output_args(settings)
+    };
+
+    let output = tokio::process::Command::new("ffmpeg")
         .args(["-i", src])
         .arg("-vn")
         .args(["-map", "0:a"])
+        .args(args)
         .arg(dest)
-        .spawn()
-        .map_err(|err| Error::Ffmpeg(err.to_string()))?
-        .wait()
+        .stderr(Stdio::piped())
+        .output()
         .await
         .map_err(|err| Error::Ffmpeg(err.to_string()))?;
 
-    if !child.success() {
-        return Err(Error::FfmpegTerminated);
+    if !output.status.success() {
+        return Err(Error::FfmpegTerminated(stderr_summary(&output.stderr)));
     }
 
-    Ok(())
+    Ok(probe_duration(dest).await)
+}
+
+/// Makes sure `url` is a plain web url before it gets anywhere near yt-dlp's arguments, and that
+/// it points at one of `allowed_domains` (or their subdomains) when any are configured.
+pub(crate) fn validate_url(url: &str, allowed_domains: &[String]) -> Result<reqwest::Url, Error> {
+    // Would be treated as an option by yt-dlp
+    if url.trim_start().starts_with('-') {
+        return Err(Error::InvalidRequest);
+    }
+
+    let url = reqwest::Url::parse(url).map_err(|_| Error::InvalidRequest)?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(Error::InvalidRequest);
+    }
+
+    let Some(host) = url.host_str() else {
+        return Err(Error::InvalidRequest);
+    };
+
+    if !allowed_domains.is_empty()
+        && !allowed_domains.iter().any(|domain| {
+            host.eq_ignore_ascii_case(domain)
+                || host
+                    .to_ascii_lowercase()
+                    .ends_with(&format!(".{}", domain.to_ascii_lowercase()))
+        })
+    {
+        return Err(Error::InvalidRequest);
+    }
+
+    // Only single videos are downloaded, so don't pretend playlists or channels will work
+    if is_playlist_url(&url) {
+        return Err(Error::PlaylistUrl);
+    }
+
+    Ok(url)
+}
+
+fn is_playlist_url(url: &reqwest::Url) -> bool {
+    let path = url.path().to_ascii_lowercase();
+    if path.starts_with("/playlist")
+        || path.starts_with("/channel/")
+        || path.starts_with("/c/")
+        || path.starts_with("/user/")
+        || path.starts_with("/@")
+    {
+        return true;
+    }
+
+    // `watch?v=...&list=...` is still a single video with `--no-playlist`
+    let has_list = url.query_pairs().any(|(key, _)| key == "list");
+    let has_video = url.query_pairs().any(|(key, _)| key == "v");
+    has_list && !has_video
+}
+
+/// Downloads the audio of `url` into the sounds directory as `name` plus whatever extension
+/// yt-dlp ended up using, and returns the resulting filename
+pub(crate) async fn download(
+    url: &str,
+    name: &str,
+    settings: &MediaSettings,
+) -> Result<String, Error> {
+    let url = validate_url(url, &settings.ytdl_allowed_domains)?;
+    let dest = sound_path(settings, name);
+    let dest = dest.as_str();
+
+    let output = tokio::process::Command::new("yt-dlp")
+        .args(ytdl_args(&url, dest, settings))
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .map_err(Error::Ytdl)?;
+
+    if !output.status.success() {
+        remove_partial_downloads(dest).await;
+        return Err(Error::YtdlTerminated(stderr_summary(&output.stderr)));
+    }
+
+    find_download(
+        &settings.sounds_dir,
+        name,
+        settings.audio_format.extension(),
+    )
+    .await
+    .ok_or_else(|| Error::YtdlTerminated(format!("no output file was found for {name}")))
+}
+
+/// Arguments for yt-dlp to download the audio of `url` to `dest`
+fn ytdl_args(url: &reqwest::Url, dest: &str, settings: &MediaSettings) -> Vec<String> {
+    let postprocessor_args = format!("ExtractAudio+ffmpeg_o:{}", output_args(settings).join(" "));
+
+    [
+        "-o",
+        dest,
+        // A url with both a video and a playlist would otherwise download the whole playlist
+        "--no-playlist",
+        "-x",
+        "--audio-format",
+        settings.audio_format.extension(),
+        // Trim and level during audio extraction, same as uploads get in `normalize`
+        "--postprocessor-args",
+        &postprocessor_args,
+        // Nothing after this can be taken as an option
+        "--",
+        url.as_str(),
+    ]
+    .map(str::to_string)
+    .to_vec()
+}
+
+/// The extension yt-dlp picks isn't always the one asked for, so look for whatever file it
+/// actually produced, preferring the `expected_ext` one
+async fn find_download(dir: &str, name: &str, expected_ext: &str) -> Option<String> {
+    let expected = format!("{name}.{expected_ext}");
+    let prefix = format!("{name}.");
+
+    let mut found = None;
+    let mut entries = tokio::fs::read_dir(dir).await.ok()?;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if !entry.file_type().await.is_ok_and(|ty| ty.is_file()) {
+            continue;
+        }
+
+        let filename = entry.file_name().to_string_lossy().to_string();
+        if filename == expected {
+            return Some(filename);
+        }
+
+        if filename.starts_with(&prefix) && !is_partial_download(&filename) {
+            found = Some(filename);
+        }
+    }
+
+    found
+}
+
+/// Files yt-dlp writes while it's still downloading or converting
+fn is_partial_download(filename: &str) -> bool {
+    [".part", ".ytdl", ".temp"]
+        .iter()
+        .any(|ext| filename.ends_with(ext))
+}
+
+/// yt-dlp leaves behind things like `<dest>.webm.part` when it fails part way through
+async fn remove_partial_downloads(dest: &str) {
+    let dest = std::path::Path::new(dest);
+    let (Some(dir), Some(prefix)) = (dest.parent(), dest.file_name()) else {
+        return;
+    };
+    let prefix = prefix.to_string_lossy();
+
+    let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+        return;
+    };
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(prefix.as_ref())
+        {
+            if let Err(err) = tokio::fs::remove_file(entry.path()).await {
+                warn!(?err, path = ?entry.path(), "failed to remove partial download");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lossless_and_opus_uploads_keep_their_format() {
+        let settings = MediaSettings::default();
+
+        assert_eq!(
+            upload_filename("intro", "pcm_s16le", &settings),
+            "intro.wav"
+        );
+        assert_eq!(
+            upload_filename("intro", "pcm_f32le", &settings),
+            "intro.wav"
+        );
+        assert_eq!(upload_filename("intro", "flac", &settings), "intro.flac");
+        assert_eq!(upload_filename("intro", "opus", &settings), "intro.opus");
+    }
+
+    #[test]
+    fn other_uploads_use_the_configured_format() {
+        let mut settings = MediaSettings::default();
+        assert_eq!(upload_filename("intro", "aac", &settings), "intro.mp3");
+
+        settings.audio_format = AudioFormat::Opus;
+        assert_eq!(upload_filename("intro", "vorbis", &settings), "intro.opus");
+    }
+
+    #[test]
+    fn durations_rounded_to_seconds() {
+        assert_eq!(parse_duration("5.024000\n"), Some(5));
+        assert_eq!(parse_duration("\n12.5\n"), Some(13));
+        assert_eq!(parse_duration("0.200000"), Some(0));
+    }
+
+    #[test]
+    fn unknown_durations() {
+        assert_eq!(parse_duration("N/A\n"), None);
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("-1.0"), None);
+    }
+
+    #[tokio::test]
+    async fn sweep_removes_only_old_transient_files() {
+        let dir = TestDir::new("sweep");
+
+        let day_ago = std::time::SystemTime::now() - Duration::from_secs(24 * 60 * 60);
+        let file = |name: &str, modified| {
+            let file = std::fs::File::create(dir.join(name)).unwrap();
+            file.set_modified(modified).unwrap();
+        };
+        file("old.webm.part", day_ago);
+        file("old.mp3", day_ago);
+        file("new.webm.part", std::time::SystemTime::now());
+
+        let target = SweepTarget {
+            dir: dir.path().to_string(),
+            retention: Duration::from_secs(60 * 60),
+            is_transient: is_partial_download,
+        };
+        assert_eq!(sweep(&target).await.unwrap(), 1);
+
+        let mut remaining = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        remaining.sort();
+        assert_eq!(remaining, ["new.webm.part", "old.mp3"]);
+    }
+
+    #[tokio::test]
+    async fn identical_files_hash_the_same() {
+        let dir = TestDir::new("hash");
+
+        let write = |name: &str, contents: &[u8]| {
+            let path = dir.join(name);
+            std::fs::write(&path, contents).unwrap();
+            path
+        };
+        let first = write("first.mp3", b"the same sound");
+        let second = write("second.mp3", b"the same sound");
+        let different = write("different.mp3", b"another sound");
+
+        let hash = content_hash(&first).await.unwrap();
+        assert_eq!(hash.len(), 64);
+        assert_eq!(hash, content_hash(&second).await.unwrap());
+        assert_ne!(hash, content_hash(&different).await.unwrap());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs ffprobe installed"]
+    async fn text_files_are_not_audio() {
+        let dir = TestDir::new("probe");
+        let path = dir.join("text.mp3");
+        std::fs::write(&path, "definitely not a sound\n").unwrap();
+
+        let result = probe_audio(&path).await;
+        assert!(matches!(result, Err(Error::UnsupportedAudio)));
+    }
+
+    #[test]
+    fn output_trimmed_to_max_duration() {
+        let settings = MediaSettings {
+            max_duration_secs: 7,
+            ..Default::default()
+        };
+
+        let args = output_args(&settings);
+        let trim = args.iter().position(|arg| arg == "-t").unwrap();
+        assert_eq!(args[trim + 1], "7");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs ffmpeg installed"]
+    async fn long_sounds_are_trimmed() {
+        let dir = TestDir::new("trim");
+        let path = |name: &str| dir.join(name);
+
+        let settings = MediaSettings::default();
+        // Stored as is and re-encoded sounds are trimmed with different arguments
+        for (src, codec, dest) in [
+            ("long.wav", "pcm_s16le", "trimmed.wav"),
+            ("long.mp3", "mp3", "trimmed.mp3"),
+        ] {
+            let status = tokio::process::Command::new("ffmpeg")
+                .args(["-f", "lavfi", "-i", "sine=frequency=440:duration=30"])
+                .arg(path(src))
+                .stderr(Stdio::null())
+                .status()
+                .await
+                .unwrap();
+            assert!(status.success());
+
+            let duration = normalize(&path(src), &path(dest), codec, &settings)
+                .await
+                .unwrap();
+            assert_eq!(duration, Some(settings.max_duration_secs), "{dest}");
+        }
+    }
+
+    /// Integrated loudness of the sound at `path` in LUFS, from the summary ffmpeg's ebur128
+    /// filter prints when it's done
+    async fn measure_loudness(path: &str) -> f32 {
+        let output = tokio::process::Command::new("ffmpeg")
+            .args(["-i", path])
+            .args(["-af", "ebur128", "-f", "null", "-"])
+            .output()
+            .await
+            .unwrap();
+
+        String::from_utf8_lossy(&output.stderr)
+            .lines()
+            .rev()
+            .find_map(|line| line.trim().strip_prefix("I:"))
+            .and_then(|line| line.trim().trim_end_matches("LUFS").trim().parse().ok())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs ffmpeg installed"]
+    async fn quiet_sounds_are_leveled() {
+        let dir = TestDir::new("loudness");
+        let src = dir.join("quiet.mp3");
+        let dest = dir.join("leveled.mp3");
+
+        let status = tokio::process::Command::new("ffmpeg")
+            .args(["-f", "lavfi", "-i", "sine=frequency=440:duration=5"])
+            .args(["-af", "volume=0.01"])
+            .arg(&src)
+            .stderr(Stdio::null())
+            .status()
+            .await
+            .unwrap();
+        assert!(status.success());
+
+        let settings = MediaSettings::default();
+        normalize(&src, &dest, "mp3", &settings).await.unwrap();
+
+        let before = measure_loudness(&src).await;
+        let after = measure_loudness(&dest).await;
+
+        assert!(
+            (after - settings.loudness_target).abs() < (before - settings.loudness_target).abs(),
+            "{before} LUFS went to {after} LUFS"
+        );
+    }
+
+    #[test]
+    fn option_and_non_web_urls_are_rejected() {
+        for url in [
+            "-o/etc/passwd",
+            "  --exec=rm https://youtube.com/watch?v=abc",
+            "file:///etc/passwd",
+            "ftp://youtube.com/watch?v=abc",
+        ] {
+            assert!(
+                matches!(validate_url(url, &[]), Err(Error::InvalidRequest)),
+                "{url}"
+            );
+        }
+
+        assert!(validate_url("https://youtube.com/watch?v=abc", &[]).is_ok());
+    }
+
+    #[tokio::test]
+    async fn missing_programs_are_reported() {
+        // Always around when running under cargo
+        let cargo = std::env::var("CARGO").unwrap();
+        let programs = ["memejoin-not-a-real-program", cargo.as_str()];
+
+        assert_eq!(
+            missing_programs(&programs).await,
+            ["memejoin-not-a-real-program"]
+        );
+    }
+
+    #[tokio::test]
+    async fn downloads_found_by_name() {
+        let dir = TestDir::new("find");
+        let file = |name: &str| std::fs::write(dir.join(name), "").unwrap();
+
+        // yt-dlp didn't use the extension that was asked for, and left a partial file behind
+        file("abc.webm.part");
+        file("abc.m4a");
+        file("other.mp3");
+        assert_eq!(
+            find_download(dir.path(), "abc", "mp3").await.as_deref(),
+            Some("abc.m4a")
+        );
+
+        // The expected extension wins when there's more than one
+        file("abc.mp3");
+        assert_eq!(
+            find_download(dir.path(), "abc", "mp3").await.as_deref(),
+            Some("abc.mp3")
+        );
+
+        assert_eq!(find_download(dir.path(), "missing", "mp3").await, None);
+    }
+
+    #[test]
+    fn only_single_videos_downloaded() {
+        let url = validate_url("https://youtube.com/watch?v=abc&list=def", &[]).unwrap();
+        let args = ytdl_args(&url, "sounds/abc", &MediaSettings::default());
+
+        assert!(args.iter().any(|arg| arg == "--no-playlist"), "{args:?}");
+        // The url comes last, after anything that could be read as an option
+        assert_eq!(args[args.len() - 2..], ["--", url.as_str()]);
+    }
 }
@@ -0,0 +1,70 @@
+use futures::Stream;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::warn;
+
+/// How many events a slow dashboard can fall behind by before it starts missing some
+pub(crate) const CAPACITY: usize = 16;
+
+/// Published by the bot whenever it queues up a sound, for dashboards to show live
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct NowPlaying {
+    pub(crate) guild_id: u64,
+    pub(crate) username: String,
+    pub(crate) channel: String,
+    pub(crate) intro: String,
+}
+
+/// Everything played in `guild_id` from now on. Ends once the bot's sender is gone, and dropping
+/// it (like when a client disconnects) unsubscribes.
+pub(crate) fn guild_stream(
+    rx: broadcast::Receiver<NowPlaying>,
+    guild_id: u64,
+) -> impl Stream<Item = NowPlaying> {
+    futures::stream::unfold(rx, move |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(now_playing) if now_playing.guild_id == guild_id => {
+                    return Some((now_playing, rx))
+                }
+                Ok(_) => {}
+                // Only means a few events were missed, later ones are still worth showing
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(skipped, guild_id, "now playing subscriber fell behind");
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+
+    fn now_playing(guild_id: u64, intro: &str) -> NowPlaying {
+        NowPlaying {
+            guild_id,
+            username: "bob".to_string(),
+            channel: "general".to_string(),
+            intro: intro.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn only_the_guilds_events() {
+        let (tx, rx) = broadcast::channel(CAPACITY);
+        let stream = guild_stream(rx, 1);
+
+        tx.send(now_playing(1, "hello")).unwrap();
+        tx.send(now_playing(2, "somewhere else")).unwrap();
+        tx.send(now_playing(1, "goodbye")).unwrap();
+        drop(tx);
+
+        assert_eq!(
+            stream.collect::<Vec<_>>().await,
+            [now_playing(1, "hello"), now_playing(1, "goodbye")]
+        );
+    }
+}
@@ -1,16 +1,23 @@
+use std::collections::HashMap;
+
 use crate::{
     auth::{self},
     db::{self, User},
-    htmx::{Build, HtmxBuilder, Tag},
-    settings::ApiState,
+    htmx::{Build, HtmxBuilder, SwapMethod, Tag},
+    jobs::JobStatus,
+    now_playing::NowPlaying,
+    routes,
+    settings::{self, ApiState, CSRF_HEADER},
 };
 use axum::{
-    extract::{Path, State},
-    response::{Html, Redirect},
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{Html, IntoResponse, Redirect, Response},
 };
-use iter_tools::Itertools;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
 use tracing::error;
 
+/// `<html>` with the shared `<head>`, page content goes in its `body()`
 fn page_header(title: &str) -> HtmxBuilder {
     HtmxBuilder::new(Tag::Html).head(|b| {
         b.title(title)
@@ -18,47 +25,150 @@ fn page_header(title: &str) -> HtmxBuilder {
                 "https://unpkg.com/htmx.org@1.9.3",
                 Some("sha384-lVb3Rd/Ca0AxaoZg5sACe8FJKF0tnUgR2Kd7ehUOG5GCcROv5uBIZsOqovBAcWua"),
             )
+            // For the live "now playing" panel
+            .script("https://unpkg.com/htmx.org@1.9.3/dist/ext/sse.js", None)
             // Not currently using
             // .script("https://unpkg.com/hyperscript.org@0.9.9", None)
             .style_link("https://cdn.jsdelivr.net/npm/@picocss/pico@1/css/pico.min.css")
     })
 }
 
+/// `hx-headers` for htmx to send the user's csrf token with every request from the page
+fn csrf_headers(user: &User) -> String {
+    serde_json::json!({ CSRF_HEADER: settings::csrf_token(user) }).to_string()
+}
+
+/// Renders a full error page, for failures that aren't the user's auth (which redirect to login)
+pub(crate) struct ErrorPage {
+    status: StatusCode,
+    message: String,
+}
+
+impl ErrorPage {
+    pub(crate) fn new(status: StatusCode, message: &str) -> Self {
+        Self {
+            status,
+            message: message.to_string(),
+        }
+    }
+
+    pub(crate) fn internal(message: &str) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, message)
+    }
+
+    pub(crate) fn bad_request(message: &str) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+}
+
+impl IntoResponse for ErrorPage {
+    fn into_response(self) -> Response {
+        (
+            self.status,
+            Html(
+                page_header("MemeJoin - Error")
+                    .body(|b| {
+                        b.builder(Tag::Main, |b| {
+                            b.attribute("class", "container")
+                                .builder_text(
+                                    Tag::Header2,
+                                    self.status.canonical_reason().unwrap_or("Error"),
+                                )
+                                .builder_text(Tag::Paragraph, &self.message)
+                        })
+                    })
+                    .build(),
+            ),
+        )
+            .into_response()
+    }
+}
+
 pub(crate) async fn home(
     State(state): State<ApiState>,
-    user: Option<User>,
-) -> Result<Html<String>, Redirect> {
-    if let Some(user) = user {
-        let db = state.db.lock().await;
+    user: User,
+) -> Result<Html<String>, ErrorPage> {
+    let db = &state.db;
 
-        let user_guilds = db.get_user_guilds(&user.name).map_err(|err| {
-            error!(?err, "failed to get user guilds");
-            // TODO: change this to returning a error to the client
-            Redirect::to(&format!("{}/login", state.origin))
-        })?;
+    let user_guilds = db.get_user_guilds(&user.name).map_err(|err| {
+        error!(?err, "failed to get user guilds");
+        ErrorPage::internal("failed to get user guilds")
+    })?;
+    let intro_counts = db.count_user_intros(&user.name).map_err(|err| {
+        error!(?err, "failed to count user intros");
+        ErrorPage::internal("failed to count user intros")
+    })?;
+    let unjoined_guilds = db.get_unjoined_user_guilds(&user.name).map_err(|err| {
+        error!(?err, "failed to get unjoined user guilds");
+        ErrorPage::internal("failed to get unjoined user guilds")
+    })?;
 
-        Ok(Html(
-            page_header("MemeJoin - Home")
-                .builder(Tag::Div, |b| {
-                    b.attribute("class", "container")
-                        .builder_text(Tag::Header2, "Choose a Guild")
-                        .push_builder(guild_list(&state.origin, user_guilds.iter()))
+    Ok(Html(
+        page_header("MemeJoin - Home")
+            .body(|b| {
+                b.attribute("hx-headers", &csrf_headers(&user))
+                    .builder(Tag::Div, |b| {
+                        let b = b
+                            .attribute("class", "container")
+                            .builder_text(Tag::Header2, "Choose a Guild")
+                            .push_builder(guild_list(
+                                &state.origin,
+                                user_guilds.iter(),
+                                &intro_counts,
+                            ));
+
+                        if unjoined_guilds.is_empty() {
+                            b
+                        } else {
+                            b.builder_text(Tag::Header3, "Guilds to Set Up")
+                                .push_builder(unjoined_guild_list(&state.origin, &unjoined_guilds))
+                        }
+                    })
+            })
+            .build(),
+    ))
+}
+
+/// Guilds the bot is in that the user hasn't been added to yet
+fn unjoined_guild_list(origin: &str, guilds: &[db::Guild]) -> HtmxBuilder {
+    HtmxBuilder::new(Tag::Empty).ul(|b| {
+        let mut b = b;
+        for guild in guilds {
+            b = b.li(|b| {
+                b.text(&guild.name).builder(Tag::Anchor, |b| {
+                    b.attribute("href", "#")
+                        .attribute("style", "margin-left: 0.5em")
+                        .hx_post(&format!("{}/guild/{}/setup", origin, guild.id))
+                        .text("Set up")
                 })
-                .build(),
-        ))
-    } else {
-        Err(Redirect::to(&format!("{}/login", state.origin)))
-    }
+            });
+        }
+
+        b
+    })
 }
 
-fn guild_list<'a>(origin: &str, guilds: impl Iterator<Item = &'a db::Guild>) -> HtmxBuilder {
+fn guild_list<'a>(
+    origin: &str,
+    guilds: impl Iterator<Item = &'a db::Guild>,
+    intro_counts: &HashMap<u64, u32>,
+) -> HtmxBuilder {
     HtmxBuilder::new(Tag::Empty).ul(|b| {
         let mut b = b;
         let mut in_any_guilds = false;
         for guild in guilds {
             in_any_guilds = true;
 
-            b = b.li(|b| b.link(&guild.name, &format!("{}/guild/{}", origin, guild.id)));
+            let intro_count = intro_counts.get(&guild.id).copied().unwrap_or_default();
+            b = b.li(|b| {
+                b.link(&guild.name, &format!("{}/guild/{}", origin, guild.id))
+                    .builder(Tag::Small, |b| {
+                        b.attribute("style", "margin-left: 0.5em").text(&format!(
+                            "({intro_count} intro{} set up)",
+                            if intro_count == 1 { "" } else { "s" }
+                        ))
+                    })
+            });
         }
 
         if !in_any_guilds {
@@ -69,10 +179,20 @@ fn guild_list<'a>(origin: &str, guilds: impl Iterator<Item = &'a db::Guild>) ->
     })
 }
 
+/// `secs` as minutes and seconds, like `1:05`
+fn duration(secs: u32) -> String {
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+/// `toggles` is where pausing or resuming each intro gets posted to along with the ids of the
+/// paused ones, for lists of the user's own intros
 fn intro_list<'a>(
+    origin: &str,
+    guild_id: u64,
     intros: impl Iterator<Item = &'a db::Intro>,
     label: &str,
     post: &str,
+    toggles: Option<(&str, &[i32])>,
 ) -> HtmxBuilder {
     HtmxBuilder::new(Tag::Empty).form(|b| {
         b.attribute("class", "container")
@@ -85,11 +205,57 @@ fn intro_list<'a>(
                     .attribute("style", "max-height: 50%; overflow-y: scroll");
                 for intro in intros {
                     b = b.builder(Tag::Label, |b| {
-                        b.builder(Tag::Input, |b| {
-                            b.attribute("type", "checkbox")
-                                .attribute("name", &intro.id.to_string())
+                        let b = b
+                            .builder(Tag::Input, |b| {
+                                b.attribute("type", "checkbox")
+                                    .attribute("name", &intro.id.to_string())
+                            })
+                            .builder_text(
+                                Tag::Paragraph,
+                                &format!(
+                                    "{} ({}{} play{}, uploaded by {})",
+                                    intro.name,
+                                    intro
+                                        .duration_secs
+                                        .map(|secs| format!("{}, ", duration(secs)))
+                                        .unwrap_or_default(),
+                                    intro.play_count,
+                                    if intro.play_count == 1 { "" } else { "s" },
+                                    intro.uploaded_by.as_deref().unwrap_or("unknown")
+                                ),
+                            );
+
+                        let b = match toggles {
+                            Some((toggle_post, paused)) => b.builder(Tag::Input, |b| {
+                                let enabled = !paused.contains(&intro.id);
+                                // Saved as soon as it's flipped, it has no name so it isn't
+                                // sent along when removing intros
+                                let b = b
+                                    .attribute("type", "checkbox")
+                                    .attribute("role", "switch")
+                                    .attribute("aria-label", "Play this intro")
+                                    .hx_post(&format!("{}/{}", toggle_post, intro.id))
+                                    .hx_trigger("change")
+                                    .attribute(
+                                        "hx-vals",
+                                        &serde_json::json!({ "enabled": !enabled }).to_string(),
+                                    );
+
+                                if enabled {
+                                    b.flag("checked")
+                                } else {
+                                    b
+                                }
+                            }),
+                            None => b,
+                        };
+
+                        b.builder(Tag::Audio, |b| {
+                            b.flag("controls").attribute("preload", "none").attribute(
+                                "src",
+                                &format!("{}/v2/intros/{}/preview/{}", origin, guild_id, intro.id),
+                            )
                         })
-                        .builder_text(Tag::Paragraph, &intro.name)
                     });
                 }
 
@@ -99,35 +265,57 @@ fn intro_list<'a>(
     })
 }
 
+const USER_INTROS_PAGE_SIZE: u32 = 100;
+
 pub(crate) async fn guild_dashboard(
     State(state): State<ApiState>,
     user: User,
     Path(guild_id): Path<u64>,
-) -> Result<Html<String>, Redirect> {
-    let (guild_intros, guild_channels, all_user_intros, user_permissions) = {
-        let db = state.db.lock().await;
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Html<String>, ErrorPage> {
+    let page = params
+        .get("page")
+        .and_then(|page| page.parse::<u32>().ok())
+        .unwrap_or_default();
+    let tag = params.get("tag").filter(|tag| !tag.is_empty());
+
+    let (guild_intros, guild_tags, guild_channels, all_user_intros, user_permissions) = {
+        let db = &state.db;
 
-        let guild_intros = db.get_guild_intros(guild_id).map_err(|err| {
+        let guild_intros = match tag {
+            Some(tag) => db.get_guild_intros_by_tag(guild_id, tag),
+            None => db.get_guild_intros(guild_id),
+        }
+        .map_err(|err| {
             error!(?err, %guild_id, "couldn't get guild intros");
-            // TODO: change to actual error
-            Redirect::to(&format!("{}/login", state.origin))
+            ErrorPage::internal("couldn't get guild intros")
+        })?;
+        let guild_tags = db.get_guild_tags(guild_id).map_err(|err| {
+            error!(?err, %guild_id, "couldn't get guild tags");
+            ErrorPage::internal("couldn't get guild tags")
         })?;
         let guild_channels = db.get_guild_channels(guild_id).map_err(|err| {
             error!(?err, %guild_id, "couldn't get guild channels");
-            // TODO: change to actual error
-            Redirect::to(&format!("{}/login", state.origin))
-        })?;
-        let all_user_intros = db.get_all_user_intros(guild_id).map_err(|err| {
-            error!(?err, %guild_id, "couldn't get user intros");
-            // TODO: change to actual error
-            Redirect::to(&format!("{}/login", state.origin))
+            ErrorPage::internal("couldn't get guild channels")
         })?;
+        // Grab one extra to know if there is a next page
+        let all_user_intros = db
+            .get_all_user_intros_paged(
+                guild_id,
+                USER_INTROS_PAGE_SIZE + 1,
+                page.saturating_mul(USER_INTROS_PAGE_SIZE),
+            )
+            .map_err(|err| {
+                error!(?err, %guild_id, "couldn't get user intros");
+                ErrorPage::internal("couldn't get user intros")
+            })?;
         let user_permissions = db
             .get_user_permissions(&user.name, guild_id)
             .unwrap_or_default();
 
         (
             guild_intros,
+            guild_tags,
             guild_channels,
             all_user_intros,
             user_permissions,
@@ -136,83 +324,163 @@ pub(crate) async fn guild_dashboard(
 
     let can_upload = user_permissions.can(auth::Permission::UploadSounds);
     let is_moderator = user_permissions.can(auth::Permission::Moderator);
-    let mod_dashboard = moderator_dashboard(&state, guild_id).await;
+    let mod_dashboard = moderator_dashboard(&state, &user, guild_id).await;
 
+    let has_next_page = all_user_intros.len() > USER_INTROS_PAGE_SIZE as usize;
     let user_intros = all_user_intros
         .iter()
+        .take(USER_INTROS_PAGE_SIZE as usize)
         .filter(|intro| intro.username == user.name)
-        .group_by(|intro| &intro.channel_name);
+        .collect::<Vec<_>>();
 
     Ok(Html(
-        HtmxBuilder::new(Tag::Html)
-            .push_builder(page_header("MemeJoin - Dashboard"))
-            .builder(Tag::Nav, |b| {
-                b.builder(Tag::HeaderGroup, |b| {
-                    b.attribute("class", "container")
-                        .builder(Tag::Header1, |b| b.text("MemeJoin - A bot for user intros"))
-                        .builder_text(Tag::Header6, &user.name)
-                })
-            })
-            .builder(Tag::Empty, |b| {
-                let mut b = if is_moderator {
-                    b.builder(Tag::Div, |b| {
+        page_header("MemeJoin - Dashboard")
+            .body(|b| {
+                let b = b.attribute("hx-headers", &csrf_headers(&user));
+
+                b.builder(Tag::Nav, |b| {
+                    b.builder(Tag::HeaderGroup, |b| {
                         b.attribute("class", "container")
-                            .builder(Tag::Article, |b| {
-                                b.builder_text(Tag::Header, "Wow, you're a moderator")
-                                    .push_builder(mod_dashboard)
-                                    .builder_text(Tag::Footer, "End of super cool mod section")
-                            })
+                            .builder(Tag::Header1, |b| b.text("MemeJoin - A bot for user intros"))
+                            .builder_text(Tag::Header6, &user.name)
                     })
-                } else {
-                    b
-                };
-                b = if can_upload {
+                })
+                .builder(Tag::Empty, |b| {
+                    let mut b = if is_moderator {
+                        b.builder(Tag::Div, |b| {
+                            b.attribute("class", "container")
+                                .builder(Tag::Article, |b| {
+                                    b.builder_text(Tag::Header, "Wow, you're a moderator")
+                                        .push_builder(mod_dashboard)
+                                        .builder_text(Tag::Footer, "End of super cool mod section")
+                                })
+                        })
+                    } else {
+                        b
+                    };
+                    b = if can_upload {
+                        b.builder(Tag::Div, |b| {
+                            b.attribute("class", "container")
+                                .builder(Tag::Article, |b| {
+                                    b.builder_text(Tag::Header, "Upload New Intro")
+                                        .push_builder(upload_form(&state.origin, guild_id))
+                                })
+                        })
+                        .builder(Tag::Div, |b| {
+                            b.attribute("class", "container")
+                                .builder(Tag::Article, |b| {
+                                    b.builder_text(Tag::Header, "Upload New Intro from Url")
+                                        .push_builder(ytdl_form(&state.origin, guild_id))
+                                })
+                        })
+                    } else {
+                        b
+                    };
+
                     b.builder(Tag::Div, |b| {
                         b.attribute("class", "container")
                             .builder(Tag::Article, |b| {
-                                b.builder_text(Tag::Header, "Upload New Intro")
-                                    .push_builder(upload_form(&state.origin, guild_id))
-                            })
-                    })
-                    .builder(Tag::Div, |b| {
-                        b.attribute("class", "container")
-                            .builder(Tag::Article, |b| {
-                                b.builder_text(Tag::Header, "Upload New Intro from Url")
-                                    .push_builder(ytdl_form(&state.origin, guild_id))
+                                let mut b = b
+                                    .builder_text(Tag::Header, "Guild Intros")
+                                    .push_builder(tag_filters(
+                                        &state.origin,
+                                        guild_id,
+                                        &guild_tags,
+                                        tag.map(String::as_str),
+                                    ));
+
+                                for (channel_id, channel_name) in guild_channels {
+                                    b = b.details(&channel_name, |b| {
+                                        let mut b = b;
+
+                                        for kind in [db::IntroKind::Intro, db::IntroKind::Outro] {
+                                            // Get user intros for this channel
+                                            let intros = user_intros
+                                                .iter()
+                                                .filter(|intro| {
+                                                    intro.channel_id == channel_id
+                                                        && intro.kind == kind
+                                                })
+                                                .copied();
+
+                                            b = b.builder(Tag::Div, |b| {
+                                                b.attribute("id", "channel-intro-selector")
+                                                    .push_builder(channel_intro_selector(
+                                                        &state.origin,
+                                                        guild_id,
+                                                        channel_id,
+                                                        kind,
+                                                        intros,
+                                                        guild_intros.iter(),
+                                                    ))
+                                            });
+                                        }
+
+                                        b
+                                    });
+                                }
+
+                                b.push_builder(page_navigation(
+                                    &state.origin,
+                                    guild_id,
+                                    tag.map(String::as_str),
+                                    page,
+                                    has_next_page,
+                                ))
                             })
                     })
-                } else {
-                    b
-                };
+                })
+            })
+            .build(),
+    ))
+}
+
+/// Read-only list of a guild's intros for people who haven't logged in, only if the guild opted in
+pub(crate) async fn public_guild_library(
+    State(state): State<ApiState>,
+    Path(guild_id): Path<u64>,
+) -> Result<Html<String>, ErrorPage> {
+    let db = &state.db;
+
+    let guild = db
+        .get_active_guilds()
+        .map_err(|err| {
+            error!(?err, "couldn't get guilds");
+            ErrorPage::internal("couldn't get guilds")
+        })?
+        .into_iter()
+        .find(|guild| guild.id == guild_id && guild.public_library)
+        .ok_or_else(|| {
+            ErrorPage::new(StatusCode::NOT_FOUND, "this guild's intros aren't public")
+        })?;
+    let guild_intros = db.get_guild_intros(guild_id).map_err(|err| {
+        error!(?err, %guild_id, "couldn't get guild intros");
+        ErrorPage::internal("couldn't get guild intros")
+    })?;
 
+    Ok(Html(
+        page_header(&format!("MemeJoin - {}", guild.name))
+            .body(|b| {
                 b.builder(Tag::Div, |b| {
                     b.attribute("class", "container")
-                        .builder(Tag::Article, |b| {
-                            let mut b = b.builder_text(Tag::Header, "Guild Intros");
-
-                            let mut user_intros = user_intros.into_iter().peekable();
-
-                            for guild_channel_name in guild_channels {
-                                // Get user intros for this channel
-                                let intros = user_intros
-                                    .peeking_take_while(|(channel_name, _)| {
-                                        channel_name == &&guild_channel_name
-                                    })
-                                    .map(|(_, intros)| intros.map(|intro| &intro.intro))
-                                    .flatten();
-
-                                b = b.builder(Tag::Article, |b| {
-                                    b.builder_text(Tag::Header, &guild_channel_name).builder(
-                                        Tag::Div,
+                        .builder_text(Tag::Header2, &format!("{} Intros", guild.name))
+                        .link("Log in to pick yours", &format!("{}/login", state.origin))
+                        .ul(|b| {
+                            let mut b = b;
+                            for intro in &guild_intros {
+                                b = b.li(|b| {
+                                    b.builder_text(Tag::Paragraph, &intro.name).builder(
+                                        Tag::Audio,
                                         |b| {
-                                            b.attribute("id", "channel-intro-selector")
-                                                .push_builder(channel_intro_selector(
-                                                    &state.origin,
-                                                    guild_id,
-                                                    &guild_channel_name,
-                                                    intros,
-                                                    guild_intros.iter(),
-                                                ))
+                                            b.flag("controls")
+                                                .attribute("preload", "none")
+                                                .attribute(
+                                                    "src",
+                                                    &format!(
+                                                        "{}/v2/intros/{}/preview/{}",
+                                                        state.origin, guild_id, intro.id
+                                                    ),
+                                                )
                                         },
                                     )
                                 });
@@ -226,138 +494,674 @@ pub(crate) async fn guild_dashboard(
     ))
 }
 
+/// Dashboard url for a guild, keeping the selected tag filter (if any)
+fn dashboard_url(origin: &str, guild_id: u64, tag: Option<&str>, page: u32) -> String {
+    match tag {
+        Some(tag) => format!(
+            "{}/guild/{}?page={}&tag={}",
+            origin,
+            guild_id,
+            page,
+            utf8_percent_encode(tag, NON_ALPHANUMERIC)
+        ),
+        None => format!("{}/guild/{}?page={}", origin, guild_id, page),
+    }
+}
+
+fn page_navigation(
+    origin: &str,
+    guild_id: u64,
+    tag: Option<&str>,
+    page: u32,
+    has_next_page: bool,
+) -> HtmxBuilder {
+    let page_url = |page: u32| dashboard_url(origin, guild_id, tag, page);
+
+    HtmxBuilder::new(Tag::Footer).builder(Tag::Nav, |b| {
+        let b = if page > 0 {
+            b.link("Previous", &page_url(page - 1))
+        } else {
+            b
+        };
+
+        if has_next_page {
+            b.link("Next", &page_url(page + 1))
+        } else {
+            b
+        }
+    })
+}
+
+/// Links for only showing the guild intros with a given tag, the selected one isn't a link
+fn tag_filters(
+    origin: &str,
+    guild_id: u64,
+    tags: &[String],
+    selected: Option<&str>,
+) -> HtmxBuilder {
+    if tags.is_empty() {
+        return HtmxBuilder::new(Tag::Empty);
+    }
+
+    let b = HtmxBuilder::new(Tag::Nav);
+    let mut b = match selected {
+        Some(_) => b.link("All", &dashboard_url(origin, guild_id, None, 0)),
+        None => b.builder_text(Tag::Strong, "All"),
+    };
+
+    for tag in tags {
+        b = if selected == Some(tag.as_str()) {
+            b.builder_text(Tag::Strong, tag)
+        } else {
+            b.link(tag, &dashboard_url(origin, guild_id, Some(tag), 0))
+        };
+    }
+
+    b
+}
+
 pub fn channel_intro_selector<'a>(
     origin: &str,
     guild_id: u64,
-    channel_name: &String,
-    intros: impl Iterator<Item = &'a db::Intro>,
+    channel_id: u64,
+    kind: db::IntroKind,
+    intros: impl Iterator<Item = &'a db::UserIntro>,
     guild_intros: impl Iterator<Item = &'a db::Intro>,
 ) -> HtmxBuilder {
+    let (noun, route) = match kind {
+        db::IntroKind::Intro => ("Intro", "intros"),
+        db::IntroKind::Outro => ("Outro", "outros"),
+    };
+
+    let intros = intros.collect::<Vec<_>>();
+    let paused = intros
+        .iter()
+        .filter(|intro| !intro.enabled)
+        .map(|intro| intro.intro.id)
+        .collect::<Vec<_>>();
+
     HtmxBuilder::new(Tag::Empty)
-        .builder_text(Tag::Strong, "Your Current Intros")
+        .builder_text(Tag::Strong, &format!("Your Current {noun}s"))
         .push_builder(intro_list(
-            intros,
-            "Remove Intro",
-            &format!("{}/v2/intros/remove/{}/{}", origin, guild_id, &channel_name),
+            origin,
+            guild_id,
+            intros.iter().map(|intro| &intro.intro),
+            &format!("Remove {noun}"),
+            &format!("{}/v2/{}/remove/{}/{}", origin, route, guild_id, channel_id),
+            Some((
+                &format!(
+                    "{}/v2/{}/enabled/{}/{}",
+                    origin, route, guild_id, channel_id
+                ),
+                &paused,
+            )),
         ))
-        .builder_text(Tag::Strong, "Select Intros")
-        .push_builder(intro_list(
-            guild_intros,
-            "Add Intro",
-            &format!("{}/v2/intros/add/{}/{}", origin, guild_id, channel_name),
+        .push_builder(volume_sliders(
+            &format!("{}/v2/{}/volume/{}/{}", origin, route, guild_id, channel_id),
+            intros.iter().map(|intro| &intro.intro),
         ))
+        .builder_text(Tag::Strong, &format!("Select {noun}s"))
+        .input(|b| {
+            b.attribute("type", "search")
+                .attribute("name", "q")
+                .attribute("placeholder", &format!("Search {route}"))
+                .hx_get(&format!(
+                    "{}/v2/intros/{}/search?channel={}&kind={}",
+                    origin, guild_id, channel_id, route
+                ))
+                .hx_trigger("input changed delay:300ms, search")
+                .hx_target("next .intro-search-results")
+        })
+        .builder(Tag::Div, |b| {
+            b.attribute("class", "intro-search-results")
+                .push_builder(add_intro_list(
+                    origin,
+                    guild_id,
+                    channel_id,
+                    kind,
+                    guild_intros,
+                ))
+        })
 }
 
-fn upload_form(origin: &str, guild_id: u64) -> HtmxBuilder {
-    HtmxBuilder::new(Tag::Empty).form(|b| {
-        b.attribute("class", "container")
-            .hx_post(&format!("{}/v2/intros/{}/upload", origin, guild_id))
-            .attribute("hx-encoding", "multipart/form-data")
-            .builder(Tag::FieldSet, |b| {
-                b.attribute("class", "container")
-                    .input(|b| {
-                        b.attribute("name", "name")
-                            .attribute("placeholder", "enter intro title")
-                    })
-                    .label(|b| {
-                        b.text("Choose File")
-                            .input(|b| b.attribute("type", "file").attribute("name", "file"))
+/// The guild's intros to pick from, or the ones that matched a search
+pub(crate) fn add_intro_list<'a>(
+    origin: &str,
+    guild_id: u64,
+    channel_id: u64,
+    kind: db::IntroKind,
+    guild_intros: impl Iterator<Item = &'a db::Intro>,
+) -> HtmxBuilder {
+    let (noun, route) = match kind {
+        db::IntroKind::Intro => ("Intro", "intros"),
+        db::IntroKind::Outro => ("Outro", "outros"),
+    };
+
+    intro_list(
+        origin,
+        guild_id,
+        guild_intros,
+        &format!("Add {noun}"),
+        &format!("{}/v2/{}/add/{}/{}", origin, route, guild_id, channel_id),
+        None,
+    )
+}
+
+/// A slider per intro for overriding how loud it plays for this user, saved as soon as it's moved
+fn volume_sliders<'a>(post: &str, intros: impl Iterator<Item = &'a db::Intro>) -> HtmxBuilder {
+    let mut b = HtmxBuilder::new(Tag::Empty);
+    for intro in intros {
+        // 0 means the volume was never set, which plays at full volume
+        let volume = if intro.volume <= 0 { 100 } else { intro.volume };
+
+        b = b.form(|b| {
+            b.hx_post(&format!("{}/{}", post, intro.id))
+                .hx_trigger("change")
+                .hx_target("closest #channel-intro-selector")
+                .attribute("hx-encoding", "multipart/form-data")
+                .label(|b| {
+                    b.text(&format!("{} volume", intro.name)).input(|b| {
+                        b.attribute("type", "range")
+                            .attribute("name", "volume")
+                            .attribute("min", "0")
+                            .attribute("max", "200")
+                            .attribute("value", &volume.to_string())
                     })
-            })
-            .button(|b| b.attribute("type", "submit").text("Upload"))
-    })
+                })
+        });
+    }
+
+    b
+}
+
+fn upload_form(origin: &str, guild_id: u64) -> HtmxBuilder {
+    HtmxBuilder::new(Tag::Empty)
+        .form(|b| {
+            b.attribute("class", "container")
+                .hx_post(&format!("{}/v2/intros/{}/upload", origin, guild_id))
+                .hx_target("#upload-job-status")
+                .attribute("hx-encoding", "multipart/form-data")
+                .builder(Tag::FieldSet, |b| {
+                    b.attribute("class", "container")
+                        .input(|b| {
+                            b.attribute("name", "name")
+                                .attribute("placeholder", "enter intro title")
+                        })
+                        .label(|b| {
+                            b.text("Choose File")
+                                .input(|b| b.attribute("type", "file").attribute("name", "file"))
+                        })
+                        .input(|b| {
+                            b.attribute("name", "tags")
+                                .attribute("placeholder", "tags, separated by commas")
+                        })
+                })
+                .button(|b| b.attribute("type", "submit").text("Upload"))
+        })
+        .builder(Tag::Div, |b| b.attribute("id", "upload-job-status"))
 }
 
 fn ytdl_form(origin: &str, guild_id: u64) -> HtmxBuilder {
-    HtmxBuilder::new(Tag::Empty).form(|b| {
-        b.attribute("class", "container")
-            .hx_get(&format!("{}/v2/intros/{}/add", origin, guild_id))
-            .builder(Tag::FieldSet, |b| {
-                b.attribute("class", "container")
-                    .label(|b| {
-                        b.text("Video Url").input(|b| {
-                            b.attribute("placeholder", "enter video url")
-                                .attribute("name", "url")
+    HtmxBuilder::new(Tag::Empty)
+        .form(|b| {
+            b.attribute("class", "container")
+                .hx_post(&format!("{}/v2/intros/{}/add", origin, guild_id))
+                .hx_target("#ytdl-job-status")
+                .attribute("hx-encoding", "multipart/form-data")
+                .builder(Tag::FieldSet, |b| {
+                    b.attribute("class", "container")
+                        .label(|b| {
+                            b.text("Video Url").input(|b| {
+                                b.attribute("placeholder", "enter video url")
+                                    .attribute("name", "url")
+                            })
                         })
-                    })
-                    .label(|b| {
-                        b.text("Intro Title").input(|b| {
-                            b.attribute("placeholder", "enter intro title")
-                                .attribute("name", "name")
+                        .label(|b| {
+                            b.text("Intro Title").input(|b| {
+                                b.attribute("placeholder", "enter intro title")
+                                    .attribute("name", "name")
+                            })
                         })
-                    })
-            })
-            .button(|b| b.attribute("type", "submit").text("Upload"))
-    })
+                        .label(|b| {
+                            b.text("Tags").input(|b| {
+                                b.attribute("placeholder", "tags, separated by commas")
+                                    .attribute("name", "tags")
+                            })
+                        })
+                })
+                .button(|b| b.attribute("type", "submit").text("Upload"))
+        })
+        .builder(Tag::Div, |b| b.attribute("id", "ytdl-job-status"))
+}
+
+/// Shows how a media job is doing, polling for updates until it's finished
+pub(crate) fn job_status(
+    origin: &str,
+    guild_id: u64,
+    job_id: &str,
+    status: &JobStatus,
+) -> HtmxBuilder {
+    let waiting_on = match status {
+        JobStatus::Pending => "Waiting to start...",
+        JobStatus::Running => "Processing...",
+        JobStatus::Done(intro_id) => {
+            return HtmxBuilder::new(Tag::Paragraph)
+                .attribute("data-intro-id", &intro_id.to_string())
+                .text("Done!")
+        }
+        JobStatus::Failed(message) => {
+            return HtmxBuilder::new(Tag::Paragraph).text(&format!("Failed: {message}"))
+        }
+    };
+
+    HtmxBuilder::new(Tag::Div)
+        .hx_get(&format!("{}/v2/intros/{}/job/{}", origin, guild_id, job_id))
+        .hx_trigger("every 1s")
+        .hx_swap(SwapMethod::OuterHtml)
+        .builder_text(Tag::Paragraph, waiting_on)
+        .builder(Tag::Progress, |b| b)
 }
 
 async fn permissions_editor(state: &ApiState, guild_id: u64) -> HtmxBuilder {
-    let db = state.db.lock().await;
+    let db = &state.db;
     let user_permissions = db.get_all_user_permissions(guild_id).unwrap_or_default();
 
+    // Moderator can't be handed out from here
+    let editable_permissions = enum_iterator::all::<auth::Permission>()
+        .filter(|perm| *perm != auth::Permission::Moderator && *perm != auth::Permission::None)
+        .collect::<Vec<_>>();
+    let permission_names = editable_permissions
+        .iter()
+        .map(|perm| perm.to_string())
+        .collect::<Vec<_>>();
+    let headers = std::iter::once("User")
+        .chain(permission_names.iter().map(String::as_str))
+        .collect::<Vec<_>>();
+
     HtmxBuilder::new(Tag::Empty).form(|b| {
         b.hx_post(&format!(
             "{}/guild/{}/permissions/update",
             state.origin, guild_id
         ))
         .attribute("hx-encoding", "multipart/form-data")
-        .builder(Tag::Table, |b| {
-            let mut b = b.attribute("role", "grid").builder(Tag::TableHead, |b| {
-                let mut b = b.builder_text(Tag::TableHeader, "User");
+        .table(&headers, &user_permissions, |b, (username, permissions)| {
+            let mut b = b.builder_text(Tag::TableData, username);
+
+            for perm in editable_permissions.iter().copied() {
+                b = b.builder(Tag::TableData, |b| {
+                    b.builder(Tag::Input, |b| {
+                        let mut b = b
+                            .attribute("type", "checkbox")
+                            .attribute("name", &format!("{}#{}", username, perm.to_string()));
+
+                        if permissions.can(auth::Permission::Moderator) {
+                            b = b.flag("disabled");
+                        }
+
+                        if permissions.can(perm) {
+                            return b.flag("checked");
+                        }
+
+                        b
+                    })
+                });
+            }
+
+            b
+        })
+        .button(|b| b.attribute("type", "submit").text("Update Permissions"))
+    })
+}
+
+async fn role_permissions_editor(state: &ApiState, guild_id: u64) -> HtmxBuilder {
+    let role_permissions = state
+        .db
+        .get_guild_role_permissions(guild_id)
+        .unwrap_or_default();
+
+    let permissions = enum_iterator::all::<auth::Permission>()
+        .filter(|perm| *perm != auth::Permission::None)
+        .collect::<Vec<_>>();
+    let permission_names = permissions
+        .iter()
+        .map(|perm| perm.to_string())
+        .collect::<Vec<_>>();
+    let headers = std::iter::once("Discord Role ID")
+        .chain(permission_names.iter().map(String::as_str))
+        .collect::<Vec<_>>();
 
-                for perm in enum_iterator::all::<auth::Permission>() {
-                    if perm == auth::Permission::Moderator || perm == auth::Permission::None {
-                        continue;
-                    }
+    // The last row has no role yet, it's for adding a new one
+    let rows = role_permissions
+        .into_iter()
+        .map(Some)
+        .chain(std::iter::once(None));
 
-                    b = b.builder_text(Tag::TableHeader, &perm.to_string());
+    HtmxBuilder::new(Tag::Empty).form(|b| {
+        b.hx_post(&format!("{}/guild/{}/roles/update", state.origin, guild_id))
+            .attribute("hx-encoding", "multipart/form-data")
+            .table(&headers, rows, |b, row| {
+                let (mut b, role, role_permissions) = match row {
+                    Some((role_id, role_permissions)) => (
+                        b.builder_text(Tag::TableData, &role_id.to_string()),
+                        role_id.to_string(),
+                        role_permissions,
+                    ),
+                    None => (
+                        b.builder(Tag::TableData, |b| {
+                            b.builder(Tag::Input, |b| {
+                                b.attribute("type", "text")
+                                    .attribute("name", "new_role_id")
+                                    .attribute("placeholder", "New role ID")
+                            })
+                        }),
+                        "new".to_string(),
+                        Default::default(),
+                    ),
+                };
+
+                for perm in permissions.iter().copied() {
+                    b = b.builder(Tag::TableData, |b| {
+                        b.builder(Tag::Input, |b| {
+                            let b = b
+                                .attribute("type", "checkbox")
+                                .attribute("name", &format!("{}#{}", role, perm.to_string()));
+
+                            // Can't use `can` here, it treats moderators as having everything
+                            if role_permissions.contains(perm.into()) {
+                                b.flag("checked")
+                            } else {
+                                b
+                            }
+                        })
+                    });
                 }
 
                 b
-            });
+            })
+            .button(|b| {
+                b.attribute("type", "submit")
+                    .text("Update Role Permissions")
+            })
+    })
+}
 
-            for permission in user_permissions {
-                b = b.builder(Tag::TableRow, |b| {
-                    let mut b = b.builder_text(Tag::TableData, permission.0.as_str());
+async fn guild_settings_form(state: &ApiState, guild_id: u64) -> HtmxBuilder {
+    let guild = state.db.get_guild(guild_id).ok();
+    let restrict_to_permission = guild
+        .as_ref()
+        .and_then(|guild| guild.restrict_to_permission);
+    let default_intro_id = guild.as_ref().and_then(|guild| guild.default_intro_id);
+    let sound_delay = guild
+        .as_ref()
+        .map(|guild| guild.sound_delay)
+        .unwrap_or_default();
+    let max_volume = guild
+        .as_ref()
+        .map_or(routes::MAX_VOLUME, |guild| guild.max_volume);
+    let public_library = guild.is_some_and(|guild| guild.public_library);
+    let guild_intros = state.db.get_guild_intros(guild_id).unwrap_or_default();
 
-                    for perm in enum_iterator::all::<auth::Permission>() {
-                        if perm == auth::Permission::Moderator || perm == auth::Permission::None {
-                            continue;
+    HtmxBuilder::new(Tag::Empty).form(|b| {
+        b.hx_post(&format!("{}/guild/{}/settings", state.origin, guild_id))
+            .attribute("hx-encoding", "multipart/form-data")
+            .builder(Tag::FieldSet, |b| {
+                b.label(|b| {
+                    b.text("Only play intros for users with").select(|b| {
+                        let mut b = b
+                            .attribute("name", "restrict_to_permission")
+                            .option_selected("", "Everyone", restrict_to_permission.is_none());
+
+                        for perm in enum_iterator::all::<auth::Permission>() {
+                            if perm == auth::Permission::None {
+                                continue;
+                            }
+
+                            b = b.option_selected(
+                                &perm.to_string(),
+                                &perm.to_string(),
+                                restrict_to_permission == Some(perm),
+                            );
                         }
 
-                        b = b.builder(Tag::TableData, |b| {
-                            b.builder(Tag::Input, |b| {
-                                let mut b = b.attribute("type", "checkbox").attribute(
-                                    "name",
-                                    &format!("{}#{}", permission.0, perm.to_string()),
+                        b
+                    })
+                })
+                .label(|b| {
+                    b.text("Play this for users without an intro of their own")
+                        .select(|b| {
+                            let mut b = b.attribute("name", "default_intro").option_selected(
+                                "",
+                                "Nothing",
+                                default_intro_id.is_none(),
+                            );
+
+                            for intro in &guild_intros {
+                                b = b.option_selected(
+                                    &intro.id.to_string(),
+                                    &intro.name,
+                                    default_intro_id == Some(intro.id),
                                 );
+                            }
 
-                                if permission.1.can(auth::Permission::Moderator) {
-                                    b = b.flag("disabled");
-                                }
+                            b
+                        })
+                })
+                .label(|b| {
+                    b.text("Wait this many milliseconds after joining before playing intros")
+                        .input(|b| {
+                            b.attribute("type", "number")
+                                .attribute("name", "sound_delay")
+                                .attribute("min", "0")
+                                .attribute("max", &routes::MAX_SOUND_DELAY_MS.to_string())
+                                .attribute("value", &sound_delay.to_string())
+                        })
+                })
+                .label(|b| {
+                    b.text("Never play intros louder than this percent")
+                        .input(|b| {
+                            b.attribute("type", "number")
+                                .attribute("name", "max_volume")
+                                .attribute("min", "0")
+                                .attribute("max", &routes::MAX_VOLUME.to_string())
+                                .attribute("value", &max_volume.to_string())
+                        })
+                })
+                .label(|b| {
+                    b.text("Who can browse this guild's intros").select(|b| {
+                        b.attribute("name", "public_library")
+                            .option_selected("false", "Only members", !public_library)
+                            .option_selected("true", "Anyone, without logging in", public_library)
+                    })
+                })
+            })
+            .button(|b| b.attribute("type", "submit").text("Update Settings"))
+    })
+}
 
-                                if permission.1.can(perm) {
-                                    return b.flag("checked");
-                                }
+async fn channel_settings_form(state: &ApiState, guild_id: u64) -> HtmxBuilder {
+    let channels = state
+        .db
+        .get_guild_channel_settings(guild_id)
+        .unwrap_or_default();
 
-                                b
-                            })
-                        });
-                    }
+    HtmxBuilder::new(Tag::Empty).form(|b| {
+        b.hx_post(&format!(
+            "{}/guild/{}/channels/update",
+            state.origin, guild_id
+        ))
+        .attribute("hx-encoding", "multipart/form-data")
+        .builder(Tag::FieldSet, |b| {
+            let mut b = b.builder_text(Tag::Strong, "Play intros in");
 
-                    b
+            for (channel_id, channel_name, intros_enabled) in &channels {
+                b = b.label(|b| {
+                    b.input(|b| {
+                        let b = b.attribute("type", "checkbox");
+                        // Channels added by hand can't be toggled until they're synced
+                        let b = match channel_id {
+                            Some(channel_id) => b.attribute("name", &channel_id.to_string()),
+                            None => b.flag("disabled"),
+                        };
+
+                        if *intros_enabled {
+                            b.flag("checked")
+                        } else {
+                            b
+                        }
+                    })
+                    .text(&match channel_id {
+                        Some(_) => channel_name.clone(),
+                        None => format!("{channel_name} (not synced yet)"),
+                    })
                 });
             }
 
             b
         })
-        .button(|b| b.attribute("type", "submit").text("Update Permissions"))
+        .button(|b| b.attribute("type", "submit").text("Update Channels"))
+    })
+}
+
+/// For registering a channel the bot hasn't synced from discord yet
+fn add_channel_form(origin: &str, guild_id: u64) -> HtmxBuilder {
+    HtmxBuilder::new(Tag::Empty).form(|b| {
+        b.hx_post(&format!("{}/guild/{}/channels/add", origin, guild_id))
+            .attribute("hx-encoding", "multipart/form-data")
+            .builder(Tag::FieldSet, |b| {
+                b.label(|b| {
+                    b.text("Add a channel the bot doesn't know about yet")
+                        .input(|b| {
+                            b.attribute("name", "name")
+                                .attribute("placeholder", "channel name")
+                        })
+                })
+            })
+            .button(|b| b.attribute("type", "submit").text("Add Channel"))
+    })
+}
+
+async fn guild_intros_editor(state: &ApiState, guild_id: u64) -> HtmxBuilder {
+    let intros = state.db.get_guild_intros(guild_id).unwrap_or_default();
+
+    HtmxBuilder::new(Tag::Empty).table(
+        &["Intro", "Length", "Uploaded By", ""],
+        &intros,
+        |b, intro| {
+            let confirmation_id = format!("delete-intro-{}", intro.id);
+
+            b.builder_text(Tag::TableData, &intro.name)
+                .builder_text(
+                    Tag::TableData,
+                    &intro.duration_secs.map(duration).unwrap_or_default(),
+                )
+                .builder_text(
+                    Tag::TableData,
+                    intro.uploaded_by.as_deref().unwrap_or("unknown"),
+                )
+                .builder(Tag::TableData, |b| {
+                    b.attribute("id", &confirmation_id).button(|b| {
+                        b.attribute("class", "secondary")
+                            .hx_get(&format!(
+                                "{}/v2/intros/{}/users/{}",
+                                state.origin, guild_id, intro.id
+                            ))
+                            .hx_target(&format!("#{confirmation_id}"))
+                            .text("Delete")
+                    })
+                })
+        },
+    )
+}
+
+/// Lists who'd lose `intro` if it were deleted, along with the button to actually delete it
+pub(crate) fn intro_delete_confirmation(
+    origin: &str,
+    guild_id: u64,
+    intro: &db::Intro,
+    assignments: &[(String, String)],
+) -> HtmxBuilder {
+    let b = if assignments.is_empty() {
+        HtmxBuilder::new(Tag::Empty).builder_text(Tag::Paragraph, "Nobody is using this intro")
+    } else {
+        HtmxBuilder::new(Tag::Empty)
+            .builder_text(Tag::Paragraph, "Used by")
+            .ul(|b| {
+                let mut b = b;
+                for (username, channel_name) in assignments {
+                    b = b.li(|b| b.text(&format!("{username} in {channel_name}")));
+                }
+
+                b
+            })
+    };
+
+    b.button(|b| {
+        b.attribute("class", "secondary")
+            .hx_delete(&format!(
+                "{}/v2/intros/{}/delete/{}",
+                origin, guild_id, intro.id
+            ))
+            .hx_confirm(&format!(
+                "Delete '{}'? This removes it for everyone in the guild.",
+                intro.name
+            ))
+            .text("Delete")
     })
 }
 
-async fn moderator_dashboard(state: &ApiState, guild_id: u64) -> HtmxBuilder {
+fn maintenance_form(origin: &str, guild_id: u64) -> HtmxBuilder {
+    HtmxBuilder::new(Tag::Empty).form(|b| {
+        b.hx_post(&format!("{}/guild/{}/maintenance/vacuum", origin, guild_id))
+            .hx_target("#maintenance-result")
+            .button(|b| b.attribute("type", "submit").text("Vacuum Database"))
+            .builder(Tag::Div, |b| b.attribute("id", "maintenance-result"))
+    })
+}
+
+/// Fills in with what the bot plays in the guild as it happens, newest first
+fn now_playing_panel(origin: &str, guild_id: u64) -> HtmxBuilder {
+    HtmxBuilder::new(Tag::Empty)
+        .builder_text(Tag::Header3, "Now Playing")
+        .builder(Tag::Div, |b| {
+            b.attribute("hx-ext", "sse")
+                .attribute(
+                    "sse-connect",
+                    &format!("{}/v2/guild/{}/events", origin, guild_id),
+                )
+                .attribute("sse-swap", "now-playing")
+                .attribute("hx-swap", "afterbegin")
+        })
+}
+
+/// One entry in the [`now_playing_panel`]
+pub(crate) fn now_playing(now_playing: &NowPlaying) -> HtmxBuilder {
+    HtmxBuilder::new(Tag::Paragraph).text(&format!(
+        "{} played {} in {}",
+        now_playing.username, now_playing.intro, now_playing.channel
+    ))
+}
+
+async fn moderator_dashboard(state: &ApiState, user: &User, guild_id: u64) -> HtmxBuilder {
     let permissions_editor = permissions_editor(state, guild_id).await;
-    HtmxBuilder::new(Tag::Empty).push_builder(permissions_editor)
+    let role_permissions_editor = role_permissions_editor(state, guild_id).await;
+    let guild_settings_form = guild_settings_form(state, guild_id).await;
+    let channel_settings_form = channel_settings_form(state, guild_id).await;
+    let guild_intros_editor = guild_intros_editor(state, guild_id).await;
+    let b = HtmxBuilder::new(Tag::Empty)
+        .push_builder(now_playing_panel(&state.origin, guild_id))
+        .push_builder(permissions_editor)
+        .push_builder(role_permissions_editor)
+        .push_builder(guild_settings_form)
+        .push_builder(channel_settings_form)
+        .push_builder(add_channel_form(&state.origin, guild_id))
+        .push_builder(guild_intros_editor);
+
+    // Vacuuming locks the database for every guild, not just this one
+    if state.is_operator(&user.name) {
+        b.push_builder(maintenance_form(&state.origin, guild_id))
+    } else {
+        b
+    }
 }
 
 pub(crate) async fn login(
@@ -371,9 +1175,11 @@ pub(crate) async fn login(
 
         Ok(Html(
             page_header("MemeJoin - Login")
-                .builder(Tag::Main, |b| {
-                    b.attribute("class", "container")
-                        .link("Login with Discord", &authorize_uri)
+                .body(|b| {
+                    b.builder(Tag::Main, |b| {
+                        b.attribute("class", "container")
+                            .link("Login with Discord", &authorize_uri)
+                    })
                 })
                 .build(),
         ))
@@ -386,3 +1192,120 @@ pub(crate) async fn login(
     //        .build(),
     //)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_document(html: &str) {
+        assert!(html.starts_with("<html><head>"), "{html}");
+        assert!(html.contains("</head><body"), "{html}");
+        assert!(html.ends_with("</body></html>"), "{html}");
+        assert_eq!(html.matches("<html>").count(), 1, "{html}");
+        assert_eq!(html.matches("<body").count(), 1, "{html}");
+    }
+
+    #[tokio::test]
+    async fn pages_have_a_body() {
+        let (state, user, _dir) = settings::test_state("page-body");
+
+        let Ok(Html(html)) = login(State(state.clone()), None).await else {
+            panic!("login redirected without a user");
+        };
+        assert_document(&html);
+
+        let Ok(Html(html)) = home(State(state.clone()), user.clone()).await else {
+            panic!("home page failed");
+        };
+        assert_document(&html);
+
+        let Ok(Html(html)) =
+            guild_dashboard(State(state), user.clone(), Path(1), Query(HashMap::new())).await
+        else {
+            panic!("guild dashboard failed");
+        };
+        assert_document(&html);
+
+        // Every htmx form on the dashboard sends the csrf token along from here
+        let body = format!(
+            "<body hx-headers='{}' >",
+            crate::htmx::escape(&csrf_headers(&user))
+        );
+        assert!(html.contains(&body), "{html}");
+    }
+
+    #[test]
+    fn intro_names_are_escaped() {
+        let intro = db::Intro {
+            id: 1,
+            name: "<script>alert(1)</script>".to_string(),
+            filename: "1.mp3".to_string(),
+            volume: 0,
+            duration_secs: None,
+            uploaded_by: None,
+            created_at: chrono::Utc::now().naive_utc(),
+            play_count: 0,
+            content_hash: None,
+        };
+
+        let html = intro_list(
+            "https://memejoin.example",
+            1,
+            [&intro].into_iter(),
+            "Add",
+            "/v2/intros/add/1/100",
+            None,
+        )
+        .build();
+
+        assert!(!html.contains("<script>"), "{html}");
+        assert!(
+            html.contains("&lt;script&gt;alert(1)&lt;/script&gt;"),
+            "{html}"
+        );
+    }
+
+    #[test]
+    fn channels_identified_by_id_in_urls() {
+        let intro = || db::Intro {
+            id: 1,
+            name: "hello".to_string(),
+            filename: "1.mp3".to_string(),
+            volume: 0,
+            duration_secs: None,
+            uploaded_by: None,
+            created_at: chrono::Utc::now().naive_utc(),
+            play_count: 0,
+            content_hash: None,
+        };
+        let user_intro = db::UserIntro {
+            intro: intro(),
+            channel_name: "afk / quiet".to_string(),
+            channel_id: 100,
+            username: "bob".to_string(),
+            kind: db::IntroKind::Intro,
+            enabled: true,
+        };
+
+        let html = channel_intro_selector(
+            "https://memejoin.example",
+            1,
+            100,
+            db::IntroKind::Intro,
+            [&user_intro].into_iter(),
+            [&intro()].into_iter(),
+        )
+        .build();
+
+        for url in [
+            "/v2/intros/remove/1/100",
+            "/v2/intros/enabled/1/100",
+            "/v2/intros/volume/1/100/1",
+            "/v2/intros/1/search?channel=100",
+            "/v2/intros/add/1/100",
+        ] {
+            assert!(html.contains(url), "{url} missing from {html}");
+        }
+        assert!(!html.contains("afk"), "{html}");
+    }
+}
@@ -0,0 +1,62 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Allows one request per key every `interval`, e.g. one expensive download per user.
+pub(crate) struct RateLimiter {
+    interval: Duration,
+    last_allowed: Mutex<HashMap<String, Instant>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_allowed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns whether `key` is allowed through right now, counting it as a request if so
+    pub(crate) fn check(&self, key: &str) -> bool {
+        let now = Instant::now();
+        let mut last_allowed = self
+            .last_allowed
+            .lock()
+            .expect("rate limiter lock was poisoned");
+
+        // Forget about anyone who is allowed again anyways so this doesn't grow forever
+        last_allowed.retain(|_, last| now.duration_since(*last) < self.interval);
+
+        if last_allowed.contains_key(key) {
+            return false;
+        }
+
+        last_allowed.insert(key.to_string(), now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_request_in_interval_is_rejected() {
+        let limiter = RateLimiter::new(Duration::from_secs(60));
+
+        assert!(limiter.check("bob"));
+        assert!(!limiter.check("bob"));
+        // Everyone gets their own limit
+        assert!(limiter.check("alice"));
+    }
+
+    #[test]
+    fn allowed_again_after_interval() {
+        let limiter = RateLimiter::new(Duration::ZERO);
+
+        assert!(limiter.check("bob"));
+        assert!(limiter.check("bob"));
+    }
+}
@@ -1,29 +1,55 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Instant};
 
 use axum::{
-    extract::{Multipart, Path, Query, State},
-    http::{HeaderMap, HeaderValue},
-    response::{Html, IntoResponse, Redirect},
+    body::Body,
+    extract::{
+        multipart::{Field, MultipartError},
+        Multipart, Path, Query, State,
+    },
+    http::{HeaderMap, HeaderValue, Request},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Redirect, Response,
+    },
+    Json,
 };
 
 use axum_extra::extract::{cookie::Cookie, CookieJar};
 use chrono::{Duration, Utc};
+use futures::{Stream, StreamExt};
 use reqwest::{StatusCode, Url};
-use serde::{Deserialize, Deserializer};
-use std::str::FromStr;
-use tracing::{error, info};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{convert::Infallible, str::FromStr};
+use tokio::io::AsyncWriteExt;
+use tower_http::services::ServeFile;
+use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
 use crate::{
     auth::{self},
     db,
     htmx::Build,
-    page,
+    page::{self, ErrorPage},
 };
-use crate::{media, settings::ApiState};
+use crate::{
+    jobs::JobStatus,
+    media, now_playing,
+    settings::{ApiState, ApiUser, MediaSettings},
+    HandlerMessage,
+};
+
+pub(crate) async fn prometheus_metrics(State(state): State<ApiState>) -> String {
+    state.metrics.render()
+}
 
-pub(crate) async fn health() -> &'static str {
-    "Hello!"
+pub(crate) async fn health(State(state): State<ApiState>) -> (StatusCode, &'static str) {
+    match state.db.ping() {
+        Ok(()) => (StatusCode::OK, "Hello!"),
+        Err(err) => {
+            error!(?err, "health check couldn't reach the database");
+            (StatusCode::SERVICE_UNAVAILABLE, "database unavailable")
+        }
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -37,6 +63,16 @@ pub(crate) enum Error {
     NoGuildFound,
     #[error("invalid request")]
     InvalidRequest,
+    #[error("uploaded file is too large")]
+    UploadTooLarge,
+    #[error("not a supported audio file")]
+    UnsupportedAudio,
+    #[error("playlists and channels aren't supported, link to a single video instead")]
+    PlaylistUrl,
+    #[error("sound file doesn't exist")]
+    SoundNotFound,
+    #[error("job doesn't exist")]
+    JobNotFound,
 
     #[error("Invalid permissions for request")]
     InvalidPermission,
@@ -45,13 +81,20 @@ pub(crate) enum Error {
     #[error("{0}")]
     Ffmpeg(String),
 
-    #[error("ytdl terminated unsuccessfully")]
-    YtdlTerminated,
-    #[error("ffmpeg terminated unsuccessfully")]
-    FfmpegTerminated,
+    #[error("ytdl terminated unsuccessfully: {0}")]
+    YtdlTerminated(String),
+    #[error("ffmpeg terminated unsuccessfully: {0}")]
+    FfmpegTerminated(String),
 
     #[error("database error: {0}")]
-    Database(#[from] rusqlite::Error),
+    Database(#[from] db::Error),
+
+    #[error("the bot isn't running")]
+    BotUnavailable,
+    #[error("too many requests, try again later")]
+    TooManyRequests,
+    #[error("background task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
 }
 
 impl IntoResponse for Error {
@@ -62,21 +105,39 @@ impl IntoResponse for Error {
             Self::Auth(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg).into_response(),
             Self::GetUser(error) => (StatusCode::UNAUTHORIZED, error.to_string()).into_response(),
 
-            Self::NoGuildFound => (StatusCode::NOT_FOUND, self.to_string()).into_response(),
-            Self::InvalidRequest => (StatusCode::BAD_REQUEST, self.to_string()).into_response(),
+            Self::NoGuildFound | Self::SoundNotFound | Self::JobNotFound => {
+                (StatusCode::NOT_FOUND, self.to_string()).into_response()
+            }
+            Self::InvalidRequest | Self::UnsupportedAudio | Self::PlaylistUrl => {
+                (StatusCode::BAD_REQUEST, self.to_string()).into_response()
+            }
+            Self::UploadTooLarge => {
+                (StatusCode::PAYLOAD_TOO_LARGE, self.to_string()).into_response()
+            }
 
             Self::InvalidPermission => (StatusCode::UNAUTHORIZED, self.to_string()).into_response(),
             Self::Ytdl(error) => {
                 (StatusCode::INTERNAL_SERVER_ERROR, error.to_string()).into_response()
             }
             Self::Ffmpeg(error) => (StatusCode::INTERNAL_SERVER_ERROR, error).into_response(),
-            Self::YtdlTerminated | Self::FfmpegTerminated => {
+            Self::YtdlTerminated(_) | Self::FfmpegTerminated(_) => {
                 (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response()
             }
 
+            Self::Database(
+                error @ (db::Error::DuplicateIntroName | db::Error::DuplicateChannelName),
+            ) => (StatusCode::BAD_REQUEST, error.to_string()).into_response(),
             Self::Database(error) => {
                 (StatusCode::INTERNAL_SERVER_ERROR, error.to_string()).into_response()
             }
+
+            Self::BotUnavailable => {
+                (StatusCode::SERVICE_UNAVAILABLE, self.to_string()).into_response()
+            }
+            Self::TooManyRequests => {
+                (StatusCode::TOO_MANY_REQUESTS, self.to_string()).into_response()
+            }
+            Self::Task(_) => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response(),
         }
     }
 }
@@ -93,6 +154,34 @@ struct DiscordUserGuild {
     pub owner: bool,
 }
 
+#[derive(Deserialize)]
+struct DiscordGuildMember {
+    roles: Vec<String>,
+}
+
+async fn get_discord_member_roles(
+    client: &reqwest::Client,
+    access_token: &str,
+    guild_id: u64,
+) -> Result<Vec<u64>, Error> {
+    let member: DiscordGuildMember = auth::with_discord_retries(auth::is_transient, || {
+        let request = client
+            .get(format!(
+                "https://discord.com/api/v10/users/@me/guilds/{guild_id}/member"
+            ))
+            .bearer_auth(access_token);
+
+        async move { request.send().await?.error_for_status()?.json().await }
+    })
+    .await?;
+
+    Ok(member
+        .roles
+        .iter()
+        .filter_map(|role| role.parse().ok())
+        .collect())
+}
+
 fn serde_string_as_u64<'de, D>(deserializer: D) -> Result<u64, D::Error>
 where
     D: Deserializer<'de>,
@@ -104,10 +193,69 @@ where
         .map_err(|_| serde::de::Error::invalid_value(serde::de::Unexpected::Str(value), &"u64"))
 }
 
+/// Adds the user to `guild_id`, giving them permissions from their discord roles the first time
+async fn set_up_user_guild(
+    db: &db::Database,
+    client: &reqwest::Client,
+    access_token: &str,
+    username: &str,
+    guild_id: u64,
+    owner: bool,
+) -> Result<(), Error> {
+    db.insert_user_guild(username, guild_id)
+        .map_err(Error::Database)?;
+
+    let existing_permissions = db.get_user_permissions(username, guild_id).ok();
+    let mut permissions = existing_permissions.unwrap_or_else(|| {
+        if owner {
+            auth::Permissions::all()
+        } else {
+            Default::default()
+        }
+    });
+
+    // Guilds without a role mapping keep the default of only trusting the owner
+    let role_permissions = db.get_guild_role_permissions(guild_id)?;
+    if !owner && !role_permissions.is_empty() {
+        match get_discord_member_roles(client, access_token, guild_id).await {
+            Ok(roles) => {
+                for (role_id, role_permissions) in role_permissions {
+                    if roles.contains(&role_id) {
+                        permissions |= role_permissions;
+                    }
+                }
+            }
+            Err(err) => {
+                error!(?err, guild_id, "couldn't get discord member roles");
+            }
+        }
+    }
+
+    if existing_permissions != Some(permissions) {
+        db.insert_user_permission(username, guild_id, permissions)
+            .map_err(Error::Database)?;
+    }
+
+    Ok(())
+}
+
 pub(crate) async fn v2_auth(
     State(state): State<ApiState>,
     Query(params): Query<HashMap<String, String>>,
     jar: CookieJar,
+) -> Result<(CookieJar, Redirect), Error> {
+    let result = authenticate(&state, &params, jar).await;
+
+    let outcome = if result.is_ok() { "success" } else { "failure" };
+    metrics::increment_counter!("memejoin_auth_total", "result" => outcome);
+
+    result
+}
+
+async fn authenticate(
+    state: &ApiState,
+    params: &HashMap<String, String>,
+    jar: CookieJar,
 ) -> Result<(CookieJar, Redirect), Error> {
     let Some(code) = params.get("code") else {
         return Err(Error::Auth("no code".to_string()));
@@ -126,42 +274,56 @@ pub(crate) async fn v2_auth(
 
     let client = reqwest::Client::new();
 
-    let auth: auth::Discord = client
-        .post("https://discord.com/api/oauth2/token")
-        .form(&data)
-        .send()
-        .await
-        .map_err(|err| Error::Auth(err.to_string()))?
-        .json()
-        .await
-        .map_err(|err| {
-            error!(?err, "auth error");
-            Error::Auth(err.to_string())
-        })?;
+    // The code can only be exchanged once, so this is only retried when the request never made
+    // it to discord
+    let auth: auth::Discord = auth::with_discord_retries(reqwest::Error::is_connect, || {
+        let request = client
+            .post("https://discord.com/api/oauth2/token")
+            .form(&data);
+
+        async move { request.send().await?.json().await }
+    })
+    .await
+    .map_err(|err| {
+        error!(?err, "auth error");
+        Error::Auth(format!("couldn't get an access token from discord: {err}"))
+    })?;
+
+    let missing_scopes = auth.missing_scopes();
+    if !missing_scopes.is_empty() {
+        return Err(Error::Auth(format!(
+            "discord login is missing the {} scope(s), try logging in again",
+            missing_scopes.join(", ")
+        )));
+    }
     let token = Uuid::new_v4().to_string();
 
     // Get authorized username
-    let user: DiscordUser = client
-        .get("https://discord.com/api/v10/users/@me")
-        .bearer_auth(&auth.access_token)
-        .send()
-        .await?
-        .json()
-        .await?;
+    let user: DiscordUser = auth::with_discord_retries(auth::is_transient, || {
+        let request = client
+            .get("https://discord.com/api/v10/users/@me")
+            .bearer_auth(&auth.access_token);
+
+        async move { request.send().await?.error_for_status()?.json().await }
+    })
+    .await
+    .map_err(|err| Error::Auth(format!("couldn't get user from discord: {err}")))?;
 
     // TODO: get bot's guilds so we only save users who are able to use the bot
-    let discord_guilds: Vec<DiscordUserGuild> = client
-        .get("https://discord.com/api/v10/users/@me/guilds")
-        .bearer_auth(&auth.access_token)
-        .send()
-        .await?
-        .json()
+    let discord_guilds: Vec<DiscordUserGuild> =
+        auth::with_discord_retries(auth::is_transient, || {
+            let request = client
+                .get("https://discord.com/api/v10/users/@me/guilds")
+                .bearer_auth(&auth.access_token);
+
+            async move { request.send().await?.error_for_status()?.json().await }
+        })
         .await
-        .map_err(|err| Error::Auth(err.to_string()))?;
+        .map_err(|err| Error::Auth(format!("couldn't get guilds from discord: {err}")))?;
 
-    let db = state.db.lock().await;
+    let db = &state.db;
 
-    let guilds = db.get_guilds().map_err(Error::Database)?;
+    let guilds = db.get_active_guilds().map_err(Error::Database)?;
     let mut in_a_guild = false;
     for guild in guilds {
         let Some(discord_guild) = discord_guilds
@@ -180,31 +342,32 @@ pub(crate) async fn v2_auth(
             now + Duration::weeks(4),
             &auth.access_token,
             now + Duration::seconds(auth.expires_in as i64),
+            &auth.refresh_token,
         )
         .map_err(Error::Database)?;
 
-        db.insert_user_guild(&user.username, guild.id)
-            .map_err(Error::Database)?;
-
-        if db.get_user_permissions(&user.username, guild.id).is_err() {
-            db.insert_user_permission(
-                &user.username,
-                guild.id,
-                if discord_guild.owner {
-                    auth::Permissions(auth::Permission::all())
-                } else {
-                    Default::default()
-                },
-            )
-            .map_err(Error::Database)?;
-        }
+        set_up_user_guild(
+            db,
+            &client,
+            &auth.access_token,
+            &user.username,
+            guild.id,
+            discord_guild.owner,
+        )
+        .await?;
     }
 
     if !in_a_guild {
         return Err(Error::NoGuildFound);
     }
 
-    // TODO: add permissions based on roles
+    // Remembered so guilds the bot joins later can be offered on the home page
+    let discord_guilds = discord_guilds
+        .iter()
+        .map(|guild| (guild.id, guild.owner))
+        .collect::<Vec<_>>();
+    db.set_user_discord_guilds(&user.username, &discord_guilds)
+        .map_err(Error::Database)?;
 
     let uri = Url::parse(&state.origin).expect("should be a valid url");
 
@@ -215,105 +378,464 @@ pub(crate) async fn v2_auth(
     Ok((jar.add(cookie), Redirect::to(&format!("{}/", state.origin))))
 }
 
+/// Sets the user up in a guild they were in at login, but that the bot only joined afterwards
+pub(crate) async fn v2_set_up_guild(
+    State(state): State<ApiState>,
+    Path(guild_id): Path<u64>,
+    user: db::User,
+) -> Result<HeaderMap, Error> {
+    let db = &state.db;
+
+    if !db
+        .get_active_guilds()
+        .map_err(Error::Database)?
+        .into_iter()
+        .any(|guild| guild.id == guild_id)
+    {
+        return Err(Error::NoGuildFound);
+    }
+
+    let Some(owner) = db
+        .get_user_discord_guild_owner(&user.name, guild_id)
+        .map_err(Error::Database)?
+    else {
+        return Err(Error::NoGuildFound);
+    };
+
+    set_up_user_guild(
+        db,
+        &reqwest::Client::new(),
+        &user.discord_token,
+        &user.name,
+        guild_id,
+        owner,
+    )
+    .await?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert("HX-Refresh", HeaderValue::from_static("true"));
+
+    Ok(headers)
+}
+
 pub(crate) async fn v2_add_intro_to_user(
     State(state): State<ApiState>,
-    Path((guild_id, channel)): Path<(u64, String)>,
+    Path((guild_id, channel_id)): Path<(u64, u64)>,
     user: db::User,
-    mut form_data: Multipart,
-) -> Result<Html<String>, Redirect> {
-    let db = state.db.lock().await;
+    form_data: Multipart,
+) -> Result<Html<String>, ErrorPage> {
+    add_user_intros(
+        state,
+        guild_id,
+        channel_id,
+        user,
+        form_data,
+        db::IntroKind::Intro,
+    )
+    .await
+}
+
+pub(crate) async fn v2_add_outro_to_user(
+    State(state): State<ApiState>,
+    Path((guild_id, channel_id)): Path<(u64, u64)>,
+    user: db::User,
+    form_data: Multipart,
+) -> Result<Html<String>, ErrorPage> {
+    add_user_intros(
+        state,
+        guild_id,
+        channel_id,
+        user,
+        form_data,
+        db::IntroKind::Outro,
+    )
+    .await
+}
 
+/// Intro ids from a form of checkboxes named after them. Any other fields that come along with
+/// them (a csrf token for example) are skipped rather than failing the whole request.
+async fn form_intro_ids(mut form_data: Multipart) -> Vec<i32> {
+    let mut intro_ids = Vec::new();
     while let Ok(Some(field)) = form_data.next_field().await {
-        let Some(intro_id) = field.name() else {
+        let Some(field_name) = field.name() else {
             continue;
         };
 
-        let intro_id = intro_id.parse::<i32>().map_err(|err| {
-            error!(?err, "invalid intro id");
-            // TODO: change to actual error
-            Redirect::to(&format!("{}/login", state.origin))
-        })?;
+        match field_name.parse::<i32>() {
+            Ok(intro_id) => intro_ids.push(intro_id),
+            Err(_) => debug!(field_name, "skipping non intro id form field"),
+        }
+    }
+
+    intro_ids
+}
+
+/// How many intros a user ends up with in a channel, picking one they already have doesn't count
+fn intros_after_adding(current: &[db::Intro], intro_ids: &[i32]) -> usize {
+    let mut ids = current.iter().map(|intro| intro.id).collect::<Vec<_>>();
+    for intro_id in intro_ids {
+        if !ids.contains(intro_id) {
+            ids.push(*intro_id);
+        }
+    }
+
+    ids.len()
+}
 
-        db.insert_user_intro(&user.name, guild_id, &channel, intro_id)
+async fn add_user_intros(
+    state: ApiState,
+    guild_id: u64,
+    channel_id: u64,
+    user: db::User,
+    form_data: Multipart,
+    kind: db::IntroKind,
+) -> Result<Html<String>, ErrorPage> {
+    let db = &state.db;
+    check_guild_channel(&state, guild_id, channel_id)?;
+
+    let intro_ids = form_intro_ids(form_data).await;
+
+    // Paused intros are still picked, so they count towards the limit too
+    let current_intros = db
+        .get_user_channel_picks(&user.name, guild_id, channel_id, kind)
+        .map_err(|err| {
+            error!(?err, "failed to get user intros");
+            ErrorPage::internal("failed to get user intros")
+        })?
+        .into_iter()
+        .map(|pick| pick.intro)
+        .collect::<Vec<_>>();
+    if intros_after_adding(&current_intros, &intro_ids) > state.max_intros_per_channel {
+        let noun = match kind {
+            db::IntroKind::Intro => "intros",
+            db::IntroKind::Outro => "outros",
+        };
+
+        return Err(ErrorPage::bad_request(&format!(
+            "you can only pick up to {} {noun} per channel",
+            state.max_intros_per_channel
+        )));
+    }
+
+    for intro_id in intro_ids {
+        db.insert_user_intro(&user.name, guild_id, channel_id, intro_id, kind)
             .map_err(|err| {
                 error!(?err, "failed to add user intro");
-                // TODO: change to actual error
-                Redirect::to(&format!("{}/login", state.origin))
+                ErrorPage::internal("failed to add user intro")
             })?;
     }
 
-    let guild_intros = db.get_guild_intros(guild_id).map_err(|err| {
-        error!(?err, %guild_id, "couldn't get guild intros");
-        // TODO: change to actual error
-        Redirect::to(&format!("{}/login", state.origin))
-    })?;
+    user_channel_intro_selector(&state, guild_id, channel_id, &user, kind)
+}
 
-    let intros = db
-        .get_user_channel_intros(&user.name, guild_id, &channel)
-        .map_err(|err| {
-            error!(?err, user = %user.name, %guild_id, "couldn't get user intros");
-            // TODO: change to actual error
-            Redirect::to(&format!("{}/login", state.origin))
-        })?;
+pub(crate) async fn v2_remove_intro_from_user(
+    State(state): State<ApiState>,
+    Path((guild_id, channel_id)): Path<(u64, u64)>,
+    user: db::User,
+    form_data: Multipart,
+) -> Result<Html<String>, ErrorPage> {
+    remove_user_intros(
+        state,
+        guild_id,
+        channel_id,
+        user,
+        form_data,
+        db::IntroKind::Intro,
+    )
+    .await
+}
 
-    Ok(Html(
-        page::channel_intro_selector(
-            &state.origin,
+pub(crate) async fn v2_remove_outro_from_user(
+    State(state): State<ApiState>,
+    Path((guild_id, channel_id)): Path<(u64, u64)>,
+    user: db::User,
+    form_data: Multipart,
+) -> Result<Html<String>, ErrorPage> {
+    remove_user_intros(
+        state,
+        guild_id,
+        channel_id,
+        user,
+        form_data,
+        db::IntroKind::Outro,
+    )
+    .await
+}
+
+async fn remove_user_intros(
+    state: ApiState,
+    guild_id: u64,
+    channel_id: u64,
+    user: db::User,
+    form_data: Multipart,
+    kind: db::IntroKind,
+) -> Result<Html<String>, ErrorPage> {
+    let db = &state.db;
+    check_guild_channel(&state, guild_id, channel_id)?;
+
+    for intro_id in form_intro_ids(form_data).await {
+        db.delete_user_intro(&user.name, guild_id, channel_id, intro_id, kind)
+            .map_err(|err| {
+                error!(?err, "failed to remove user intro");
+                ErrorPage::internal("failed to remove user intro")
+            })?;
+    }
+
+    user_channel_intro_selector(&state, guild_id, channel_id, &user, kind)
+}
+
+pub(crate) async fn v2_set_user_intro_volume(
+    State(state): State<ApiState>,
+    Path((guild_id, channel_id, intro_id)): Path<(u64, u64, i32)>,
+    user: db::User,
+    form_data: Multipart,
+) -> Result<Html<String>, ErrorPage> {
+    set_user_intro_volume(
+        state,
+        guild_id,
+        channel_id,
+        intro_id,
+        user,
+        form_data,
+        db::IntroKind::Intro,
+    )
+    .await
+}
+
+pub(crate) async fn v2_set_user_outro_volume(
+    State(state): State<ApiState>,
+    Path((guild_id, channel_id, intro_id)): Path<(u64, u64, i32)>,
+    user: db::User,
+    form_data: Multipart,
+) -> Result<Html<String>, ErrorPage> {
+    set_user_intro_volume(
+        state,
+        guild_id,
+        channel_id,
+        intro_id,
+        user,
+        form_data,
+        db::IntroKind::Outro,
+    )
+    .await
+}
+
+async fn set_user_intro_volume(
+    state: ApiState,
+    guild_id: u64,
+    channel_id: u64,
+    intro_id: i32,
+    user: db::User,
+    mut form_data: Multipart,
+    kind: db::IntroKind,
+) -> Result<Html<String>, ErrorPage> {
+    let mut volume = None;
+    while let Ok(Some(field)) = form_data.next_field().await {
+        if field.name() != Some("volume") {
+            continue;
+        }
+
+        let value = field.text().await.unwrap_or_default();
+        volume = Some(value.trim().parse::<i32>().map_err(|err| {
+            error!(?err, "invalid volume");
+            ErrorPage::bad_request("invalid volume")
+        })?);
+    }
+
+    let Some(volume) = volume else {
+        return Err(ErrorPage::bad_request("missing volume"));
+    };
+    check_guild_channel(&state, guild_id, channel_id)?;
+
+    state
+        .db
+        .set_user_intro_volume(
+            &user.name,
             guild_id,
-            &channel,
-            intros.iter(),
-            guild_intros.iter(),
+            channel_id,
+            intro_id,
+            kind,
+            volume.clamp(0, 200),
         )
-        .build(),
-    ))
+        .map_err(|err| match err {
+            db::Error::UserIntroNotFound => {
+                ErrorPage::new(StatusCode::NOT_FOUND, "intro isn't picked for this channel")
+            }
+            err => {
+                error!(?err, "failed to set user intro volume");
+                ErrorPage::internal("failed to set user intro volume")
+            }
+        })?;
+
+    user_channel_intro_selector(&state, guild_id, channel_id, &user, kind)
 }
 
-pub(crate) async fn v2_remove_intro_from_user(
+pub(crate) async fn v2_set_user_intro_enabled(
     State(state): State<ApiState>,
-    Path((guild_id, channel)): Path<(u64, String)>,
+    Path((guild_id, channel_id, intro_id)): Path<(u64, u64, i32)>,
     user: db::User,
-    mut form_data: Multipart,
-) -> Result<Html<String>, Redirect> {
-    let db = state.db.lock().await;
+    form_data: Multipart,
+) -> Result<Html<String>, ErrorPage> {
+    set_user_intro_enabled(
+        state,
+        guild_id,
+        channel_id,
+        intro_id,
+        user,
+        form_data,
+        db::IntroKind::Intro,
+    )
+    .await
+}
+
+pub(crate) async fn v2_set_user_outro_enabled(
+    State(state): State<ApiState>,
+    Path((guild_id, channel_id, intro_id)): Path<(u64, u64, i32)>,
+    user: db::User,
+    form_data: Multipart,
+) -> Result<Html<String>, ErrorPage> {
+    set_user_intro_enabled(
+        state,
+        guild_id,
+        channel_id,
+        intro_id,
+        user,
+        form_data,
+        db::IntroKind::Outro,
+    )
+    .await
+}
 
+/// Pauses or resumes one of the user's intros, it stays picked either way
+async fn set_user_intro_enabled(
+    state: ApiState,
+    guild_id: u64,
+    channel_id: u64,
+    intro_id: i32,
+    user: db::User,
+    mut form_data: Multipart,
+    kind: db::IntroKind,
+) -> Result<Html<String>, ErrorPage> {
+    let mut enabled = None;
     while let Ok(Some(field)) = form_data.next_field().await {
-        let Some(intro_id) = field.name() else {
+        if field.name() != Some("enabled") {
             continue;
-        };
+        }
+
+        let value = field.text().await.unwrap_or_default();
+        enabled = Some(value.trim().parse::<bool>().map_err(|err| {
+            error!(?err, "invalid enabled value");
+            ErrorPage::bad_request("invalid enabled value")
+        })?);
+    }
+
+    let Some(enabled) = enabled else {
+        return Err(ErrorPage::bad_request("missing enabled value"));
+    };
+    check_guild_channel(&state, guild_id, channel_id)?;
 
-        let intro_id = intro_id.parse::<i32>().map_err(|err| {
-            error!(?err, "invalid intro id");
-            // TODO: change to actual error
-            Redirect::to(&format!("{}/login", state.origin))
+    state
+        .db
+        .set_user_intro_enabled(&user.name, guild_id, channel_id, intro_id, kind, enabled)
+        .map_err(|err| {
+            error!(?err, "failed to set user intro enabled");
+            ErrorPage::internal("failed to set user intro enabled")
         })?;
 
-        db.delete_user_intro(&user.name, guild_id, &channel, intro_id)
-            .map_err(|err| {
-                error!(?err, "failed to remove user intro");
-                // TODO: change to actual error
-                Redirect::to(&format!("{}/login", state.origin))
-            })?;
+    user_channel_intro_selector(&state, guild_id, channel_id, &user, kind)
+}
+
+/// Removes a single intro from a user's channel, for clients that aren't submitting the htmx form
+pub(crate) async fn v2_delete_intro_from_user(
+    State(state): State<ApiState>,
+    Path((guild_id, channel_id, intro_id)): Path<(u64, u64, i32)>,
+    user: db::User,
+) -> Result<Html<String>, ErrorPage> {
+    delete_user_intro(
+        &state,
+        guild_id,
+        channel_id,
+        intro_id,
+        &user,
+        db::IntroKind::Intro,
+    )
+}
+
+pub(crate) async fn v2_delete_outro_from_user(
+    State(state): State<ApiState>,
+    Path((guild_id, channel_id, intro_id)): Path<(u64, u64, i32)>,
+    user: db::User,
+) -> Result<Html<String>, ErrorPage> {
+    delete_user_intro(
+        &state,
+        guild_id,
+        channel_id,
+        intro_id,
+        &user,
+        db::IntroKind::Outro,
+    )
+}
+
+fn delete_user_intro(
+    state: &ApiState,
+    guild_id: u64,
+    channel_id: u64,
+    intro_id: i32,
+    user: &db::User,
+    kind: db::IntroKind,
+) -> Result<Html<String>, ErrorPage> {
+    check_guild_channel(state, guild_id, channel_id)?;
+
+    state
+        .db
+        .delete_user_intro(&user.name, guild_id, channel_id, intro_id, kind)
+        .map_err(|err| {
+            error!(?err, "failed to remove user intro");
+            ErrorPage::internal("failed to remove user intro")
+        })?;
+
+    user_channel_intro_selector(state, guild_id, channel_id, user, kind)
+}
+
+/// Intros can only be picked for channels that have been synced from discord
+fn check_guild_channel(state: &ApiState, guild_id: u64, channel_id: u64) -> Result<(), ErrorPage> {
+    match state.db.get_channel_name(guild_id, channel_id) {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err(ErrorPage::bad_request("channel doesn't exist")),
+        Err(err) => {
+            error!(?err, %guild_id, %channel_id, "couldn't get channel");
+            Err(ErrorPage::internal("couldn't get channel"))
+        }
     }
+}
+
+fn user_channel_intro_selector(
+    state: &ApiState,
+    guild_id: u64,
+    channel_id: u64,
+    user: &db::User,
+    kind: db::IntroKind,
+) -> Result<Html<String>, ErrorPage> {
+    let db = &state.db;
 
     let guild_intros = db.get_guild_intros(guild_id).map_err(|err| {
         error!(?err, %guild_id, "couldn't get guild intros");
-        // TODO: change to actual error
-        Redirect::to(&format!("{}/login", state.origin))
+        ErrorPage::internal("couldn't get guild intros")
     })?;
 
     let intros = db
-        .get_user_channel_intros(&user.name, guild_id, &channel)
+        .get_user_channel_picks(&user.name, guild_id, channel_id, kind)
         .map_err(|err| {
             error!(?err, user = %user.name, %guild_id, "couldn't get user intros");
-            // TODO: change to actual error
-            Redirect::to(&format!("{}/login", state.origin))
+            ErrorPage::internal("couldn't get user intros")
         })?;
 
     Ok(Html(
         page::channel_intro_selector(
             &state.origin,
             guild_id,
-            &channel,
+            channel_id,
+            kind,
             intros.iter(),
             guild_intros.iter(),
         )
@@ -321,18 +843,124 @@ pub(crate) async fn v2_remove_intro_from_user(
     ))
 }
 
+// The body limit layer on the upload route surfaces as a multipart error, so pull
+// that back out into a 413 instead of lumping it in with malformed requests
+fn multipart_error(err: MultipartError) -> Error {
+    if err.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        Error::UploadTooLarge
+    } else {
+        Error::InvalidRequest
+    }
+}
+
+/// Streams an uploaded file to `path` as it arrives instead of holding all of it in memory,
+/// giving up as soon as it goes over `max_bytes`
+async fn write_upload(mut field: Field<'_>, path: &str, max_bytes: usize) -> Result<(), Error> {
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut written = 0;
+
+    while let Some(chunk) = field.chunk().await.map_err(multipart_error)? {
+        written += chunk.len();
+        if written > max_bytes {
+            return Err(Error::UploadTooLarge);
+        }
+
+        file.write_all(&chunk).await?;
+    }
+
+    file.flush().await?;
+    Ok(())
+}
+
+/// Uploaders give tags as a comma separated list, these are matched case-insensitively
+fn parse_tags(tags: &str) -> Vec<String> {
+    let mut parsed = Vec::new();
+    for tag in tags.split(',') {
+        let tag = tag.trim().to_lowercase();
+        if !tag.is_empty() && !parsed.contains(&tag) {
+            parsed.push(tag);
+        }
+    }
+
+    parsed
+}
+
+/// Finds an intro in the guild with the exact same sound as a new one, when `dedupe_uploads` is
+/// on. The new intro reuses its file rather than keeping another copy around.
+fn duplicate_intro(
+    db: &db::Database,
+    settings: &MediaSettings,
+    guild_id: u64,
+    content_hash: &str,
+) -> Result<Option<db::Intro>, Error> {
+    if !settings.dedupe_uploads {
+        return Ok(None);
+    }
+
+    let duplicate = db.find_intro_by_hash(guild_id, content_hash)?;
+    if let Some(intro) = &duplicate {
+        info!(
+            %guild_id,
+            intro = %intro.name,
+            "new sound is identical to an existing intro, reusing its file"
+        );
+    }
+
+    Ok(duplicate)
+}
+
+/// Adds a normalized or downloaded sound in `dest_file` as a guild intro. When it's identical to
+/// an existing intro the new intro points at that file instead and `dest_file` is removed.
+#[allow(clippy::too_many_arguments)]
+async fn insert_sound_intro(
+    db: &db::Database,
+    settings: &MediaSettings,
+    guild_id: u64,
+    dest_file: media::TempFile,
+    filename: String,
+    name: &str,
+    username: &str,
+    duration_secs: Option<u32>,
+    tags: &[String],
+) -> Result<i32, Error> {
+    let content_hash = media::content_hash(dest_file.path()).await?;
+    let duplicate = duplicate_intro(db, settings, guild_id, &content_hash)?;
+    let filename = duplicate
+        .as_ref()
+        .map_or(filename, |intro| intro.filename.clone());
+
+    let intro_id = db
+        .insert_intro(
+            name,
+            0,
+            guild_id,
+            &filename,
+            username,
+            Some(&content_hash),
+            duration_secs,
+            tags,
+        )
+        .map_err(Error::Database)?;
+    if duplicate.is_none() {
+        dest_file.keep();
+    }
+
+    Ok(intro_id)
+}
+
 pub(crate) async fn v2_upload_guild_intro(
     State(state): State<ApiState>,
     Path(guild_id): Path<u64>,
     user: db::User,
     mut form_data: Multipart,
-) -> Result<HeaderMap, Error> {
-    let db = state.db.lock().await;
+) -> Result<Html<String>, Error> {
+    let db = &state.db;
     let mut name = None;
-    let mut file = None;
+    let mut has_file = false;
+    let mut tags = Vec::new();
 
     if !db
-        .get_guilds()
+        .get_active_guilds()
         .map_err(Error::Database)?
         .into_iter()
         .any(|guild| guild.id == guild_id)
@@ -348,18 +976,29 @@ pub(crate) async fn v2_upload_guild_intro(
         return Err(Error::InvalidPermission);
     }
 
-    while let Ok(Some(field)) = form_data.next_field().await {
+    let uuid = Uuid::new_v4().to_string();
+    // Cleaned up if anything below fails, only the normalized file is kept
+    let temp_file = media::TempFile::new(media::temp_path(&state.media, &uuid));
+
+    while let Some(field) = form_data.next_field().await.map_err(multipart_error)? {
         let Some(field_name) = field.name() else {
             continue;
         };
 
         if field_name.eq_ignore_ascii_case("name") {
-            name = Some(field.text().await.map_err(|_| Error::InvalidRequest)?);
+            name = Some(field.text().await.map_err(multipart_error)?);
             continue;
         }
 
         if field_name.eq_ignore_ascii_case("file") {
-            file = Some(field.bytes().await.map_err(|_| Error::InvalidRequest)?);
+            // Written out so it's ready for codec conversion
+            write_upload(field, temp_file.path(), state.max_upload_bytes).await?;
+            has_file = true;
+            continue;
+        }
+
+        if field_name.eq_ignore_ascii_case("tags") {
+            tags = parse_tags(&field.text().await.map_err(multipart_error)?);
             continue;
         }
     }
@@ -367,44 +1006,77 @@ pub(crate) async fn v2_upload_guild_intro(
     let Some(name) = name else {
         return Err(Error::InvalidRequest);
     };
-    let Some(file) = file else {
+    let name = intro_name(&name)?.to_string();
+    if !has_file {
         return Err(Error::InvalidRequest);
-    };
-
-    let uuid = Uuid::new_v4().to_string();
-    let temp_path = format!("./sounds/temp/{uuid}");
-    let dest_path = format!("./sounds/{uuid}.mp3");
-
-    // Write original file so its ready for codec conversion
-    std::fs::write(&temp_path, file)?;
-    media::normalize(&temp_path, &dest_path).await?;
-    std::fs::remove_file(&temp_path)?;
+    }
 
-    db.insert_intro(&name, 0, guild_id, &format!("{uuid}.mp3"))
-        .map_err(Error::Database)?;
+    // Checked again on insert, this just saves waiting on normalization to find out
+    if db.intro_name_exists(guild_id, &name)? {
+        return Err(Error::Database(db::Error::DuplicateIntroName));
+    }
 
-    let mut headers = HeaderMap::new();
-    headers.insert("HX-Refresh", HeaderValue::from_static("true"));
+    let db = db.clone();
+    let media_settings = state.media.clone();
+    let job_id = state.jobs.spawn(guild_id, "upload", async move {
+        let codec = media::probe_audio(temp_file.path()).await?;
+        let filename = media::upload_filename(&uuid, &codec, &media_settings);
+        let dest_file = media::TempFile::new(media::sound_path(&media_settings, &filename));
+        let duration_secs =
+            media::normalize(temp_file.path(), dest_file.path(), &codec, &media_settings).await?;
+
+        insert_sound_intro(
+            &db,
+            &media_settings,
+            guild_id,
+            dest_file,
+            filename,
+            &name,
+            &user.name,
+            duration_secs,
+            &tags,
+        )
+        .await
+    });
 
-    Ok(headers)
+    Ok(Html(
+        page::job_status(&state.origin, guild_id, &job_id, &JobStatus::Pending).build(),
+    ))
 }
 
 pub(crate) async fn v2_add_guild_intro(
     State(state): State<ApiState>,
     Path(guild_id): Path<u64>,
-    Query(mut params): Query<HashMap<String, String>>,
     user: db::User,
-) -> Result<HeaderMap, Error> {
-    let db = state.db.lock().await;
+    mut form_data: Multipart,
+) -> Result<Html<String>, Error> {
+    let db = &state.db;
+
+    let mut params = HashMap::new();
+    while let Some(field) = form_data.next_field().await.map_err(multipart_error)? {
+        let Some(field_name) = field.name().map(str::to_ascii_lowercase) else {
+            continue;
+        };
+
+        params.insert(field_name, field.text().await.map_err(multipart_error)?);
+    }
+
     let Some(url) = params.remove("url") else {
         return Err(Error::InvalidRequest);
     };
     let Some(name) = params.remove("name") else {
         return Err(Error::InvalidRequest);
     };
+    let name = intro_name(&name)?.to_string();
+    let tags = params
+        .remove("tags")
+        .map(|tags| parse_tags(&tags))
+        .unwrap_or_default();
+    // Checked up front so a bad url doesn't use up the user's rate limit
+    media::validate_url(&url, &state.media.ytdl_allowed_domains)?;
 
     if !db
-        .get_guilds()
+        .get_active_guilds()
         .map_err(Error::Database)?
         .into_iter()
         .any(|guild| guild.id == guild_id)
@@ -420,37 +1092,413 @@ pub(crate) async fn v2_add_guild_intro(
         return Err(Error::InvalidPermission);
     }
 
-    let uuid = Uuid::new_v4().to_string();
-    let child = tokio::process::Command::new("yt-dlp")
-        .arg(&url)
-        .args(["-o", &format!("sounds/{uuid}")])
-        .args(["-x", "--audio-format", "mp3"])
-        .spawn()
-        .map_err(Error::Ytdl)?
-        .wait()
-        .await
-        .map_err(Error::Ytdl)?;
+    if db.intro_name_exists(guild_id, &name)? {
+        return Err(Error::Database(db::Error::DuplicateIntroName));
+    }
+
+    if !state.ytdl_limiter.check(&user.name) {
+        return Err(Error::TooManyRequests);
+    }
+
+    let db = db.clone();
+    let media_settings = state.media.clone();
+    let job_id =
+        state
+            .jobs
+            .spawn_limited(guild_id, "ytdl", state.ytdl_permits.clone(), async move {
+                let uuid = Uuid::new_v4().to_string();
+                let filename = media::download(&url, &uuid, &media_settings).await?;
+                let dest_file = media::TempFile::new(media::sound_path(&media_settings, &filename));
+                let duration_secs = media::probe_duration(dest_file.path()).await;
+
+                insert_sound_intro(
+                    &db,
+                    &media_settings,
+                    guild_id,
+                    dest_file,
+                    filename,
+                    &name,
+                    &user.name,
+                    duration_secs,
+                    &tags,
+                )
+                .await
+            });
+
+    Ok(Html(
+        page::job_status(&state.origin, guild_id, &job_id, &JobStatus::Pending).build(),
+    ))
+}
+
+pub(crate) async fn v2_guild_intro_job(
+    State(state): State<ApiState>,
+    Path((guild_id, job_id)): Path<(u64, String)>,
+    _user: db::User,
+) -> Result<(HeaderMap, Html<String>), Error> {
+    let Some(status) = state.jobs.status(guild_id, &job_id) else {
+        return Err(Error::JobNotFound);
+    };
+
+    let mut headers = HeaderMap::new();
+    if matches!(status, JobStatus::Done(_)) {
+        headers.insert("HX-Refresh", HeaderValue::from_static("true"));
+    }
+
+    Ok((
+        headers,
+        Html(page::job_status(&state.origin, guild_id, &job_id, &status).build()),
+    ))
+}
+
+pub(crate) async fn v2_list_guild_intros(
+    State(state): State<ApiState>,
+    Path(guild_id): Path<u64>,
+    ApiUser(user): ApiUser,
+) -> Result<Json<Vec<db::Intro>>, Error> {
+    let db = &state.db;
+
+    if !db
+        .get_user_guilds(&user.name)?
+        .into_iter()
+        .any(|guild| guild.id == guild_id)
+    {
+        return Err(Error::NoGuildFound);
+    }
+
+    Ok(Json(db.get_guild_intros(guild_id)?))
+}
+
+/// The guild's intros with `q` in their name, as the list for adding them to the user's `channel`
+pub(crate) async fn v2_search_guild_intros(
+    State(state): State<ApiState>,
+    Path(guild_id): Path<u64>,
+    Query(params): Query<HashMap<String, String>>,
+    user: db::User,
+) -> Result<Html<String>, ErrorPage> {
+    let db = &state.db;
+
+    let Some(channel_id) = params
+        .get("channel")
+        .and_then(|channel| channel.parse::<u64>().ok())
+    else {
+        return Err(ErrorPage::bad_request("missing channel"));
+    };
+    let kind = match params.get("kind").map(String::as_str) {
+        None | Some("intros") => db::IntroKind::Intro,
+        Some("outros") => db::IntroKind::Outro,
+        Some(_) => return Err(ErrorPage::bad_request("invalid kind")),
+    };
+
+    let is_member = db
+        .get_user_guilds(&user.name)
+        .map_err(|err| {
+            error!(?err, user = %user.name, "couldn't get user guilds");
+            ErrorPage::internal("couldn't get user guilds")
+        })?
+        .into_iter()
+        .any(|guild| guild.id == guild_id);
+    if !is_member {
+        return Err(ErrorPage::new(StatusCode::NOT_FOUND, "guild doesn't exist"));
+    }
+
+    let query = params.get("q").map(|q| q.trim()).unwrap_or_default();
+    let intros = db.search_guild_intros(guild_id, query).map_err(|err| {
+        error!(?err, %guild_id, "couldn't search guild intros");
+        ErrorPage::internal("couldn't search guild intros")
+    })?;
+
+    Ok(Html(
+        page::add_intro_list(&state.origin, guild_id, channel_id, kind, intros.iter()).build(),
+    ))
+}
+
+#[derive(Serialize)]
+pub(crate) struct UserPermissions {
+    username: String,
+    permissions: auth::Permissions,
+}
+
+pub(crate) async fn v2_list_guild_permissions(
+    State(state): State<ApiState>,
+    Path(guild_id): Path<u64>,
+    ApiUser(user): ApiUser,
+) -> Result<Json<Vec<UserPermissions>>, Error> {
+    let db = &state.db;
+
+    let user_permissions = db
+        .get_user_permissions(&user.name, guild_id)
+        .unwrap_or_default();
 
-    if !child.success() {
-        return Err(Error::YtdlTerminated);
+    if !user_permissions.can(auth::Permission::Moderator) {
+        return Err(Error::InvalidPermission);
+    }
+
+    let permissions = db
+        .get_all_user_permissions(guild_id)?
+        .into_iter()
+        .map(|(username, permissions)| UserPermissions {
+            username,
+            permissions,
+        })
+        .collect();
+
+    Ok(Json(permissions))
+}
+
+/// Who has an intro set, so moderators know what deleting it affects before they do
+pub(crate) async fn v2_list_guild_intro_users(
+    State(state): State<ApiState>,
+    Path((guild_id, intro_id)): Path<(u64, i32)>,
+    user: db::User,
+) -> Result<Html<String>, Error> {
+    let db = &state.db;
+
+    let user_permissions = db
+        .get_user_permissions(&user.name, guild_id)
+        .unwrap_or_default();
+
+    if !user_permissions.can(auth::Permission::Moderator) {
+        return Err(Error::InvalidPermission);
+    }
+
+    let Some(intro) = db
+        .get_guild_intros(guild_id)?
+        .into_iter()
+        .find(|intro| intro.id == intro_id)
+    else {
+        return Err(Error::InvalidRequest);
+    };
+
+    let assignments = db.get_intro_assignments(guild_id, intro_id)?;
+
+    Ok(Html(
+        page::intro_delete_confirmation(&state.origin, guild_id, &intro, &assignments).build(),
+    ))
+}
+
+/// Whether a guild has opted in to anyone browsing its intros, logged in or not
+pub(crate) fn is_public_library(db: &db::Database, guild_id: u64) -> Result<bool, db::Error> {
+    Ok(db
+        .get_active_guilds()?
+        .into_iter()
+        .any(|guild| guild.id == guild_id && guild.public_library))
+}
+
+pub(crate) async fn v2_preview_guild_intro(
+    State(state): State<ApiState>,
+    Path((guild_id, intro_id)): Path<(u64, i32)>,
+    user: Option<db::User>,
+    request: Request<Body>,
+) -> Result<Response, Error> {
+    let intro = {
+        let db = &state.db;
+
+        let is_member = match &user {
+            Some(user) => db
+                .get_user_guilds(&user.name)?
+                .into_iter()
+                .any(|guild| guild.id == guild_id),
+            None => false,
+        };
+
+        if !is_member && !is_public_library(db, guild_id)? {
+            return Err(Error::NoGuildFound);
+        }
+
+        db.get_guild_intros(guild_id)?
+            .into_iter()
+            .find(|intro| intro.id == intro_id)
+            .ok_or(Error::SoundNotFound)?
+    };
+
+    // ServeFile takes care of the content type and range requests so the player can seek
+    let response = ServeFile::new(media::sound_path(&state.media, &intro.filename))
+        .try_call(request)
+        .await?;
+
+    if response.status() == StatusCode::NOT_FOUND {
+        warn!(filename = %intro.filename, "intro file is missing");
+        return Err(Error::SoundNotFound);
+    }
+
+    Ok(response.into_response())
+}
+
+const MAX_INTRO_NAME_LEN: usize = 100;
+
+/// `name` trimmed, as long as it's usable as an intro's name. New intros and renamed ones go
+/// through the same rules.
+fn intro_name(name: &str) -> Result<&str, Error> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_INTRO_NAME_LEN {
+        return Err(Error::InvalidRequest);
     }
 
-    db.insert_intro(&name, 0, guild_id, &format!("{uuid}.mp3"))
+    Ok(name)
+}
+
+pub(crate) async fn v2_rename_guild_intro(
+    State(state): State<ApiState>,
+    Path((guild_id, intro_id)): Path<(u64, i32)>,
+    user: db::User,
+    mut form_data: Multipart,
+) -> Result<HeaderMap, Error> {
+    let db = &state.db;
+
+    let user_permissions = db
+        .get_user_permissions(&user.name, guild_id)
         .map_err(Error::Database)?;
 
+    if !user_permissions.can(auth::Permission::UploadSounds) {
+        return Err(Error::InvalidPermission);
+    }
+
+    let mut name = None;
+    while let Ok(Some(field)) = form_data.next_field().await {
+        if field
+            .name()
+            .is_some_and(|field_name| field_name.eq_ignore_ascii_case("name"))
+        {
+            name = Some(field.text().await.map_err(|_| Error::InvalidRequest)?);
+        }
+    }
+
+    let name = intro_name(name.as_deref().unwrap_or_default())?;
+
+    if !db
+        .get_guild_intros(guild_id)?
+        .into_iter()
+        .any(|intro| intro.id == intro_id)
+    {
+        return Err(Error::SoundNotFound);
+    }
+
+    db.rename_intro(guild_id, intro_id, name)?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert("HX-Refresh", HeaderValue::from_static("true"));
+
+    Ok(headers)
+}
+
+pub(crate) async fn v2_delete_guild_intro(
+    State(state): State<ApiState>,
+    Path((guild_id, intro_id)): Path<(u64, i32)>,
+    user: db::User,
+) -> Result<HeaderMap, Error> {
+    let db = &state.db;
+
+    if !db
+        .get_active_guilds()
+        .map_err(Error::Database)?
+        .into_iter()
+        .any(|guild| guild.id == guild_id)
+    {
+        return Err(Error::NoGuildFound);
+    }
+
+    let user_permissions = db
+        .get_user_permissions(&user.name, guild_id)
+        .map_err(Error::Database)?;
+
+    if !user_permissions.can(auth::Permission::DeleteSounds) {
+        return Err(Error::InvalidPermission);
+    }
+
+    let Some(intro) = db
+        .get_guild_intros(guild_id)?
+        .into_iter()
+        .find(|intro| intro.id == intro_id)
+    else {
+        return Err(Error::InvalidRequest);
+    };
+
+    db.delete_intro(guild_id, intro_id)?;
+
+    // Deduplicated intros share a file, which has to stay until the last of them is gone
+    if !db.intro_file_in_use(&intro.filename)? {
+        match tokio::fs::remove_file(media::sound_path(&state.media, &intro.filename)).await {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                warn!(filename = %intro.filename, "intro file was already deleted");
+            }
+            // The intro is already gone from the db, so don't fail the request over a stray file
+            Err(err) => {
+                error!(?err, filename = %intro.filename, "failed to delete intro file");
+            }
+        }
+    }
+
+    info!(user = %user.name, %guild_id, intro = %intro.name, "deleted guild intro");
+
     let mut headers = HeaderMap::new();
     headers.insert("HX-Refresh", HeaderValue::from_static("true"));
 
     Ok(headers)
 }
 
+pub(crate) async fn v2_play_soundboard(
+    State(state): State<ApiState>,
+    Path((guild_id, channel_id, intro_id)): Path<(u64, u64, i32)>,
+    user: db::User,
+) -> Result<StatusCode, Error> {
+    let (intro, channel_name) = {
+        let db = &state.db;
+
+        if !db
+            .get_active_guilds()
+            .map_err(Error::Database)?
+            .into_iter()
+            .any(|guild| guild.id == guild_id)
+        {
+            return Err(Error::NoGuildFound);
+        }
+
+        let user_permissions = db
+            .get_user_permissions(&user.name, guild_id)
+            .map_err(Error::Database)?;
+
+        if !user_permissions.can(auth::Permission::Soundboard) {
+            return Err(Error::InvalidPermission);
+        }
+
+        let Some(channel_name) = db.get_channel_name(guild_id, channel_id)? else {
+            return Err(Error::InvalidRequest);
+        };
+
+        let Some(intro) = db
+            .get_guild_intros(guild_id)?
+            .into_iter()
+            .find(|intro| intro.id == intro_id)
+        else {
+            return Err(Error::InvalidRequest);
+        };
+
+        (intro, channel_name)
+    };
+
+    state
+        .bot_tx
+        .send(HandlerMessage::PlaySoundboard(
+            serenity::model::prelude::GuildId(guild_id),
+            serenity::model::prelude::ChannelId(channel_id),
+            channel_name,
+            user.name,
+            intro,
+        ))
+        .await
+        .map_err(|_| Error::BotUnavailable)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
 pub(crate) async fn update_guild_permissions(
     State(state): State<ApiState>,
     Path(guild_id): Path<u64>,
     user: db::User,
     mut form_data: Multipart,
 ) -> Result<HeaderMap, Error> {
-    let db = state.db.lock().await;
+    let db = &state.db;
 
     let this_user_permissions = db
         .get_user_permissions(&user.name, guild_id)
@@ -460,46 +1508,911 @@ pub(crate) async fn update_guild_permissions(
         return Err(Error::InvalidPermission);
     }
 
+    let current_permissions: HashMap<String, auth::Permissions> =
+        db.get_all_user_permissions(guild_id)?.into_iter().collect();
+
+    // Unchecked boxes aren't sent at all, so every permission with a checkbox starts out
+    // revoked and anything the form doesn't show is left alone
     let mut users_to_update: HashMap<String, auth::Permissions> = db
         .get_guild_users(guild_id)?
         .into_iter()
-        .map(|user| (user, Default::default()))
+        .map(|user| {
+            let mut permissions = current_permissions.get(&user).copied().unwrap_or_default();
+            for perm in enum_iterator::all::<auth::Permission>() {
+                if perm != auth::Permission::Moderator {
+                    permissions.remove(perm.into());
+                }
+            }
+
+            (user, permissions)
+        })
         .collect();
 
     while let Ok(Some(field)) = form_data.next_field().await {
-        let Some(field_name) = field.name() else {
+        let Some(field_name) = field.name().map(str::to_string) else {
             continue;
         };
 
         if let Some((username, permission)) = field_name.split_once('#') {
             let permission = auth::Permission::from_str(permission)?;
 
-            let username = username.to_string();
+            // There's no checkbox for it, so only a hand crafted form would try this
+            if permission == auth::Permission::Moderator {
+                warn!(user = %user.name, %guild_id, "tried to grant moderator through the permissions form");
+                return Err(Error::InvalidPermission);
+            }
+
             if field.text().await.map_err(|_| Error::InvalidRequest)? == "on" {
-                users_to_update
-                    .entry(username)
-                    .and_modify(|value| {
-                        value.add(permission);
-                    })
-                    .or_insert_with(|| {
-                        let mut perm = auth::Permissions::default();
-                        perm.add(permission);
-                        perm
-                    });
+                let Some(permissions) = users_to_update.get_mut(username) else {
+                    warn!(%username, %guild_id, "ignoring permissions for user not in guild");
+                    continue;
+                };
+
+                permissions.insert(permission.into());
             }
         }
     }
 
-    for (user, permissions) in users_to_update {
-        let user_permissions = db.get_user_permissions(&user, guild_id).unwrap_or_default();
+    for (username, permissions) in users_to_update {
+        // Moderator checkboxes are disabled so they never get submitted, updating a moderator
+        // here would strip everything they have, including being a moderator
+        if current_permissions
+            .get(&username)
+            .is_some_and(|current| current.can(auth::Permission::Moderator))
+        {
+            continue;
+        }
+
+        db.insert_user_permission(&username, guild_id, permissions)?;
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert("HX-Refresh", HeaderValue::from_static("true"));
 
-        if !user_permissions.can(auth::Permission::Moderator) {
-            db.insert_user_permission(&user, guild_id, permissions)?;
+    Ok(headers)
+}
+
+pub(crate) async fn update_guild_role_permissions(
+    State(state): State<ApiState>,
+    Path(guild_id): Path<u64>,
+    user: db::User,
+    mut form_data: Multipart,
+) -> Result<HeaderMap, Error> {
+    let db = &state.db;
+
+    let user_permissions = db
+        .get_user_permissions(&user.name, guild_id)
+        .unwrap_or_default();
+
+    if !user_permissions.can(auth::Permission::Moderator) {
+        return Err(Error::InvalidPermission);
+    }
+
+    // Every existing role gets rewritten, unchecked boxes aren't sent at all
+    let mut roles_to_update: HashMap<u64, auth::Permissions> = db
+        .get_guild_role_permissions(guild_id)?
+        .into_iter()
+        .map(|(role_id, _)| (role_id, Default::default()))
+        .collect();
+    let mut new_role_id = None;
+    let mut new_role_permissions = auth::Permissions::default();
+
+    while let Ok(Some(field)) = form_data.next_field().await {
+        let Some(field_name) = field.name().map(str::to_string) else {
+            continue;
+        };
+        let value = field.text().await.map_err(|_| Error::InvalidRequest)?;
+
+        if field_name == "new_role_id" {
+            if !value.trim().is_empty() {
+                new_role_id = Some(
+                    value
+                        .trim()
+                        .parse::<u64>()
+                        .map_err(|_| Error::InvalidRequest)?,
+                );
+            }
+            continue;
         }
+
+        if let Some((role_id, permission)) = field_name.split_once('#') {
+            if value != "on" {
+                continue;
+            }
+
+            let permission = auth::Permission::from_str(permission)?;
+            if role_id == "new" {
+                new_role_permissions.insert(permission.into());
+            } else {
+                let role_id = role_id.parse().map_err(|_| Error::InvalidRequest)?;
+                roles_to_update
+                    .entry(role_id)
+                    .or_default()
+                    .insert(permission.into());
+            }
+        }
+    }
+
+    if let Some(role_id) = new_role_id {
+        roles_to_update
+            .entry(role_id)
+            .or_default()
+            .insert(new_role_permissions);
+    }
+
+    for (role_id, permissions) in roles_to_update {
+        // A role without any permissions is the same as not being mapped
+        if permissions.is_empty() {
+            db.delete_guild_role_permission(guild_id, role_id)?;
+        } else {
+            db.insert_guild_role_permission(guild_id, role_id, permissions)?;
+        }
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert("HX-Refresh", HeaderValue::from_static("true"));
+
+    Ok(headers)
+}
+
+/// Longest a guild can make intros wait after the bot joins, in milliseconds
+pub(crate) const MAX_SOUND_DELAY_MS: u32 = 10_000;
+
+/// Highest a guild's volume ceiling goes, the same as the loudest an intro can be set to
+pub(crate) const MAX_VOLUME: u32 = 200;
+
+pub(crate) async fn update_guild_settings(
+    State(state): State<ApiState>,
+    Path(guild_id): Path<u64>,
+    user: db::User,
+    mut form_data: Multipart,
+) -> Result<HeaderMap, Error> {
+    let db = &state.db;
+
+    let user_permissions = db
+        .get_user_permissions(&user.name, guild_id)
+        .unwrap_or_default();
+
+    if !user_permissions.can(auth::Permission::Moderator) {
+        return Err(Error::InvalidPermission);
+    }
+
+    while let Ok(Some(field)) = form_data.next_field().await {
+        let Some(field_name) = field.name() else {
+            continue;
+        };
+
+        if field_name.eq_ignore_ascii_case("restrict_to_permission") {
+            let value = field.text().await.map_err(|_| Error::InvalidRequest)?;
+
+            // An empty value means everyone gets their intros played
+            let permission = if value.is_empty() {
+                None
+            } else {
+                Some(auth::Permission::from_str(&value)?)
+            };
+
+            db.set_guild_restrict_to_permission(guild_id, permission)?;
+            continue;
+        }
+
+        if field_name.eq_ignore_ascii_case("default_intro") {
+            let value = field.text().await.map_err(|_| Error::InvalidRequest)?;
+
+            // An empty value turns the default intro off
+            let intro_id = if value.is_empty() {
+                None
+            } else {
+                Some(value.parse::<i32>().map_err(|_| Error::InvalidRequest)?)
+            };
+
+            db.set_guild_default_intro(guild_id, intro_id)?;
+            continue;
+        }
+
+        if field_name.eq_ignore_ascii_case("sound_delay") {
+            let value = field.text().await.map_err(|_| Error::InvalidRequest)?;
+            let sound_delay = value
+                .trim()
+                .parse::<u32>()
+                .map_err(|_| Error::InvalidRequest)?;
+
+            if sound_delay > MAX_SOUND_DELAY_MS {
+                return Err(Error::InvalidRequest);
+            }
+
+            db.set_guild_sound_delay(guild_id, sound_delay)?;
+            continue;
+        }
+
+        if field_name.eq_ignore_ascii_case("max_volume") {
+            let value = field.text().await.map_err(|_| Error::InvalidRequest)?;
+            let max_volume = value
+                .trim()
+                .parse::<u32>()
+                .map_err(|_| Error::InvalidRequest)?;
+
+            if max_volume > MAX_VOLUME {
+                return Err(Error::InvalidRequest);
+            }
+
+            db.set_guild_max_volume(guild_id, max_volume)?;
+            continue;
+        }
+
+        if field_name.eq_ignore_ascii_case("public_library") {
+            let value = field.text().await.map_err(|_| Error::InvalidRequest)?;
+            let public_library = value.parse::<bool>().map_err(|_| Error::InvalidRequest)?;
+
+            db.set_guild_public_library(guild_id, public_library)?;
+            continue;
+        }
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert("HX-Refresh", HeaderValue::from_static("true"));
+
+    Ok(headers)
+}
+
+pub(crate) async fn update_guild_channels(
+    State(state): State<ApiState>,
+    Path(guild_id): Path<u64>,
+    user: db::User,
+    mut form_data: Multipart,
+) -> Result<HeaderMap, Error> {
+    let db = &state.db;
+
+    let user_permissions = db
+        .get_user_permissions(&user.name, guild_id)
+        .unwrap_or_default();
+
+    if !user_permissions.can(auth::Permission::Moderator) {
+        return Err(Error::InvalidPermission);
+    }
+
+    // Unchecked boxes aren't sent at all, so only the checked channels show up
+    let mut enabled_channels = Vec::new();
+    while let Ok(Some(field)) = form_data.next_field().await {
+        let Some(channel_id) = field.name().and_then(|name| name.parse::<u64>().ok()) else {
+            continue;
+        };
+
+        if field.text().await.map_err(|_| Error::InvalidRequest)? == "on" {
+            enabled_channels.push(channel_id);
+        }
+    }
+
+    for (channel_id, _) in db.get_guild_channels(guild_id)? {
+        let enabled = enabled_channels.contains(&channel_id);
+        db.set_channel_intros_enabled(guild_id, channel_id, enabled)?;
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert("HX-Refresh", HeaderValue::from_static("true"));
+
+    Ok(headers)
+}
+
+/// Discord's own limit on channel names
+const MAX_CHANNEL_NAME_LEN: usize = 100;
+
+pub(crate) async fn add_guild_channel(
+    State(state): State<ApiState>,
+    Path(guild_id): Path<u64>,
+    user: db::User,
+    mut form_data: Multipart,
+) -> Result<HeaderMap, Error> {
+    let db = &state.db;
+
+    let user_permissions = db
+        .get_user_permissions(&user.name, guild_id)
+        .unwrap_or_default();
+
+    if !user_permissions.can(auth::Permission::Moderator) {
+        return Err(Error::InvalidPermission);
+    }
+
+    let mut name = None;
+    while let Ok(Some(field)) = form_data.next_field().await {
+        if field
+            .name()
+            .is_some_and(|field_name| field_name.eq_ignore_ascii_case("name"))
+        {
+            name = Some(field.text().await.map_err(|_| Error::InvalidRequest)?);
+        }
+    }
+
+    let Some(name) = name else {
+        return Err(Error::InvalidRequest);
+    };
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_CHANNEL_NAME_LEN {
+        return Err(Error::InvalidRequest);
     }
 
+    db.insert_channel(guild_id, name)?;
+
     let mut headers = HeaderMap::new();
     headers.insert("HX-Refresh", HeaderValue::from_static("true"));
 
     Ok(headers)
 }
+
+/// Live "now playing" notifications for a guild's moderators, as server sent events the dashboard
+/// swaps straight into the page
+pub(crate) async fn v2_guild_events(
+    State(state): State<ApiState>,
+    Path(guild_id): Path<u64>,
+    user: db::User,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, Error> {
+    let user_permissions = state
+        .db
+        .get_user_permissions(&user.name, guild_id)
+        .unwrap_or_default();
+
+    if !user_permissions.can(auth::Permission::Moderator) {
+        return Err(Error::InvalidPermission);
+    }
+
+    // The subscription is dropped along with the stream when the client goes away
+    let events =
+        now_playing::guild_stream(state.now_playing.subscribe(), guild_id).map(|now_playing| {
+            Ok(Event::default()
+                .event("now-playing")
+                .data(page::now_playing(&now_playing).build()))
+        });
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+pub(crate) async fn vacuum_database(
+    State(state): State<ApiState>,
+    Path(guild_id): Path<u64>,
+    user: db::User,
+) -> Result<String, Error> {
+    // Every guild shares the database, so being a moderator of one isn't enough
+    if !state.is_operator(&user.name) {
+        return Err(Error::InvalidPermission);
+    }
+
+    // Vacuuming twice in a row wouldn't reclaim anything more, it'd just lock the database longer
+    let Ok(_vacuuming) = state.vacuum_lock.clone().try_lock_owned() else {
+        return Err(Error::TooManyRequests);
+    };
+
+    info!(user = %user.name, %guild_id, "vacuuming database");
+
+    let db = state.db.clone();
+    let (elapsed, size_before, size_after) = tokio::task::spawn_blocking(move || {
+        let size_before = db.size_on_disk();
+        let start = Instant::now();
+        db.vacuum()?;
+
+        Ok::<_, db::Error>((start.elapsed(), size_before, db.size_on_disk()))
+    })
+    .await??;
+
+    info!(
+        ?elapsed,
+        size_before, size_after, "finished vacuuming database"
+    );
+
+    Ok(format!(
+        "Database vacuumed in {}ms: {size_before} bytes -> {size_after} bytes",
+        elapsed.as_millis()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn intro(id: i32) -> db::Intro {
+        db::Intro {
+            id,
+            name: format!("intro {id}"),
+            filename: format!("{id}.mp3"),
+            volume: 0,
+            duration_secs: None,
+            uploaded_by: None,
+            created_at: Utc::now().naive_utc(),
+            play_count: 0,
+            content_hash: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn form_intro_ids_skip_other_fields() {
+        use axum::{body::Body, extract::FromRequest, http::Request};
+
+        let body = [
+            "--boundary\r\n",
+            "Content-Disposition: form-data; name=\"12\"\r\n\r\non\r\n",
+            "--boundary\r\n",
+            "Content-Disposition: form-data; name=\"csrf_token\"\r\n\r\nabc123\r\n",
+            "--boundary\r\n",
+            "Content-Disposition: form-data; name=\"34\"\r\n\r\non\r\n",
+            "--boundary--\r\n",
+        ]
+        .concat();
+        let request = Request::post("/")
+            .header("content-type", "multipart/form-data; boundary=boundary")
+            .body(Body::from(body))
+            .unwrap();
+        let form_data = Multipart::from_request(request, &()).await.unwrap();
+
+        assert_eq!(form_intro_ids(form_data).await, [12, 34]);
+    }
+
+    /// A multipart upload with a single `file` field holding `contents`
+    async fn upload_form(contents: &[u8]) -> Multipart {
+        use axum::extract::FromRequest;
+
+        let mut body = b"--boundary\r\nContent-Disposition: form-data; name=\"file\"; \
+            filename=\"intro.mp3\"\r\nContent-Type: audio/mpeg\r\n\r\n"
+            .to_vec();
+        body.extend_from_slice(contents);
+        body.extend_from_slice(b"\r\n--boundary--\r\n");
+
+        let request = Request::post("/")
+            .header("content-type", "multipart/form-data; boundary=boundary")
+            .body(Body::from(body))
+            .unwrap();
+        Multipart::from_request(request, &()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn uploads_are_written_to_disk() {
+        // A couple MB, kept under axum's default body limit since this request doesn't go
+        // through the upload route's own limit
+        let contents = (0..(3 * 1024 * 1024 / 2))
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let dir = media::TestDir::new("upload");
+        let temp_file = media::TempFile::new(dir.join("upload"));
+
+        let mut form_data = upload_form(&contents).await;
+        let field = form_data.next_field().await.unwrap().unwrap();
+        write_upload(field, temp_file.path(), contents.len())
+            .await
+            .unwrap();
+
+        assert!(std::fs::read(temp_file.path()).unwrap() == contents);
+    }
+
+    #[tokio::test]
+    async fn uploads_over_the_limit_are_rejected() {
+        let contents = vec![7; 64 * 1024];
+        let dir = media::TestDir::new("upload-large");
+        let temp_file = media::TempFile::new(dir.join("upload"));
+
+        let mut form_data = upload_form(&contents).await;
+        let field = form_data.next_field().await.unwrap().unwrap();
+        let err = write_upload(field, temp_file.path(), contents.len() - 1)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, Error::UploadTooLarge), "{err:?}");
+    }
+
+    #[test]
+    fn intro_limit_counts_new_picks_only() {
+        const LIMIT: usize = 5;
+        let current = (1..=4).map(intro).collect::<Vec<_>>();
+
+        // The Nth intro fits, the N+1th doesn't
+        assert_eq!(intros_after_adding(&current, &[5]), LIMIT);
+        assert!(intros_after_adding(&current, &[5, 6]) > LIMIT);
+
+        let current = (1..=5).map(intro).collect::<Vec<_>>();
+        assert!(intros_after_adding(&current, &[6]) > LIMIT);
+
+        // Picking ones that are already set, or the same one twice, doesn't add anything
+        assert_eq!(intros_after_adding(&current, &[1, 2, 2]), LIMIT);
+        assert_eq!(intros_after_adding(&[], &[7, 7]), 1);
+    }
+
+    #[tokio::test]
+    async fn only_operators_vacuum() {
+        let (mut state, user, _dir) = crate::settings::test_state("vacuum");
+        state
+            .db
+            .insert_user_permission(&user.name, 1, auth::Permissions::all())
+            .unwrap();
+
+        // Moderating a guild isn't enough
+        let err = vacuum_database(State(state.clone()), Path(1), user.clone())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidPermission), "{err:?}");
+
+        state.operators = vec![user.name.clone()];
+        let report = vacuum_database(State(state.clone()), Path(1), user.clone())
+            .await
+            .unwrap();
+        assert!(report.starts_with("Database vacuumed in"), "{report}");
+
+        // Only one runs at a time
+        let _vacuuming = state.vacuum_lock.clone().try_lock_owned().unwrap();
+        let err = vacuum_database(State(state), Path(1), user)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::TooManyRequests), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn guild_intros_listed_as_json() {
+        let (state, user, _dir) = crate::settings::test_state("list-intros");
+        state
+            .db
+            .insert_intro("hello", 20, 1, "hello.mp3", &user.name, None, Some(3), &[])
+            .unwrap();
+
+        let Json(intros) =
+            v2_list_guild_intros(State(state.clone()), Path(1), ApiUser(user.clone()))
+                .await
+                .unwrap();
+        let json = serde_json::to_value(intros).unwrap();
+
+        let intro = &json.as_array().unwrap()[0];
+        let mut keys = intro.as_object().unwrap().keys().collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(
+            keys,
+            [
+                "content_hash",
+                "created_at",
+                "duration_secs",
+                "filename",
+                "id",
+                "name",
+                "play_count",
+                "uploaded_by",
+                "volume",
+            ]
+        );
+        assert_eq!(intro["name"], "hello");
+        assert_eq!(intro["volume"], 20);
+        assert_eq!(intro["duration_secs"], 3);
+        assert_eq!(intro["uploaded_by"], "bob");
+        assert_eq!(intro["play_count"], 0);
+        assert!(intro["content_hash"].is_null());
+
+        // Not a member of this guild
+        let result = v2_list_guild_intros(State(state), Path(2), ApiUser(user)).await;
+        assert!(matches!(result, Err(Error::NoGuildFound)));
+    }
+
+    #[tokio::test]
+    async fn identical_sounds_share_a_file() {
+        let (mut state, user, sounds_dir) = crate::settings::test_state("dedupe");
+        state.media.dedupe_uploads = true;
+
+        let mut ids = Vec::new();
+        for (filename, name) in [("first.mp3", "first"), ("second.mp3", "second")] {
+            let dest_file = media::TempFile::new(media::sound_path(&state.media, filename));
+            std::fs::write(dest_file.path(), "the same sound").unwrap();
+            let intro_id = insert_sound_intro(
+                &state.db,
+                &state.media,
+                1,
+                dest_file,
+                filename.to_string(),
+                name,
+                &user.name,
+                None,
+                &[],
+            )
+            .await
+            .unwrap();
+            ids.push(intro_id);
+        }
+
+        let intros = state.db.get_guild_intros(1).unwrap();
+        let filename = |id| &intros.iter().find(|intro| intro.id == id).unwrap().filename;
+        assert_eq!(filename(ids[0]), "first.mp3");
+        assert_eq!(filename(ids[1]), "first.mp3");
+        // Only the file both intros point at is kept
+        assert!(std::path::Path::new(&sounds_dir.join("first.mp3")).exists());
+        assert!(!std::path::Path::new(&sounds_dir.join("second.mp3")).exists());
+    }
+
+    #[tokio::test]
+    async fn health_fails_without_a_database() {
+        let (mut state, _, _dir) = crate::settings::test_state("health");
+        assert_eq!(health(State(state.clone())).await.0, StatusCode::OK);
+
+        state.db = db::Database::unreachable();
+        assert_eq!(
+            health(State(state)).await,
+            (StatusCode::SERVICE_UNAVAILABLE, "database unavailable")
+        );
+    }
+
+    #[tokio::test]
+    async fn intros_deleted_with_csrf_token() {
+        use axum::extract::FromRequestParts;
+
+        let (state, user, _dir) = crate::settings::test_state("delete-intro");
+        state
+            .db
+            .upsert_guild_channels(1, &[(100, "general".to_string())])
+            .unwrap();
+        let intro_id = state
+            .db
+            .insert_intro("hello", 0, 1, "hello.mp3", &user.name, None, None, &[])
+            .unwrap();
+        state
+            .db
+            .insert_user_intro(&user.name, 1, 100, intro_id, db::IntroKind::Intro)
+            .unwrap();
+
+        let delete = |csrf_token: Option<String>| {
+            let mut request = Request::delete(format!("/v2/intros/remove/1/general/{intro_id}"))
+                .header("cookie", "access_token=api_key");
+            if let Some(csrf_token) = csrf_token {
+                request = request.header(crate::settings::CSRF_HEADER, csrf_token);
+            }
+            let (mut parts, _) = request.body(()).unwrap().into_parts();
+            let state = state.clone();
+
+            async move {
+                let user = match db::User::from_request_parts(&mut parts, &state).await {
+                    Ok(user) => user,
+                    Err(rejection) => return rejection.into_response().status(),
+                };
+                v2_delete_intro_from_user(State(state), Path((1, 100, intro_id)), user)
+                    .await
+                    .into_response()
+                    .status()
+            }
+        };
+
+        assert_eq!(delete(None).await, StatusCode::FORBIDDEN);
+        assert_eq!(
+            state
+                .db
+                .get_user_channel_intros(&user.name, 1, 100, db::IntroKind::Intro)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let csrf_token = crate::settings::csrf_token(&user);
+        assert_eq!(delete(Some(csrf_token)).await, StatusCode::OK);
+        assert!(state
+            .db
+            .get_user_channel_intros(&user.name, 1, 100, db::IntroKind::Intro)
+            .unwrap()
+            .is_empty());
+    }
+
+    /// A multipart form with a text field for each of `fields`
+    async fn text_form(fields: &[(&str, &str)]) -> Multipart {
+        use axum::extract::FromRequest;
+
+        let mut body = fields
+            .iter()
+            .map(|(name, value)| {
+                format!(
+                    "--boundary\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n"
+                )
+            })
+            .collect::<String>();
+        body.push_str("--boundary--\r\n");
+
+        let request = Request::post("/")
+            .header("content-type", "multipart/form-data; boundary=boundary")
+            .body(Body::from(body))
+            .unwrap();
+        Multipart::from_request(request, &()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn permissions_updated_per_user() {
+        let (state, user, _dir) = crate::settings::test_state("update-permissions");
+        let expires_at = Utc::now().naive_utc() + Duration::days(1);
+        for name in ["alice", "carol"] {
+            state
+                .db
+                .insert_user(name, name, expires_at, "token", expires_at, "refresh")
+                .unwrap();
+            state.db.insert_user_guild(name, 1).unwrap();
+        }
+        state
+            .db
+            .insert_user_permission(&user.name, 1, auth::Permissions::MODERATOR)
+            .unwrap();
+        state
+            .db
+            .insert_user_permission("alice", 1, auth::Permissions::SOUNDBOARD)
+            .unwrap();
+
+        // Both are allowed to upload, only alice can delete, and alice's soundboard was unchecked
+        let form_data = text_form(&[
+            ("alice#Upload Sounds", "on"),
+            ("alice#Delete Sounds", "on"),
+            ("carol#Upload Sounds", "on"),
+        ])
+        .await;
+        update_guild_permissions(State(state.clone()), Path(1), user.clone(), form_data)
+            .await
+            .unwrap();
+
+        let permissions = |name| state.db.get_user_permissions(name, 1).unwrap();
+        assert_eq!(
+            permissions("alice"),
+            auth::Permissions::UPLOAD_SOUNDS | auth::Permissions::DELETE_SOUNDS
+        );
+        assert_eq!(permissions("carol"), auth::Permissions::UPLOAD_SOUNDS);
+        assert_eq!(permissions(&user.name), auth::Permissions::MODERATOR);
+    }
+
+    #[tokio::test]
+    async fn unchecked_permissions_are_revoked() {
+        let (state, user, _dir) = crate::settings::test_state("revoke-permissions");
+        let expires_at = Utc::now().naive_utc() + Duration::days(1);
+        state
+            .db
+            .insert_user("alice", "alice", expires_at, "token", expires_at, "refresh")
+            .unwrap();
+        state.db.insert_user_guild("alice", 1).unwrap();
+        state
+            .db
+            .insert_user_permission(&user.name, 1, auth::Permissions::MODERATOR)
+            .unwrap();
+
+        let update = |fields: &'static [(&'static str, &'static str)]| {
+            let state = state.clone();
+            let user = user.clone();
+            async move {
+                let form_data = text_form(fields).await;
+                update_guild_permissions(State(state), Path(1), user, form_data)
+                    .await
+                    .unwrap();
+            }
+        };
+        let can_upload = || {
+            state
+                .db
+                .get_user_permissions("alice", 1)
+                .unwrap()
+                .can(auth::Permission::UploadSounds)
+        };
+
+        update(&[("alice#Upload Sounds", "on")]).await;
+        assert!(can_upload());
+
+        update(&[]).await;
+        assert!(!can_upload());
+    }
+
+    #[test]
+    fn intro_names_trimmed_and_limited() {
+        assert_eq!(intro_name("  hello there \n").unwrap(), "hello there");
+
+        assert!(matches!(intro_name("   "), Err(Error::InvalidRequest)));
+        let longest = "é".repeat(MAX_INTRO_NAME_LEN);
+        assert_eq!(intro_name(&longest).unwrap(), longest);
+        assert!(matches!(
+            intro_name(&format!("{longest}a")),
+            Err(Error::InvalidRequest)
+        ));
+    }
+
+    #[tokio::test]
+    async fn ytdl_intro_names_validated() {
+        let (state, user, _dir) = crate::settings::test_state("ytdl-name");
+        for name in [" ".to_string(), "a".repeat(MAX_INTRO_NAME_LEN + 1)] {
+            let form =
+                text_form(&[("url", "https://youtube.com/watch?v=abc"), ("name", &name)]).await;
+            let result =
+                v2_add_guild_intro(State(state.clone()), Path(1), user.clone(), form).await;
+            assert!(matches!(result, Err(Error::InvalidRequest)));
+        }
+    }
+
+    #[tokio::test]
+    async fn search_results_add_to_the_searched_channel() {
+        let (state, user, _dir) = crate::settings::test_state("search-channel");
+        state
+            .db
+            .insert_intro("hello", 0, 1, "hello.mp3", &user.name, None, None, &[])
+            .unwrap();
+        let search = |channel: &str| {
+            let params = HashMap::from([
+                ("channel".to_string(), channel.to_string()),
+                ("q".to_string(), "hel".to_string()),
+            ]);
+            v2_search_guild_intros(State(state.clone()), Path(1), Query(params), user.clone())
+        };
+
+        let Ok(Html(html)) = search("101").await else {
+            panic!("search failed");
+        };
+        assert!(html.contains("/v2/intros/add/1/101"), "{html}");
+        assert!(html.contains("hello"), "{html}");
+
+        // Channels go by their discord id, not their name
+        assert!(search("general chat").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn volume_only_set_on_picked_intros() {
+        let (state, user, _dir) = crate::settings::test_state("volume-unpicked");
+        state
+            .db
+            .upsert_guild_channels(1, &[(100, "general".to_string())])
+            .unwrap();
+        let intro_id = state
+            .db
+            .insert_intro("hello", 0, 1, "hello.mp3", &user.name, None, None, &[])
+            .unwrap();
+
+        let response = v2_set_user_intro_volume(
+            State(state.clone()),
+            Path((1, 100, intro_id)),
+            user.clone(),
+            text_form(&[("volume", "150")]).await,
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(state
+            .db
+            .get_user_channel_picks(&user.name, 1, 100, db::IntroKind::Intro)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn same_named_channels_picked_and_toggled_separately() {
+        let (state, user, _dir) = crate::settings::test_state("same-named-channels");
+        state
+            .db
+            .upsert_guild_channels(
+                1,
+                &[(100, "general".to_string()), (101, "general".to_string())],
+            )
+            .unwrap();
+        state
+            .db
+            .insert_user_permission(&user.name, 1, auth::Permissions::all())
+            .unwrap();
+        let intro_id = state
+            .db
+            .insert_intro("hello", 0, 1, "hello.mp3", &user.name, None, None, &[])
+            .unwrap();
+
+        let intro_field = intro_id.to_string();
+        let result = v2_add_intro_to_user(
+            State(state.clone()),
+            Path((1, 101)),
+            user.clone(),
+            text_form(&[(&intro_field, "on")]).await,
+        )
+        .await;
+        assert!(result.is_ok());
+        let picks = |channel_id| {
+            state
+                .db
+                .get_user_channel_picks(&user.name, 1, channel_id, db::IntroKind::Intro)
+                .unwrap()
+                .len()
+        };
+        assert_eq!(picks(100), 0);
+        assert_eq!(picks(101), 1);
+
+        update_guild_channels(
+            State(state.clone()),
+            Path(1),
+            user.clone(),
+            text_form(&[("100", "on")]).await,
+        )
+        .await
+        .unwrap();
+        assert!(state.db.channel_intros_enabled(1, 100).unwrap());
+        assert!(!state.db.channel_intros_enabled(1, 101).unwrap());
+    }
+}
@@ -1,56 +1,191 @@
-use std::sync::Arc;
+use std::{io, path::Path, sync::Arc};
 
 use crate::{
     auth,
     db::{self, Database},
+    jobs::Jobs,
+    now_playing::{self, NowPlaying},
+    rate_limit::RateLimiter,
+    routes, HandlerMessage,
+};
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{request::Parts, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+    Json,
 };
-use axum::{async_trait, extract::FromRequestParts, http::request::Parts, response::Redirect};
 use axum_extra::extract::CookieJar;
-use chrono::Utc;
+use chrono::{Duration, Utc};
+use metrics_exporter_prometheus::PrometheusHandle;
 use serde::{Deserialize, Serialize};
 use serenity::prelude::TypeMapKey;
+use sha2::{Digest, Sha256};
+use tokio::sync::{broadcast, mpsc, Semaphore};
 use tracing::error;
 
 // TODO: make this is wrapped type so cloning isn't happening
 #[derive(Clone)]
 pub(crate) struct ApiState {
-    pub db: Arc<tokio::sync::Mutex<Database>>,
+    pub db: Database,
     pub secrets: auth::DiscordSecret,
     pub origin: String,
+    pub bot_tx: mpsc::Sender<HandlerMessage>,
+    pub media: MediaSettings,
+    pub ytdl_limiter: Arc<RateLimiter>,
+    pub ytdl_permits: Arc<Semaphore>,
+    pub jobs: Jobs,
+    pub metrics: PrometheusHandle,
+    pub max_intros_per_channel: usize,
+    pub max_upload_bytes: usize,
+    pub now_playing: broadcast::Sender<NowPlaying>,
+    pub operators: Vec<String>,
+    /// Held while the database is being vacuumed, so only one runs at a time
+    pub vacuum_lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl ApiState {
+    /// Whether `username` runs this instance, and so can do things that affect every guild
+    pub(crate) fn is_operator(&self, username: &str) -> bool {
+        self.operators.iter().any(|operator| operator == username)
+    }
+}
+
+/// Looks the user up from their `access_token` cookie, refreshing their discord token if it's
+/// expired. `None` means they need to log in again.
+async fn authenticate(headers: &HeaderMap, state: &ApiState) -> Option<db::User> {
+    let jar = CookieJar::from_headers(headers);
+    let token = jar.get("access_token")?;
+
+    let now = Utc::now().naive_utc();
+    match state.db.get_valid_user_from_api_key(token.value(), now) {
+        Ok(Some(user)) if user.discord_token_expires_at < now => {
+            refresh_user_discord_token(state, user)
+                .await
+                .map_err(|err| {
+                    error!(?err, "failed to refresh discord token");
+                })
+                .ok()
+        }
+        Ok(user) => user,
+        Err(err) => {
+            error!(?err, "failed to authenticate user");
+
+            None
+        }
+    }
+}
+
+fn login_redirect(origin: &str) -> Redirect {
+    Redirect::to(&format!("{}/login", origin))
+}
+
+fn unauthorized() -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::UNAUTHORIZED,
+        Json(serde_json::json!({ "error": "not logged in" })),
+    )
+}
+
+/// Header htmx sends the csrf token back in, see [`csrf_token`]
+pub(crate) const CSRF_HEADER: &str = "x-csrf-token";
+
+/// Token pages hand out for their htmx requests to send back, so another site can't make a logged
+/// in user's browser change things. It's derived from the session's api key, so it changes with
+/// every login without having to be stored anywhere.
+pub(crate) fn csrf_token(user: &db::User) -> String {
+    format!("{:x}", Sha256::digest(format!("csrf:{}", user.api_key)))
+}
+
+fn has_csrf_token(headers: &HeaderMap, user: &db::User) -> bool {
+    headers
+        .get(CSRF_HEADER)
+        .and_then(|token| token.to_str().ok())
+        .is_some_and(|token| token == csrf_token(user))
+}
+
+pub(crate) enum UserRejection {
+    /// Not logged in, or the session expired
+    Login(Redirect),
+    /// Logged in, but a request that changes something didn't come with the session's csrf token
+    Csrf,
+}
+
+impl IntoResponse for UserRejection {
+    fn into_response(self) -> Response {
+        match self {
+            Self::Login(redirect) => redirect.into_response(),
+            Self::Csrf => (StatusCode::FORBIDDEN, "missing or invalid csrf token").into_response(),
+        }
+    }
 }
 
 #[async_trait]
 impl FromRequestParts<ApiState> for db::User {
-    type Rejection = Redirect;
+    type Rejection = UserRejection;
 
     async fn from_request_parts(
-        Parts { headers, .. }: &mut Parts,
+        Parts {
+            method, headers, ..
+        }: &mut Parts,
         state: &ApiState,
     ) -> Result<Self, Self::Rejection> {
-        let jar = CookieJar::from_headers(&headers);
-
-        if let Some(token) = jar.get("access_token") {
-            match state.db.lock().await.get_user_from_api_key(token.value()) {
-                Ok(user) => {
-                    let now = Utc::now().naive_utc();
-                    if user.api_key_expires_at < now || user.discord_token_expires_at < now {
-                        Err(Redirect::to(&format!("{}/login", state.origin)))
-                    } else {
-                        Ok(user)
-                    }
-                }
-                Err(err) => {
-                    error!(?err, "failed to authenticate user");
-
-                    Err(Redirect::to(&format!("{}/login", state.origin)))
-                }
-            }
-        } else {
-            Err(Redirect::to(&format!("{}/login", state.origin)))
+        let user = authenticate(headers, state)
+            .await
+            .ok_or_else(|| UserRejection::Login(login_redirect(&state.origin)))?;
+
+        if !method.is_safe() && !has_csrf_token(headers, &user) {
+            return Err(UserRejection::Csrf);
         }
+
+        Ok(user)
     }
 }
 
+/// Same as extracting a [`db::User`], but for endpoints used by programs rather than browsers, so
+/// failing to authenticate is a 401 instead of a redirect to the login page
+pub(crate) struct ApiUser(pub(crate) db::User);
+
+#[async_trait]
+impl FromRequestParts<ApiState> for ApiUser {
+    type Rejection = (StatusCode, Json<serde_json::Value>);
+
+    async fn from_request_parts(
+        Parts { headers, .. }: &mut Parts,
+        state: &ApiState,
+    ) -> Result<Self, Self::Rejection> {
+        authenticate(headers, state)
+            .await
+            .map(ApiUser)
+            .ok_or_else(unauthorized)
+    }
+}
+
+async fn refresh_user_discord_token(
+    state: &ApiState,
+    mut user: db::User,
+) -> Result<db::User, routes::Error> {
+    let Some(refresh_token) = &user.discord_refresh_token else {
+        return Err(routes::Error::Auth("no refresh token stored".to_string()));
+    };
+
+    let auth = auth::refresh_discord_token(&state.secrets, refresh_token).await?;
+    let expires_at = Utc::now().naive_utc() + Duration::seconds(auth.expires_in as i64);
+
+    state.db.update_user_discord_token(
+        &user.name,
+        &auth.access_token,
+        expires_at,
+        &auth.refresh_token,
+    )?;
+
+    user.discord_token = auth.access_token;
+    user.discord_token_expires_at = expires_at;
+    user.discord_refresh_token = Some(auth.refresh_token);
+
+    Ok(user)
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct Settings {
@@ -58,7 +193,432 @@ pub(crate) struct Settings {
     pub(crate) run_api: bool,
     #[serde(default)]
     pub(crate) run_bot: bool,
+    /// How long the bot stays in a voice channel after its queue empties, 0 leaves right away
+    #[serde(default)]
+    pub(crate) idle_timeout_secs: u64,
+    #[serde(default)]
+    pub(crate) sweeper: SweeperSettings,
+    #[serde(default)]
+    pub(crate) media: MediaSettings,
+    /// Largest request body accepted by the sound upload endpoint, in bytes
+    #[serde(default = "default_max_upload_bytes")]
+    pub(crate) max_upload_bytes: usize,
+    /// Origins allowed to make cross origin requests to the api, only `APP_ORIGIN` when empty
+    #[serde(default)]
+    pub(crate) allowed_origins: Vec<String>,
+    /// Most intros (and separately outros) a user can pick for a single channel
+    #[serde(default = "default_max_intros_per_channel")]
+    pub(crate) max_intros_per_channel: usize,
+    /// Discord usernames allowed to run maintenance on the whole database, nobody when empty
+    #[serde(default)]
+    pub(crate) operators: Vec<String>,
+}
+
+/// Why `settings.json` couldn't be loaded
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum ConfigError {
+    #[error("{0} doesn't exist")]
+    Missing(String),
+    #[error("couldn't read {0}: {1}")]
+    Read(String, io::Error),
+    #[error("couldn't parse {0}: {1}")]
+    Parse(String, serde_json::Error),
+    #[error("invalid {0}: {1}")]
+    Invalid(&'static str, String),
+}
+
+impl Settings {
+    /// Reads and validates the settings file at `path`
+    pub(crate) fn load(path: impl AsRef<Path>) -> Result<Settings, ConfigError> {
+        let path = path.as_ref();
+        let display = path.display().to_string();
+
+        let contents = std::fs::read_to_string(path).map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => ConfigError::Missing(display.clone()),
+            _ => ConfigError::Read(display.clone(), err),
+        })?;
+        let settings = serde_json::from_str::<Settings>(&contents)
+            .map_err(|err| ConfigError::Parse(display, err))?;
+
+        settings.validate()?;
+
+        Ok(settings)
+    }
+
+    /// Catches values that would parse fine but break something once the bot is running
+    fn validate(&self) -> Result<(), ConfigError> {
+        for origin in &self.allowed_origins {
+            parse_origin(origin).map_err(|err| ConfigError::Invalid("allowedOrigins", err))?;
+        }
+
+        if self.max_intros_per_channel == 0 {
+            return Err(ConfigError::Invalid(
+                "maxIntrosPerChannel",
+                "must be more than 0".to_string(),
+            ));
+        }
+
+        if self.max_upload_bytes == 0 {
+            return Err(ConfigError::Invalid(
+                "maxUploadBytes",
+                "must be more than 0".to_string(),
+            ));
+        }
+
+        if self.media.max_duration_secs == 0 {
+            return Err(ConfigError::Invalid(
+                "media.maxDurationSecs",
+                "must be more than 0".to_string(),
+            ));
+        }
+
+        // The range ffmpeg's loudnorm filter accepts
+        if !(-70.0..=-5.0).contains(&self.media.loudness_target) {
+            return Err(ConfigError::Invalid(
+                "media.loudnessTarget",
+                format!("{} isn't between -70 and -5", self.media.loudness_target),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// `allowed_origins` as they'd appear in an `Origin` header, falling back to `app_origin`
+    pub(crate) fn cors_origins(&self, app_origin: &str) -> Result<Vec<HeaderValue>, String> {
+        let origins = if self.allowed_origins.is_empty() {
+            vec![app_origin.to_string()]
+        } else {
+            self.allowed_origins.clone()
+        };
+
+        origins.iter().map(|origin| parse_origin(origin)).collect()
+    }
 }
+
+fn parse_origin(origin: &str) -> Result<HeaderValue, String> {
+    let url = reqwest::Url::parse(origin.trim())
+        .map_err(|err| format!("invalid origin '{origin}': {err}"))?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(format!("invalid origin '{origin}': must be http or https"));
+    }
+
+    // Browsers send just the scheme, host and port, so drop any path or trailing slash
+    HeaderValue::from_str(&url.origin().ascii_serialization())
+        .map_err(|err| format!("invalid origin '{origin}': {err}"))
+}
+
+fn default_max_upload_bytes() -> usize {
+    10 * 1024 * 1024
+}
+
+fn default_max_intros_per_channel() -> usize {
+    5
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct SweeperSettings {
+    /// How often the transient directories are swept
+    pub(crate) interval_secs: u64,
+    /// How long files in the upload temp directory are kept around
+    pub(crate) temp_retention_secs: u64,
+    /// How long yt-dlp's partial downloads are kept around, in case one is still running
+    pub(crate) partial_download_retention_secs: u64,
+}
+
+impl Default for SweeperSettings {
+    fn default() -> Self {
+        Self {
+            interval_secs: 60 * 60,
+            temp_retention_secs: 24 * 60 * 60,
+            partial_download_retention_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct MediaSettings {
+    /// Where sound files are kept, uploads in progress go in a `temp` directory inside of it
+    pub(crate) sounds_dir: String,
+    /// Longest an intro is allowed to be, anything past this is trimmed off rather than rejected
+    pub(crate) max_duration_secs: u32,
+    /// Integrated loudness (in LUFS) that sounds get leveled to
+    pub(crate) loudness_target: f32,
+    /// How long a user has to wait between adding intros from a url
+    pub(crate) ytdl_cooldown_secs: u64,
+    /// How many yt-dlp downloads can run at once across every guild
+    pub(crate) max_concurrent_ytdl: usize,
+    /// Domains intros can be downloaded from, any domain is allowed when this is empty
+    pub(crate) ytdl_allowed_domains: Vec<String>,
+    /// What new sounds get stored as, existing sounds are left as they are
+    pub(crate) audio_format: AudioFormat,
+    /// Whether a new sound that's identical to one of the guild's existing intros reuses that
+    /// intro's file instead of keeping a second copy
+    pub(crate) dedupe_uploads: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum AudioFormat {
+    #[default]
+    Mp3,
+    /// Opus in an ogg container, which is what discord ends up playing anyway
+    Opus,
+}
+
+impl AudioFormat {
+    /// Extension sound files of this format are stored with, ffmpeg picks the container from it
+    pub(crate) fn extension(&self) -> &'static str {
+        match self {
+            AudioFormat::Mp3 => "mp3",
+            AudioFormat::Opus => "opus",
+        }
+    }
+}
+
+impl Default for MediaSettings {
+    fn default() -> Self {
+        Self {
+            sounds_dir: "./sounds".to_string(),
+            max_duration_secs: 10,
+            loudness_target: -16.0,
+            ytdl_cooldown_secs: 30,
+            max_concurrent_ytdl: 2,
+            ytdl_allowed_domains: Vec::new(),
+            audio_format: AudioFormat::default(),
+            dedupe_uploads: false,
+        }
+    }
+}
+
 impl TypeMapKey for Settings {
     type Value = Arc<Settings>;
 }
+
+/// State backed by its own database file, with guild 1 and the user `bob` in it. The database
+/// and sounds live in the returned directory, which has to be kept around until the test is done.
+#[cfg(test)]
+pub(crate) fn test_state(name: &str) -> (ApiState, db::User, crate::media::TestDir) {
+    let dir = crate::media::TestDir::new(name);
+    let db = Database::new(dir.join("memejoin.sqlite")).expect("failed to open database");
+
+    let expires_at = Utc::now().naive_utc() + Duration::days(1);
+    db.upsert_guild(1, "guild").unwrap();
+    db.insert_user("bob", "api_key", expires_at, "token", expires_at, "refresh")
+        .unwrap();
+    db.insert_user_guild("bob", 1).unwrap();
+    let user = db.get_user("bob").unwrap().unwrap();
+
+    let (bot_tx, _) = mpsc::channel(1);
+    let state = ApiState {
+        db,
+        secrets: auth::DiscordSecret {
+            client_id: "client_id".to_string(),
+            client_secret: "client_secret".to_string(),
+        },
+        origin: "https://memejoin.example".to_string(),
+        bot_tx,
+        media: MediaSettings {
+            sounds_dir: dir.path().to_string(),
+            ..Default::default()
+        },
+        ytdl_limiter: Arc::new(RateLimiter::new(std::time::Duration::from_secs(30))),
+        ytdl_permits: Arc::new(Semaphore::new(1)),
+        jobs: Default::default(),
+        metrics: metrics_exporter_prometheus::PrometheusBuilder::new()
+            .build_recorder()
+            .handle(),
+        max_intros_per_channel: 5,
+        max_upload_bytes: default_max_upload_bytes(),
+        now_playing: broadcast::channel(now_playing::CAPACITY).0,
+        operators: Vec::new(),
+        vacuum_lock: Default::default(),
+    };
+
+    (state, user, dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(allowed_origins: &[&str]) -> Settings {
+        let mut settings: Settings = serde_json::from_str("{}").unwrap();
+        settings.allowed_origins = allowed_origins.iter().map(|o| o.to_string()).collect();
+        settings
+    }
+
+    #[test]
+    fn cors_origins_fall_back_to_app_origin() {
+        let origins = settings(&[])
+            .cors_origins("https://memejoin.example")
+            .unwrap();
+        assert_eq!(
+            origins,
+            vec![HeaderValue::from_static("https://memejoin.example")]
+        );
+    }
+
+    #[test]
+    fn cors_origins_from_list() {
+        let origins = settings(&["https://admin.example/", "http://localhost:8080"])
+            .cors_origins("https://memejoin.example")
+            .unwrap();
+        assert_eq!(
+            origins,
+            vec![
+                HeaderValue::from_static("https://admin.example"),
+                HeaderValue::from_static("http://localhost:8080"),
+            ]
+        );
+
+        // Still has to be something tower-http can build a layer from
+        let _ = tower_http::cors::CorsLayer::new().allow_origin(origins);
+    }
+
+    #[test]
+    fn page_auth_rejection_redirects_to_login() {
+        use axum::response::IntoResponse;
+
+        let response = login_redirect("https://memejoin.example").into_response();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            response.headers()["location"],
+            "https://memejoin.example/login"
+        );
+    }
+
+    #[test]
+    fn api_auth_rejection_is_json_401() {
+        use axum::response::IntoResponse;
+
+        let (status, Json(body)) = unauthorized();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body, serde_json::json!({ "error": "not logged in" }));
+
+        let response = unauthorized().into_response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()["content-type"], "application/json");
+    }
+
+    #[test]
+    fn cors_origins_rejects_invalid() {
+        assert!(settings(&["not a url"]).cors_origins("").is_err());
+        assert!(settings(&["ftp://files.example"]).cors_origins("").is_err());
+    }
+
+    /// A settings file in its own temp directory, so tests don't step on each other
+    fn settings_file(name: &str, contents: &str) -> (crate::media::TestDir, String) {
+        let dir = crate::media::TestDir::new(&format!("settings-{name}"));
+
+        let path = dir.join("settings.json");
+        std::fs::write(&path, contents).unwrap();
+        (dir, path)
+    }
+
+    #[test]
+    fn load_missing_file() {
+        let err = Settings::load("/definitely/not/a/settings.json").unwrap_err();
+        assert!(matches!(err, ConfigError::Missing(_)), "{err:?}");
+        assert_eq!(
+            err.to_string(),
+            "/definitely/not/a/settings.json doesn't exist"
+        );
+    }
+
+    #[test]
+    fn load_bad_json() {
+        let (_dir, path) = settings_file("bad-json", r#"{ "runApi": true, "#);
+        let err = Settings::load(&path).unwrap_err();
+        assert!(matches!(err, ConfigError::Parse(..)), "{err:?}");
+
+        let (_dir, path) = settings_file("bad-type", r#"{ "runApi": "yes" }"#);
+        let err = Settings::load(&path).unwrap_err();
+        assert!(matches!(err, ConfigError::Parse(..)), "{err:?}");
+    }
+
+    #[test]
+    fn load_invalid_field() {
+        let (_dir, path) = settings_file("invalid-field", r#"{ "allowedOrigins": ["not a url"] }"#);
+        let err = Settings::load(&path).unwrap_err();
+        assert!(
+            matches!(err, ConfigError::Invalid("allowedOrigins", _)),
+            "{err:?}"
+        );
+
+        let (_dir, path) = settings_file(
+            "invalid-loudness",
+            r#"{ "media": { "loudnessTarget": 3 } }"#,
+        );
+        let err = Settings::load(&path).unwrap_err();
+        assert!(
+            matches!(err, ConfigError::Invalid("media.loudnessTarget", _)),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn load_valid() {
+        let (_dir, path) = settings_file("valid", r#"{ "runApi": true, "idleTimeoutSecs": 30 }"#);
+        let settings = Settings::load(&path).unwrap();
+        assert!(settings.run_api);
+        assert!(!settings.run_bot);
+        assert_eq!(settings.idle_timeout_secs, 30);
+    }
+
+    async fn extract_user(
+        state: &ApiState,
+        method: &str,
+        csrf_token: Option<&str>,
+    ) -> Result<db::User, StatusCode> {
+        let mut request = axum::http::Request::builder()
+            .method(method)
+            .uri("/")
+            .header("cookie", "access_token=api_key");
+        if let Some(csrf_token) = csrf_token {
+            request = request.header(CSRF_HEADER, csrf_token);
+        }
+        let (mut parts, _) = request.body(()).unwrap().into_parts();
+
+        db::User::from_request_parts(&mut parts, state)
+            .await
+            .map_err(|rejection| rejection.into_response().status())
+    }
+
+    #[tokio::test]
+    async fn csrf_token_required_to_change_things() {
+        let (state, user, _dir) = test_state("settings-csrf");
+        let token = csrf_token(&user);
+
+        assert_eq!(
+            extract_user(&state, "POST", None).await.unwrap_err(),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            extract_user(&state, "DELETE", Some("not the token"))
+                .await
+                .unwrap_err(),
+            StatusCode::FORBIDDEN
+        );
+
+        assert_eq!(
+            extract_user(&state, "POST", Some(&token))
+                .await
+                .unwrap()
+                .name,
+            "bob"
+        );
+        // Just looking doesn't need one
+        assert!(extract_user(&state, "GET", None).await.is_ok());
+    }
+
+    #[test]
+    fn csrf_token_changes_with_session() {
+        let (_, mut user, _dir) = test_state("settings-csrf-session");
+        let token = csrf_token(&user);
+
+        user.api_key = "new_api_key".to_string();
+        assert_ne!(csrf_token(&user), token);
+    }
+}
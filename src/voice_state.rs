@@ -0,0 +1,190 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serenity::model::prelude::{Member, UserId};
+
+/// Whether a voice state update is about the bot itself, which shouldn't get an intro played
+pub(crate) fn is_self(member: &Member, bot_id: UserId) -> bool {
+    member.user.id == bot_id
+}
+
+/// Tells actual channel joins apart from the rest of the voice state updates discord sends, like
+/// muting or deafening, and ignores someone rejoining the same channel within `window`.
+pub(crate) struct JoinDebouncer {
+    window: Duration,
+    users: Mutex<HashMap<(u64, u64), SeenUser>>,
+}
+
+#[derive(Default)]
+struct SeenUser {
+    channel_id: Option<u64>,
+    last_join: Option<(u64, Instant)>,
+}
+
+impl JoinDebouncer {
+    pub(crate) fn new(window: Duration) -> Self {
+        Self {
+            window,
+            users: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records which channel `user_id` is in now, returning whether they just joined it.
+    /// `old_channel_id` is where discord says they were, which only matters for users that
+    /// haven't been seen yet, like ones that were already in a channel when the bot started.
+    pub(crate) fn is_join(
+        &self,
+        guild_id: u64,
+        user_id: u64,
+        old_channel_id: Option<u64>,
+        channel_id: Option<u64>,
+    ) -> bool {
+        self.is_join_at(
+            guild_id,
+            user_id,
+            old_channel_id,
+            channel_id,
+            Instant::now(),
+        )
+    }
+
+    fn is_join_at(
+        &self,
+        guild_id: u64,
+        user_id: u64,
+        old_channel_id: Option<u64>,
+        channel_id: Option<u64>,
+        now: Instant,
+    ) -> bool {
+        let mut users = self.users.lock().expect("join debouncer lock was poisoned");
+
+        let seen = users
+            .entry((guild_id, user_id))
+            .or_insert_with(|| SeenUser {
+                channel_id: old_channel_id,
+                last_join: None,
+            });
+        let previous_channel_id = std::mem::replace(&mut seen.channel_id, channel_id);
+        let recent_join = seen
+            .last_join
+            .filter(|(_, joined_at)| now.duration_since(*joined_at) < self.window);
+
+        let Some(channel_id) = channel_id else {
+            // Nothing left worth remembering, so this doesn't grow with every user ever seen
+            if recent_join.is_none() {
+                users.remove(&(guild_id, user_id));
+            }
+
+            return false;
+        };
+
+        if previous_channel_id == Some(channel_id)
+            || recent_join.map(|(last_channel_id, _)| last_channel_id) == Some(channel_id)
+        {
+            return false;
+        }
+
+        seen.last_join = Some((channel_id, now));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(5);
+
+    fn member(user_id: u64, username: &str) -> Member {
+        serde_json::from_value(serde_json::json!({
+            "deaf": false,
+            "guild_id": 1,
+            "joined_at": null,
+            "mute": false,
+            "nick": null,
+            "roles": [],
+            "user": {
+                "id": user_id,
+                "username": username,
+                "discriminator": "0001",
+                "avatar": null,
+                "bot": false,
+            },
+        }))
+        .expect("failed to deserialize member")
+    }
+
+    #[test]
+    fn bot_is_recognized_by_id() {
+        let bot_id = UserId(42);
+
+        assert!(is_self(&member(42, "MemeJoin"), bot_id));
+        assert!(is_self(&member(42, "Renamed Bot"), bot_id));
+        assert!(!is_self(&member(7, "MemeJoin"), bot_id));
+    }
+
+    #[test]
+    fn only_joins_count() {
+        let debouncer = JoinDebouncer::new(WINDOW);
+        let now = Instant::now();
+
+        assert!(debouncer.is_join_at(1, 2, None, Some(10), now));
+        // Muting, deafening and so on repeat the same channel
+        assert!(!debouncer.is_join_at(1, 2, None, Some(10), now));
+        assert!(!debouncer.is_join_at(1, 2, None, Some(10), now + Duration::from_secs(60)));
+
+        // Moving to another channel is a join
+        assert!(debouncer.is_join_at(1, 2, None, Some(11), now + Duration::from_secs(61)));
+
+        // Leaving isn't
+        assert!(!debouncer.is_join_at(1, 2, None, None, now + Duration::from_secs(62)));
+    }
+
+    #[test]
+    fn quick_rejoins_are_ignored() {
+        let debouncer = JoinDebouncer::new(WINDOW);
+        let now = Instant::now();
+
+        assert!(debouncer.is_join_at(1, 2, None, Some(10), now));
+        assert!(!debouncer.is_join_at(1, 2, None, None, now + Duration::from_secs(1)));
+        assert!(!debouncer.is_join_at(1, 2, None, Some(10), now + Duration::from_secs(2)));
+
+        // Once the window has passed it's a join again
+        assert!(!debouncer.is_join_at(1, 2, None, None, now + Duration::from_secs(3)));
+        assert!(debouncer.is_join_at(1, 2, None, Some(10), now + WINDOW + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn unseen_users_start_in_their_old_channel() {
+        let debouncer = JoinDebouncer::new(WINDOW);
+        let now = Instant::now();
+
+        // Already in a channel before the bot started, then muted themselves
+        assert!(!debouncer.is_join_at(1, 2, Some(10), Some(10), now));
+        assert!(debouncer.is_join_at(1, 3, Some(10), Some(11), now));
+        assert!(debouncer.is_join_at(1, 4, None, Some(10), now));
+    }
+
+    #[test]
+    fn users_are_tracked_separately() {
+        let debouncer = JoinDebouncer::new(WINDOW);
+        let now = Instant::now();
+
+        assert!(debouncer.is_join_at(1, 2, None, Some(10), now));
+        assert!(debouncer.is_join_at(1, 3, None, Some(10), now));
+        assert!(debouncer.is_join_at(4, 2, None, Some(40), now));
+    }
+
+    #[test]
+    fn users_that_left_are_forgotten() {
+        let debouncer = JoinDebouncer::new(WINDOW);
+        let now = Instant::now();
+
+        assert!(debouncer.is_join_at(1, 2, None, Some(10), now));
+        assert!(!debouncer.is_join_at(1, 2, None, None, now + WINDOW * 2));
+        assert!(debouncer.users.lock().unwrap().is_empty());
+    }
+}